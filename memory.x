@@ -1,6 +1,7 @@
 MEMORY
 {
   BOOT2                             : ORIGIN = 0x10000000, LENGTH = 0x100
-  FLASH                             : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+  /* The last 4K sector holds the keymap saved by VIA, the 512K before it the UART capture */
+  FLASH                             : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K - 512K
   RAM                               : ORIGIN = 0x20000000, LENGTH = 264K
 }
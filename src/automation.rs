@@ -0,0 +1,97 @@
+use crate::commands::{Chord, Command, LineReader, MAX_LINE};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pipe::Pipe;
+use embassy_time::Timer;
+
+/// Baud rate of the automation commands on the UART header, 8N1
+pub const BAUD_RATE: u32 = 115200;
+
+/// Command bytes received on the UART header while it takes automation commands, see `uart.rs`
+pub static AUTOMATION_INPUT: Pipe<CriticalSectionRawMutex, MAX_LINE> = Pipe::new();
+
+/// Replies to the automation commands, sent on the UART header
+pub static AUTOMATION_OUTPUT: Pipe<CriticalSectionRawMutex, 64> = Pipe::new();
+
+/// Key events of the automation commands, typed by `hid_task` on the keyboard interface
+pub static AUTOMATION_EVENTS: Channel<CriticalSectionRawMutex, AutomationEvent, 16> =
+    Channel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutomationEvent {
+    /// Press a keyboard usage
+    Press(u8),
+    /// Release a keyboard usage
+    Release(u8),
+    /// Type an ASCII character with a US layout
+    Char(u8),
+}
+
+/// Types keystrokes sent as text commands to the UART header in keyboard mode
+///
+/// Another computer connected to the header drives the keyboard of the host OSKAR
+/// is plugged into, one command per line:
+///
+/// - `TYPE "root\n"` types the text with a US layout
+/// - `PRESS F12` taps a key, `PRESS CTRL+ALT+DEL` presses keys together
+/// - `HOLD DEL 3000` holds keys for a time in milliseconds
+///
+/// Every command is answered with `OK` once its keys are sent, or with `ERR` and a reason.
+#[embassy_executor::task]
+pub async fn automation_task() -> ! {
+    let mut reader = LineReader::new();
+
+    loop {
+        let mut byte = [0];
+        AUTOMATION_INPUT.read(&mut byte).await;
+        let Some(result) = reader.push(byte[0]) else {
+            continue;
+        };
+
+        match result {
+            Ok(command) => {
+                run_command(command).await;
+                AUTOMATION_OUTPUT.write_all(b"OK\r\n").await;
+            }
+            Err(reason) => {
+                defmt::warn!("[AUTOMATION]: Rejected command: {}", reason);
+                AUTOMATION_OUTPUT.write_all(b"ERR ").await;
+                AUTOMATION_OUTPUT.write_all(reason.as_bytes()).await;
+                AUTOMATION_OUTPUT.write_all(b"\r\n").await;
+            }
+        }
+    }
+}
+
+async fn run_command(command: Command) {
+    match command {
+        Command::Type(text) => {
+            for c in text {
+                AUTOMATION_EVENTS.send(AutomationEvent::Char(c)).await;
+            }
+        }
+        Command::Press(chord) => {
+            press_chord(&chord).await;
+            release_chord(&chord).await;
+        }
+        Command::Hold(chord, duration) => {
+            press_chord(&chord).await;
+            Timer::after(duration).await;
+            release_chord(&chord).await;
+        }
+    }
+}
+
+async fn press_chord(chord: &Chord) {
+    for usage in chord {
+        AUTOMATION_EVENTS.send(AutomationEvent::Press(*usage)).await;
+    }
+}
+
+async fn release_chord(chord: &Chord) {
+    for usage in chord.iter().rev() {
+        AUTOMATION_EVENTS
+            .send(AutomationEvent::Release(*usage))
+            .await;
+    }
+}
@@ -0,0 +1,311 @@
+use crate::FLASH_SIZE;
+use crate::via::SharedFlash;
+use core::fmt::Write;
+use embassy_rp::flash::{ERASE_SIZE, PAGE_SIZE};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_timeout};
+use heapless::String;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Flash reserved for the capture in `memory.x`, right before the sector of VIA
+const CAPTURE_SIZE: u32 = 512 * 1024;
+const CAPTURE_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32 - CAPTURE_SIZE;
+const CAPTURE_PAGES: u32 = CAPTURE_SIZE / PAGE_SIZE as u32;
+const PAGES_PER_SECTOR: u32 = (ERASE_SIZE / PAGE_SIZE) as u32;
+
+/// Sequence number, boot count and magic at the start of every written page
+const PAGE_HEADER_SIZE: usize = 8;
+const PAGE_MAGIC: [u8; 2] = *b"OC";
+
+/// Length with the lost flag, and the time in ms since boot
+const ENTRY_HEADER_SIZE: usize = 5;
+
+/// Most data bytes of an entry, the batches of `uart_read`
+const MAX_ENTRY_DATA: usize = 64;
+
+/// Bytes were lost before the entry, because the ring buffer or the capture pipe was full
+const ENTRY_LOST: u8 = 1 << 7;
+
+/// Length byte of erased flash, which ends the entries of a page
+const ENTRY_END: u8 = 0xFF;
+
+/// Entries waiting to be written to flash, enough for the time a sector erase takes
+const CAPTURE_PIPE_SIZE: usize = 1024;
+
+/// A partially filled page is written once the header has been quiet for this long
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// The dump gives up when the host doesn't read it for this long
+const DUMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CAPTURE_PIPE: Pipe<CriticalSectionRawMutex, CAPTURE_PIPE_SIZE> = Pipe::new();
+
+/// Whether the next entry has to be flagged with `ENTRY_LOST`
+static LOST: AtomicBool = AtomicBool::new(false);
+
+/// Page written next, where the dump starts with the oldest pages
+static NEXT_PAGE: AtomicU32 = AtomicU32::new(0);
+
+/// Signal to send the capture to the host on the CDC-ACM port, from a key combo or the
+/// `CAPTURE_DUMP_RATE` of the host
+pub static DUMP_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Record up to 64 bytes received on the header, `lost` if some were dropped before them
+///
+/// Entries that don't fit into the capture pipe are dropped and flag the next one.
+pub fn record(data: &[u8], lost: bool) {
+    let lost = LOST.swap(false, Ordering::Relaxed) || lost;
+    let n = data.len().min(MAX_ENTRY_DATA);
+    let timestamp = Instant::now().as_millis() as u32;
+
+    let mut entry = [0; ENTRY_HEADER_SIZE + MAX_ENTRY_DATA];
+    entry[0] = n as u8 | if lost { ENTRY_LOST } else { 0 };
+    entry[1..5].copy_from_slice(&timestamp.to_le_bytes());
+    entry[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + n].copy_from_slice(&data[..n]);
+
+    let mut entry = &entry[..ENTRY_HEADER_SIZE + n];
+    if CAPTURE_PIPE.free_capacity() < entry.len() {
+        LOST.store(true, Ordering::Relaxed);
+        return;
+    }
+    // The pipe only takes the bytes up to its end at once
+    while let Ok(written) = CAPTURE_PIPE.try_write(entry) {
+        entry = &entry[written..];
+        if entry.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Writes the recorded entries to the flash reserved for the capture
+///
+/// The flash is a ring of 256 byte pages, each starting with a sequence number, the boot
+/// count and a magic, followed by entries of a length byte, the time in ms since boot
+/// and the data. A sector is erased when its first page is written, and every boot
+/// starts with a new sector after the newest page.
+#[embassy_executor::task]
+pub async fn capture_task(flash: &'static SharedFlash) -> ! {
+    let newest = {
+        let mut flash = flash.lock().await;
+        (0..CAPTURE_PAGES)
+            .filter_map(|page| {
+                let mut header = [0; PAGE_HEADER_SIZE];
+                flash.blocking_read(page_offset(page), &mut header).ok()?;
+                parse_page_header(&header).map(|(sequence, boot)| (page, sequence, boot))
+            })
+            .max_by_key(|&(_, sequence, _)| sequence)
+    };
+    let mut writer = match newest {
+        Some((page, sequence, boot)) => {
+            let next = (page / PAGES_PER_SECTOR + 1) * PAGES_PER_SECTOR % CAPTURE_PAGES;
+            CaptureWriter::new(next, sequence.wrapping_add(1), boot.wrapping_add(1))
+        }
+        None => CaptureWriter::new(0, 0, 0),
+    };
+    defmt::info!(
+        "[CAPTURE]: Boot {}, writing page {}",
+        writer.boot,
+        writer.next
+    );
+
+    loop {
+        let mut header = [0; ENTRY_HEADER_SIZE];
+        if writer.is_empty() {
+            read_exact(&mut header).await;
+        } else if with_timeout(FLUSH_DELAY, read_exact(&mut header))
+            .await
+            .is_err()
+        {
+            writer.flush(flash).await;
+            continue;
+        }
+
+        let n = (header[0] & !ENTRY_LOST) as usize;
+        if !writer.fits(ENTRY_HEADER_SIZE + n) {
+            writer.flush(flash).await;
+        }
+        let entry = writer.push(ENTRY_HEADER_SIZE + n);
+        entry[..ENTRY_HEADER_SIZE].copy_from_slice(&header);
+        read_exact(&mut entry[ENTRY_HEADER_SIZE..]).await;
+    }
+}
+
+/// Read exactly `buf.len()` bytes from the capture pipe, entries are written as a whole
+async fn read_exact(mut buf: &mut [u8]) {
+    while !buf.is_empty() {
+        let n = CAPTURE_PIPE.read(buf).await;
+        buf = &mut buf[n..];
+    }
+}
+
+fn page_offset(page: u32) -> u32 {
+    CAPTURE_OFFSET + page * PAGE_SIZE as u32
+}
+
+/// Sequence number and boot count of a written page
+fn parse_page_header(page: &[u8]) -> Option<(u32, u16)> {
+    let [s0, s1, s2, s3, b0, b1, m0, m1, ..] = *page else {
+        return None;
+    };
+    ([m0, m1] == PAGE_MAGIC).then(|| {
+        (
+            u32::from_le_bytes([s0, s1, s2, s3]),
+            u16::from_le_bytes([b0, b1]),
+        )
+    })
+}
+
+/// Page being filled with entries and its place in the ring
+struct CaptureWriter {
+    page: [u8; PAGE_SIZE],
+    len: usize,
+    next: u32,
+    sequence: u32,
+    boot: u16,
+}
+
+impl CaptureWriter {
+    fn new(next: u32, sequence: u32, boot: u16) -> Self {
+        NEXT_PAGE.store(next, Ordering::Relaxed);
+        Self {
+            page: [0xFF; PAGE_SIZE],
+            len: PAGE_HEADER_SIZE,
+            next,
+            sequence,
+            boot,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == PAGE_HEADER_SIZE
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        self.len + len <= PAGE_SIZE
+    }
+
+    /// Room for an entry of `len` bytes at the end of the page
+    fn push(&mut self, len: usize) -> &mut [u8] {
+        let start = self.len;
+        self.len += len;
+        &mut self.page[start..self.len]
+    }
+
+    /// Write the page to flash, erasing its sector first if it is the first page of it
+    async fn flush(&mut self, flash: &SharedFlash) {
+        self.page[0..4].copy_from_slice(&self.sequence.to_le_bytes());
+        self.page[4..6].copy_from_slice(&self.boot.to_le_bytes());
+        self.page[6..8].copy_from_slice(&PAGE_MAGIC);
+
+        let offset = page_offset(self.next);
+        let mut flash = flash.lock().await;
+        let erase = if self.next % PAGES_PER_SECTOR == 0 {
+            flash.blocking_erase(offset, offset + ERASE_SIZE as u32)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = erase.and_then(|()| flash.blocking_write(offset, &self.page)) {
+            defmt::error!(
+                "[CAPTURE]: Failed to write page {}: {:?}",
+                self.next,
+                defmt::Debug2Format(&e)
+            );
+        }
+
+        self.next = (self.next + 1) % CAPTURE_PAGES;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.page = [0xFF; PAGE_SIZE];
+        self.len = PAGE_HEADER_SIZE;
+        NEXT_PAGE.store(self.next, Ordering::Relaxed);
+    }
+}
+
+/// Send the capture to the host as text, oldest first
+///
+/// Every line starts with the boot count and the time since that boot, lost bytes are
+/// marked with a line of their own, and the dump is framed by `-- start of capture --`
+/// and `-- end of capture --` lines.
+pub async fn dump<const N: usize>(
+    flash: &SharedFlash,
+    usb_pipe: &Pipe<NoopRawMutex, N>,
+) -> Result<(), TimeoutError> {
+    defmt::info!("[CAPTURE]: Sending the capture");
+    // Let the capture task write the page it is filling
+    Timer::after(FLUSH_DELAY).await;
+    send(usb_pipe, b"\r\n-- start of capture --\r\n").await?;
+
+    let first = NEXT_PAGE.load(Ordering::Relaxed);
+    let mut line_start = true;
+    let mut last_boot = None;
+    for i in 0..CAPTURE_PAGES {
+        let mut page = [0; PAGE_SIZE];
+        let read = flash
+            .lock()
+            .await
+            .blocking_read(page_offset((first + i) % CAPTURE_PAGES), &mut page);
+        let Some((_, boot)) = read.ok().and_then(|()| parse_page_header(&page)) else {
+            continue;
+        };
+        if last_boot != Some(boot) && !line_start {
+            send(usb_pipe, b"\r\n").await?;
+            line_start = true;
+        }
+        last_boot = Some(boot);
+
+        let mut entries = &page[PAGE_HEADER_SIZE..];
+        while let [flags, t0, t1, t2, t3, rest @ ..] = entries {
+            let n = (flags & !ENTRY_LOST) as usize;
+            if *flags == ENTRY_END || n > rest.len() {
+                break;
+            }
+            let timestamp = u32::from_le_bytes([*t0, *t1, *t2, *t3]);
+            if flags & ENTRY_LOST != 0 {
+                if !line_start {
+                    send(usb_pipe, b"\r\n").await?;
+                }
+                send_prefix(usb_pipe, boot, timestamp).await?;
+                send(usb_pipe, b"-- bytes lost --\r\n").await?;
+                line_start = true;
+            }
+            for line in rest[..n].split_inclusive(|&b| b == b'\n') {
+                if line_start {
+                    send_prefix(usb_pipe, boot, timestamp).await?;
+                }
+                send(usb_pipe, line).await?;
+                line_start = line.ends_with(b"\n");
+            }
+            entries = &rest[n..];
+        }
+    }
+
+    if !line_start {
+        send(usb_pipe, b"\r\n").await?;
+    }
+    send(usb_pipe, b"-- end of capture --\r\n").await
+}
+
+async fn send<const N: usize>(
+    usb_pipe: &Pipe<NoopRawMutex, N>,
+    data: &[u8],
+) -> Result<(), TimeoutError> {
+    with_timeout(DUMP_TIMEOUT, usb_pipe.write_all(data)).await
+}
+
+/// Boot count and seconds since that boot, e.g. `[boot 3     12.345] `
+async fn send_prefix<const N: usize>(
+    usb_pipe: &Pipe<NoopRawMutex, N>,
+    boot: u16,
+    timestamp: u32,
+) -> Result<(), TimeoutError> {
+    let mut prefix: String<32> = String::new();
+    let _ = write!(
+        prefix,
+        "[boot {} {:>6}.{:03}] ",
+        boot,
+        timestamp / 1000,
+        timestamp % 1000
+    );
+    send(usb_pipe, prefix.as_bytes()).await
+}
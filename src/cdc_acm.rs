@@ -0,0 +1,354 @@
+use crate::pio_uart::{FORMAT_8N1, Format, MAX_BAUD_RATE, MIN_BAUD_RATE, Parity, StopBits};
+use core::cell::Cell;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Endpoint, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+use portable_atomic::{AtomicU8, Ordering};
+use static_cell::StaticCell;
+
+type EpIn = <Driver<'static, USB> as embassy_usb::driver::Driver<'static>>::EndpointIn;
+type EpOut = <Driver<'static, USB> as embassy_usb::driver::Driver<'static>>::EndpointOut;
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+/// Line coding, control line and SEND_BREAK requests, and the SERIAL_STATE notification
+const ACM_CAPABILITIES: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQ_SEND_BREAK: u8 = 0x23;
+
+/// Baud rate that starts the auto-baud detection instead of being used, e.g. `stty -F
+/// /dev/ttyACM0 50`, it is too slow for the UART anyway
+pub const AUTO_BAUD_RATE: u32 = 50;
+
+/// Baud rate that sends the capture of the header instead of being used, see
+/// `capture::dump`
+pub const CAPTURE_DUMP_RATE: u32 = 75;
+
+/// SEND_BREAK duration that holds the break until the host ends it with a duration of 0
+pub const BREAK_UNTIL_STOPPED: u16 = 0xFFFF;
+
+/// Request type of notifications: device to host, class, interface
+const NOTIFICATION_REQUEST_TYPE: u8 = 0xA1;
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+/// Serial state bits of the SERIAL_STATE notification
+pub const SERIAL_STATE_RX_CARRIER: u16 = 1 << 0;
+pub const SERIAL_STATE_TX_CARRIER: u16 = 1 << 1;
+pub const SERIAL_STATE_BREAK: u16 = 1 << 2;
+pub const SERIAL_STATE_FRAMING: u16 = 1 << 4;
+pub const SERIAL_STATE_PARITY: u16 = 1 << 5;
+pub const SERIAL_STATE_OVERRUN: u16 = 1 << 6;
+
+const MAX_PACKET_SIZE: u16 = 64;
+/// Long enough for the 10 byte SERIAL_STATE notification
+const NOTIFICATION_PACKET_SIZE: u16 = 16;
+const NOTIFICATION_POLL_MS: u8 = 10;
+
+/// Baud rate and frame format set by the host
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct LineCoding {
+    pub data_rate: u32,
+    pub format: Format,
+}
+
+impl LineCoding {
+    /// Line coding of the 7 byte SET_LINE_CODING data, if it is supported
+    fn parse(data: &[u8]) -> Option<Self> {
+        let [r0, r1, r2, r3, stop_bits, parity, data_bits, ..] = *data else {
+            return None;
+        };
+        let stop_bits = match stop_bits {
+            0 => StopBits::One,
+            1 => StopBits::OnePointFive,
+            2 => StopBits::Two,
+            _ => return None,
+        };
+        let parity = match parity {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => return None,
+        };
+        let format = Format {
+            data_bits,
+            parity,
+            stop_bits,
+        };
+        // Reserved rates aren't used as a rate, so they don't have to be in range
+        let data_rate = u32::from_le_bytes([r0, r1, r2, r3]);
+        let rate_valid = matches!(data_rate, AUTO_BAUD_RATE | CAPTURE_DUMP_RATE)
+            || (MIN_BAUD_RATE..=MAX_BAUD_RATE).contains(&data_rate);
+        (rate_valid && format.is_valid()).then_some(Self { data_rate, format })
+    }
+
+    /// GET_LINE_CODING data
+    fn to_bytes(self) -> [u8; 7] {
+        let [r0, r1, r2, r3] = self.data_rate.to_le_bytes();
+        let stop_bits = match self.format.stop_bits {
+            StopBits::One => 0,
+            StopBits::OnePointFive => 1,
+            StopBits::Two => 2,
+        };
+        let parity = match self.format.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        [r0, r1, r2, r3, stop_bits, parity, self.format.data_bits]
+    }
+}
+
+/// Line coding until the host sets one
+const DEFAULT_LINE_CODING: LineCoding = LineCoding {
+    data_rate: 115200,
+    format: FORMAT_8N1,
+};
+
+static LINE_CODING: Mutex<CriticalSectionRawMutex, Cell<LineCoding>> =
+    Mutex::new(Cell::new(DEFAULT_LINE_CODING));
+
+/// Control lines set by the host, see `CONTROL_LINE_*`
+static CONTROL_LINES: AtomicU8 = AtomicU8::new(0);
+
+const CONTROL_LINE_DTR: u8 = 1 << 0;
+const CONTROL_LINE_RTS: u8 = 1 << 1;
+
+/// Signal to notify when the host changed the line coding
+pub static LINE_CODING_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signal to notify when the host changed the control lines
+pub static CONTROL_LINES_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signal with the duration in ms of a break requested by the host, 0 ends the break
+pub static BREAK_REQUESTED: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+
+/// Line coding last set by the host
+pub fn line_coding() -> LineCoding {
+    LINE_CODING.lock(|line_coding| line_coding.get())
+}
+
+/// Replace the baud rate of the line coding, e.g. with a detected one
+pub fn set_data_rate(data_rate: u32) {
+    LINE_CODING.lock(|cell| {
+        cell.set(LineCoding {
+            data_rate,
+            ..cell.get()
+        })
+    });
+}
+
+/// Whether the host asserts DTR, e.g. while the port is open
+pub fn dtr() -> bool {
+    CONTROL_LINES.load(Ordering::Relaxed) & CONTROL_LINE_DTR != 0
+}
+
+/// Whether the host asserts RTS
+pub fn rts() -> bool {
+    CONTROL_LINES.load(Ordering::Relaxed) & CONTROL_LINE_RTS != 0
+}
+
+/// Receives the data the host writes to the serial port
+pub struct CdcReceiver {
+    ep_out: EpOut,
+}
+
+impl CdcReceiver {
+    /// Wait until the host configured the device
+    pub async fn wait_connection(&mut self) {
+        self.ep_out.wait_enabled().await;
+    }
+
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        self.ep_out.read(buf).await
+    }
+}
+
+/// Sends data the host reads from the serial port
+pub struct CdcSender {
+    ep_in: EpIn,
+}
+
+impl CdcSender {
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.ep_in.write(data).await
+    }
+}
+
+/// Sends the serial state notifications
+pub struct CdcNotifier {
+    comm_if: InterfaceNumber,
+    ep_in: EpIn,
+}
+
+impl CdcNotifier {
+    /// Report the serial state, see `SERIAL_STATE_*`
+    ///
+    /// The error bits are reported once per notification, the carrier bits are states.
+    pub async fn serial_state(&mut self, state: u16) -> Result<(), EndpointError> {
+        let [index0, index1] = (self.comm_if.0 as u16).to_le_bytes();
+        let [state0, state1] = state.to_le_bytes();
+        self.ep_in
+            .write(&[
+                NOTIFICATION_REQUEST_TYPE,
+                NOTIFICATION_SERIAL_STATE,
+                0,
+                0,
+                index0,
+                index1,
+                2,
+                0,
+                state0,
+                state1,
+            ])
+            .await
+    }
+}
+
+/// Serial port interface that reports line errors, which embassy's CDC-ACM class can't
+pub fn new_cdc_acm(
+    builder: &mut Builder<'static, Driver<'static, USB>>,
+) -> (CdcReceiver, CdcSender, CdcNotifier) {
+    static CONTROL: StaticCell<CdcControl> = StaticCell::new();
+
+    let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE);
+
+    // Communication interface with the notification endpoint
+    let mut iface = func.interface();
+    let comm_if = iface.interface_number();
+    let data_if = comm_if.0 + 1;
+    let mut alt = iface.alt_setting(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE, None);
+    alt.descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01]);
+    alt.descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, ACM_CAPABILITIES]);
+    alt.descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if.0, data_if]);
+    alt.descriptor(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, data_if]);
+    let notification_ep = alt.endpoint_interrupt_in(NOTIFICATION_PACKET_SIZE, NOTIFICATION_POLL_MS);
+
+    // Data interface
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+    let ep_out = alt.endpoint_bulk_out(MAX_PACKET_SIZE);
+    let ep_in = alt.endpoint_bulk_in(MAX_PACKET_SIZE);
+    drop(func);
+
+    builder.handler(CONTROL.init(CdcControl { comm_if }));
+
+    (
+        CdcReceiver { ep_out },
+        CdcSender { ep_in },
+        CdcNotifier {
+            comm_if,
+            ep_in: notification_ep,
+        },
+    )
+}
+
+/// Answers the CDC class requests of the communication interface
+struct CdcControl {
+    comm_if: InterfaceNumber,
+}
+
+impl Handler for CdcControl {
+    fn reset(&mut self) {
+        CONTROL_LINES.store(0, Ordering::Relaxed);
+        CONTROL_LINES_CHANGED.signal(());
+        BREAK_REQUESTED.signal(0);
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.comm_if.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SET_LINE_CODING => match LineCoding::parse(data) {
+                // Only the format is taken, the detected rate replaces the current one
+                Some(line_coding) if line_coding.data_rate == AUTO_BAUD_RATE => {
+                    defmt::debug!("[UART]: Auto-baud with {:?}", line_coding.format);
+                    LINE_CODING.lock(|cell| {
+                        cell.set(LineCoding {
+                            format: line_coding.format,
+                            ..cell.get()
+                        })
+                    });
+                    LINE_CODING_CHANGED.signal(());
+                    crate::uart::AUTO_BAUD_REQUESTED.signal(());
+                    Some(OutResponse::Accepted)
+                }
+                // The line coding is kept, so the capture goes on with the rate of the target
+                Some(line_coding) if line_coding.data_rate == CAPTURE_DUMP_RATE => {
+                    defmt::debug!("[UART]: Capture dump");
+                    crate::capture::DUMP_REQUESTED.signal(());
+                    Some(OutResponse::Accepted)
+                }
+                Some(line_coding) => {
+                    defmt::debug!("[UART]: Line coding {:?}", line_coding);
+                    LINE_CODING.lock(|cell| cell.set(line_coding));
+                    LINE_CODING_CHANGED.signal(());
+                    Some(OutResponse::Accepted)
+                }
+                None => Some(OutResponse::Rejected),
+            },
+            REQ_SET_CONTROL_LINE_STATE => {
+                let lines = req.value as u8 & (CONTROL_LINE_DTR | CONTROL_LINE_RTS);
+                defmt::debug!("[UART]: Control lines {:#04x}", lines);
+                CONTROL_LINES.store(lines, Ordering::Relaxed);
+                CONTROL_LINES_CHANGED.signal(());
+                Some(OutResponse::Accepted)
+            }
+            REQ_SEND_BREAK => {
+                defmt::debug!("[UART]: Break {} ms", req.value);
+                BREAK_REQUESTED.signal(req.value);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.comm_if.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING if buf.len() >= 7 => {
+                buf[..7].copy_from_slice(&line_coding().to_bytes());
+                Some(InResponse::Accepted(&buf[..7]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
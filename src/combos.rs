@@ -0,0 +1,338 @@
+use crate::keys::{Event, Key, KeyEvent, NUM_KEYS};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// Keys of a combo have to be pressed within this time of the first one
+pub const COMBO_TERM: Duration = Duration::from_millis(50);
+
+/// Keys that trigger `action` when pressed together instead of their own actions
+pub struct Combo<T> {
+    pub keys: &'static [Key],
+    pub action: T,
+}
+
+impl<T> Combo<T> {
+    /// Keys of the combo, bit n = key with index n
+    fn mask(&self) -> u8 {
+        self.keys.iter().fold(0, |mask, key| mask | key_bit(*key))
+    }
+}
+
+/// Event after combo detection, either a key event that didn't become a combo or a combo event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ComboEvent {
+    Key(KeyEvent),
+    /// Combo `index` of the combo list was pressed or released
+    Combo {
+        index: usize,
+        event: Event,
+    },
+}
+
+/// Events resolved from a single key event or timeout
+pub type ComboEvents = Vec<ComboEvent, 8>;
+
+/// Combo whose keys are still held
+#[derive(Clone, Copy)]
+struct ActiveCombo {
+    index: usize,
+    /// Keys not released yet, their releases are swallowed
+    held: u8,
+    released: bool,
+}
+
+const fn key_bit(key: Key) -> u8 {
+    1 << key.index()
+}
+
+/// Detects combos by holding back key presses for the combo term
+///
+/// Presses of keys that are part of a combo are buffered until the pressed keys
+/// match a combo, the combo term expires, a buffered key is released or a key
+/// that can't complete a combo is pressed. Buffered presses that didn't become
+/// a combo are then passed on in their original order. A combo is released with
+/// the first of its keys, the releases of the other keys are swallowed.
+pub struct ComboDetector {
+    term: Duration,
+    /// Presses held back while they might still become a combo
+    buffer: Vec<Key, NUM_KEYS>,
+    started: Option<Instant>,
+    active: Vec<ActiveCombo, 2>,
+}
+
+impl ComboDetector {
+    pub const fn new(term: Duration) -> Self {
+        Self {
+            term,
+            buffer: Vec::new(),
+            started: None,
+            active: Vec::new(),
+        }
+    }
+
+    /// Time at which buffered presses are decided by a timeout, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started.map(|started| started + self.term)
+    }
+
+    /// Advance time, resolving the buffered presses once the combo term expired
+    pub fn tick<T>(&mut self, combos: &[Combo<T>], now: Instant) -> ComboEvents {
+        let mut events = ComboEvents::new();
+        if self.deadline().is_some_and(|deadline| now >= deadline) {
+            self.resolve(combos, &mut events);
+        }
+        events
+    }
+
+    /// Feed a raw key event and get the resulting key and combo events
+    pub fn process<T>(
+        &mut self,
+        combos: &[Combo<T>],
+        key_event: KeyEvent,
+        now: Instant,
+    ) -> ComboEvents {
+        let mut events = self.tick(combos, now);
+        let bit = key_bit(key_event.key);
+
+        match key_event.event {
+            Event::Pressed => {
+                let buffered = self.buffered_mask() | bit;
+                if !key_event.key.is_rotation() && Self::completes(combos, buffered) {
+                    let _ = self.buffer.push(key_event.key);
+                    if self.started.is_none() {
+                        self.started = Some(now);
+                    }
+
+                    // No need to wait if no bigger combo could still match
+                    let bigger = combos.iter().any(|combo| {
+                        combo.mask() != buffered && combo.mask() & buffered == buffered
+                    });
+                    if !bigger {
+                        self.resolve(combos, &mut events);
+                    }
+                    return events;
+                }
+
+                self.flush(&mut events);
+                if !key_event.key.is_rotation() && Self::completes(combos, bit) {
+                    let _ = self.buffer.push(key_event.key);
+                    self.started = Some(now);
+                } else {
+                    let _ = events.push(ComboEvent::Key(key_event));
+                }
+            }
+            Event::Released => {
+                if self.buffered_mask() & bit != 0 {
+                    self.resolve(combos, &mut events);
+                }
+
+                if let Some(position) = self.active.iter().position(|active| active.held & bit != 0)
+                {
+                    let active = &mut self.active[position];
+                    // The first released key releases the combo
+                    if !active.released {
+                        active.released = true;
+                        let _ = events.push(ComboEvent::Combo {
+                            index: active.index,
+                            event: Event::Released,
+                        });
+                    }
+                    active.held &= !bit;
+                    if active.held == 0 {
+                        self.active.swap_remove(position);
+                    }
+                } else {
+                    let _ = events.push(ComboEvent::Key(key_event));
+                }
+            }
+        }
+
+        events
+    }
+
+    /// Whether some combo contains all keys of `mask`
+    fn completes<T>(combos: &[Combo<T>], mask: u8) -> bool {
+        combos
+            .iter()
+            .any(|combo| combo.keys.len() > 1 && combo.mask() & mask == mask)
+    }
+
+    fn buffered_mask(&self) -> u8 {
+        self.buffer.iter().fold(0, |mask, key| mask | key_bit(*key))
+    }
+
+    /// Trigger the combo matching the buffered presses or pass them on as key presses
+    fn resolve<T>(&mut self, combos: &[Combo<T>], events: &mut ComboEvents) {
+        let buffered = self.buffered_mask();
+        let index = combos
+            .iter()
+            .position(|combo| combo.keys.len() > 1 && combo.mask() == buffered);
+
+        match index {
+            Some(index) if !self.active.is_full() => {
+                let _ = events.push(ComboEvent::Combo {
+                    index,
+                    event: Event::Pressed,
+                });
+                let _ = self.active.push(ActiveCombo {
+                    index,
+                    held: buffered,
+                    released: false,
+                });
+                self.buffer.clear();
+                self.started = None;
+            }
+            _ => self.flush(events),
+        }
+    }
+
+    /// Pass on the buffered presses as key presses
+    fn flush(&mut self, events: &mut ComboEvents) {
+        for key in self.buffer.iter() {
+            let _ = events.push(ComboEvent::Key(KeyEvent {
+                key: *key,
+                event: Event::Pressed,
+            }));
+        }
+        self.buffer.clear();
+        self.started = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Event::{Pressed, Released};
+    use crate::keys::Key::*;
+
+    static COMBOS: [Combo<()>; 3] = [
+        Combo {
+            keys: &[Key1, Key2],
+            action: (),
+        },
+        Combo {
+            keys: &[Key1, Key2, Key3],
+            action: (),
+        },
+        Combo {
+            keys: &[EncoderButton, Key3],
+            action: (),
+        },
+    ];
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn key(key: Key, event: Event) -> KeyEvent {
+        KeyEvent { key, event }
+    }
+
+    fn passed(key: Key, event: Event) -> ComboEvent {
+        ComboEvent::Key(KeyEvent { key, event })
+    }
+
+    fn combo(index: usize, event: Event) -> ComboEvent {
+        ComboEvent::Combo { index, event }
+    }
+
+    #[test]
+    fn keys_pressed_within_combo_term_trigger_combo() {
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        assert!(
+            detector
+                .process(&COMBOS, key(EncoderButton, Pressed), at(0))
+                .is_empty()
+        );
+        assert_eq!(detector.deadline(), Some(at(50)));
+        // No bigger combo contains both keys, so it doesn't wait for the term
+        assert_eq!(
+            detector.process(&COMBOS, key(Key3, Pressed), at(49)),
+            [combo(2, Pressed)]
+        );
+        assert_eq!(detector.deadline(), None);
+    }
+
+    #[test]
+    fn combo_waits_for_bigger_combo_until_term_expired() {
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(Key1, Pressed), at(0));
+        assert!(
+            detector
+                .process(&COMBOS, key(Key2, Pressed), at(10))
+                .is_empty()
+        );
+        assert!(detector.tick(&COMBOS, at(49)).is_empty());
+        assert_eq!(detector.tick(&COMBOS, at(50)), [combo(0, Pressed)]);
+
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(Key1, Pressed), at(0));
+        detector.process(&COMBOS, key(Key2, Pressed), at(10));
+        assert_eq!(
+            detector.process(&COMBOS, key(Key3, Pressed), at(20)),
+            [combo(1, Pressed)]
+        );
+    }
+
+    #[test]
+    fn key_pressed_after_combo_term_is_single_key() {
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(EncoderButton, Pressed), at(0));
+        assert_eq!(
+            detector.process(&COMBOS, key(Key3, Pressed), at(50)),
+            [passed(EncoderButton, Pressed)]
+        );
+        // Key3 might start another combo itself
+        assert_eq!(detector.tick(&COMBOS, at(100)), [passed(Key3, Pressed)]);
+    }
+
+    #[test]
+    fn partial_combo_is_flushed_as_single_keys() {
+        // Released before the term expired
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(Key1, Pressed), at(0));
+        assert_eq!(
+            detector.process(&COMBOS, key(Key1, Released), at(20)),
+            [passed(Key1, Pressed), passed(Key1, Released)]
+        );
+
+        // Term expired, the release follows later
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(Key1, Pressed), at(0));
+        assert_eq!(detector.tick(&COMBOS, at(50)), [passed(Key1, Pressed)]);
+        assert_eq!(
+            detector.process(&COMBOS, key(Key1, Released), at(200)),
+            [passed(Key1, Released)]
+        );
+
+        // A key that can't complete the combo is pressed, keeping the order of the presses
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(Key1, Pressed), at(0));
+        assert_eq!(
+            detector.process(&COMBOS, key(EncoderLeft, Pressed), at(10)),
+            [passed(Key1, Pressed), passed(EncoderLeft, Pressed)]
+        );
+    }
+
+    #[test]
+    fn combo_is_released_with_first_key_without_stray_key_up() {
+        let mut detector = ComboDetector::new(COMBO_TERM);
+        detector.process(&COMBOS, key(EncoderButton, Pressed), at(0));
+        detector.process(&COMBOS, key(Key3, Pressed), at(10));
+        assert_eq!(
+            detector.process(&COMBOS, key(Key3, Released), at(100)),
+            [combo(2, Released)]
+        );
+        assert!(
+            detector
+                .process(&COMBOS, key(EncoderButton, Released), at(110))
+                .is_empty()
+        );
+
+        // Keys after the combo are passed on again
+        assert_eq!(
+            detector.process(&COMBOS, key(EncoderLeft, Pressed), at(120)),
+            [passed(EncoderLeft, Pressed)]
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use crate::keycodes::ascii_usage;
+use embassy_time::Duration;
+use heapless::Vec;
+
+/// Longest command line, longer lines are rejected
+pub const MAX_LINE: usize = 128;
+
+/// Most keys pressed together by one command, e.g. `CTRL+ALT+DEL`
+const MAX_CHORD: usize = 4;
+
+/// Longest time a `HOLD` command holds its keys, a minute
+const MAX_HOLD_MS: u64 = 60_000;
+
+/// Keyboard usages by name, besides single letters, digits and F1-F24
+const KEY_NAMES: [(&str, u8); 46] = [
+    ("ENTER", 0x28),
+    ("RETURN", 0x28),
+    ("ESC", 0x29),
+    ("ESCAPE", 0x29),
+    ("BACKSPACE", 0x2A),
+    ("TAB", 0x2B),
+    ("SPACE", 0x2C),
+    ("CAPSLOCK", 0x39),
+    ("PRINTSCREEN", 0x46),
+    ("SCROLLLOCK", 0x47),
+    ("PAUSE", 0x48),
+    ("INS", 0x49),
+    ("INSERT", 0x49),
+    ("HOME", 0x4A),
+    ("PGUP", 0x4B),
+    ("PAGEUP", 0x4B),
+    ("DEL", 0x4C),
+    ("DELETE", 0x4C),
+    ("END", 0x4D),
+    ("PGDN", 0x4E),
+    ("PAGEDOWN", 0x4E),
+    ("RIGHT", 0x4F),
+    ("LEFT", 0x50),
+    ("DOWN", 0x51),
+    ("UP", 0x52),
+    ("NUMLOCK", 0x53),
+    ("MENU", 0x65),
+    ("CTRL", 0xE0),
+    ("LCTRL", 0xE0),
+    ("SHIFT", 0xE1),
+    ("LSHIFT", 0xE1),
+    ("ALT", 0xE2),
+    ("LALT", 0xE2),
+    ("GUI", 0xE3),
+    ("WIN", 0xE3),
+    ("LGUI", 0xE3),
+    ("RCTRL", 0xE4),
+    ("RSHIFT", 0xE5),
+    ("RALT", 0xE6),
+    ("ALTGR", 0xE6),
+    ("RGUI", 0xE7),
+    ("RWIN", 0xE7),
+    ("MINUS", 0x2D),
+    ("EQUAL", 0x2E),
+    ("COMMA", 0x36),
+    ("DOT", 0x37),
+];
+
+/// Keys pressed together, in the order of the command
+pub type Chord = Vec<u8, MAX_CHORD>;
+
+/// Command of a line sent to the UART header in keyboard mode
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `TYPE "text"`, with `\n`, `\t`, `\"` and `\\` escapes
+    Type(Vec<u8, MAX_LINE>),
+    /// `PRESS <keys>`, taps the keys
+    Press(Chord),
+    /// `HOLD <keys> <ms>`, holds the keys for the given time
+    Hold(Chord, Duration),
+}
+
+/// Collects the received bytes of the automation commands into lines
+pub struct LineReader {
+    line: Vec<u8, MAX_LINE>,
+    /// The line didn't fit, it is rejected once it ends
+    overlong: bool,
+}
+
+impl LineReader {
+    pub const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            overlong: false,
+        }
+    }
+
+    /// Feed a received byte, the command or the error of a line once it ends
+    ///
+    /// Lines end with `\n`, a `\r` before it is ignored, and empty lines are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, &'static str>> {
+        match byte {
+            b'\r' => return None,
+            b'\n' => {}
+            byte => {
+                if self.line.push(byte).is_err() {
+                    self.overlong = true;
+                }
+                return None;
+            }
+        }
+
+        let result = match core::str::from_utf8(&self.line) {
+            _ if self.overlong => Some(Err("line too long")),
+            Ok(text) if text.trim().is_empty() => None,
+            Ok(text) => Some(parse_command(text)),
+            Err(_) => Some(Err("invalid characters")),
+        };
+        self.line.clear();
+        self.overlong = false;
+        result
+    }
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Command of a line without its line break
+pub fn parse_command(line: &str) -> Result<Command, &'static str> {
+    let line = line.trim();
+    let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+
+    if verb.eq_ignore_ascii_case("TYPE") {
+        parse_text(args).map(Command::Type)
+    } else if verb.eq_ignore_ascii_case("PRESS") {
+        parse_chord(args).map(Command::Press)
+    } else if verb.eq_ignore_ascii_case("HOLD") {
+        let (keys, ms) = args.rsplit_once(' ').ok_or("missing hold time")?;
+        let ms: u64 = ms.parse().map_err(|_| "invalid hold time")?;
+        if ms > MAX_HOLD_MS {
+            return Err("hold time too long");
+        }
+        Ok(Command::Hold(
+            parse_chord(keys.trim())?,
+            Duration::from_millis(ms),
+        ))
+    } else {
+        Err("unknown command")
+    }
+}
+
+/// Text between double quotes, with its escapes resolved
+fn parse_text(args: &str) -> Result<Vec<u8, MAX_LINE>, &'static str> {
+    let quoted = args
+        .strip_prefix('"')
+        .and_then(|args| args.strip_suffix('"'))
+        .ok_or("text must be quoted")?;
+
+    let mut text = Vec::new();
+    let mut bytes = quoted.bytes();
+    while let Some(byte) = bytes.next() {
+        let c = match byte {
+            b'\\' => match bytes.next() {
+                Some(b'n') => b'\n',
+                Some(b't') => b'\t',
+                Some(b'\\') => b'\\',
+                Some(b'"') => b'"',
+                _ => return Err("invalid escape"),
+            },
+            c => c,
+        };
+        if ascii_usage(c).is_none() {
+            return Err("character can't be typed");
+        }
+        // The text is shorter than the line it came from
+        let _ = text.push(c);
+    }
+    Ok(text)
+}
+
+/// Keys joined by `+`, e.g. `CTRL+ALT+DEL`
+fn parse_chord(keys: &str) -> Result<Chord, &'static str> {
+    let mut chord = Chord::new();
+    for name in keys.split('+') {
+        let usage = key_usage(name.trim()).ok_or("unknown key")?;
+        chord.push(usage).map_err(|_| "too many keys")?;
+    }
+    Ok(chord)
+}
+
+/// Keyboard usage of a key name, case-insensitive
+fn key_usage(name: &str) -> Option<u8> {
+    if let Some((_, usage)) = KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name))
+    {
+        return Some(*usage);
+    }
+
+    // F1-F12 and F13-F24 are two separate ranges of usages
+    if let Some(number) = name
+        .strip_prefix(['F', 'f'])
+        .and_then(|number| number.parse::<u8>().ok())
+    {
+        return match number {
+            1..=12 => Some(0x3A + number - 1),
+            13..=24 => Some(0x68 + number - 13),
+            _ => None,
+        };
+    }
+
+    // Single characters typed without Shift, letters in either case
+    match name.as_bytes() {
+        [c] => match ascii_usage(c.to_ascii_lowercase()) {
+            Some((usage, false)) => Some(usage),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEL: u8 = 0x4C;
+    const CTRL: u8 = 0xE0;
+    const ALT: u8 = 0xE2;
+
+    fn chord(usages: &[u8]) -> Chord {
+        Chord::from_slice(usages).unwrap()
+    }
+
+    fn text(text: &[u8]) -> Command {
+        Command::Type(Vec::from_slice(text).unwrap())
+    }
+
+    #[test]
+    fn type_resolves_escapes() {
+        assert_eq!(parse_command(r#"TYPE "a\tb\n""#), Ok(text(b"a\tb\n")));
+        assert_eq!(
+            parse_command(r#"type "say \"hi\" \\o/""#),
+            Ok(text(b"say \"hi\" \\o/"))
+        );
+        assert_eq!(parse_command(r#"TYPE "\x""#), Err("invalid escape"));
+        assert_eq!(parse_command(r#"TYPE "\""#), Err("invalid escape"));
+        assert_eq!(parse_command("TYPE unquoted"), Err("text must be quoted"));
+        assert_eq!(
+            parse_command("TYPE \"\u{e9}\""),
+            Err("character can't be typed")
+        );
+    }
+
+    #[test]
+    fn press_takes_names_function_keys_and_characters() {
+        assert_eq!(
+            parse_command("PRESS ctrl+Alt+DEL"),
+            Ok(Command::Press(chord(&[CTRL, ALT, DEL])))
+        );
+        assert_eq!(
+            parse_command("PRESS F1"),
+            Ok(Command::Press(chord(&[0x3A])))
+        );
+        assert_eq!(
+            parse_command("PRESS F12"),
+            Ok(Command::Press(chord(&[0x45])))
+        );
+        assert_eq!(
+            parse_command("PRESS F13"),
+            Ok(Command::Press(chord(&[0x68])))
+        );
+        assert_eq!(
+            parse_command("PRESS f24"),
+            Ok(Command::Press(chord(&[0x73])))
+        );
+        assert_eq!(parse_command("PRESS F25"), Err("unknown key"));
+        assert_eq!(parse_command("PRESS F0"), Err("unknown key"));
+        // Letters in either case, but no characters that need Shift
+        assert_eq!(
+            parse_command("PRESS GUI + r"),
+            Ok(Command::Press(chord(&[0xE3, 0x15])))
+        );
+        assert_eq!(parse_command("PRESS R"), Ok(Command::Press(chord(&[0x15]))));
+        assert_eq!(parse_command("PRESS !"), Err("unknown key"));
+    }
+
+    #[test]
+    fn chord_is_limited() {
+        assert!(parse_command("PRESS CTRL+SHIFT+ALT+GUI").is_ok());
+        assert_eq!(
+            parse_command("PRESS CTRL+SHIFT+ALT+GUI+A"),
+            Err("too many keys")
+        );
+        assert_eq!(parse_command("PRESS CTRL+"), Err("unknown key"));
+    }
+
+    #[test]
+    fn hold_takes_a_time_in_ms() {
+        assert_eq!(
+            parse_command("HOLD DEL 3000"),
+            Ok(Command::Hold(chord(&[DEL]), Duration::from_millis(3000)))
+        );
+        assert_eq!(
+            parse_command("  hold  CTRL+DEL   60000 "),
+            Ok(Command::Hold(
+                chord(&[CTRL, DEL]),
+                Duration::from_millis(60_000)
+            ))
+        );
+        assert_eq!(parse_command("HOLD DEL"), Err("missing hold time"));
+        assert_eq!(parse_command("HOLD DEL -1"), Err("invalid hold time"));
+        assert_eq!(parse_command("HOLD DEL 60001"), Err("hold time too long"));
+        // Too long for a u64, and for a Duration in ticks even if it fits
+        assert_eq!(
+            parse_command("HOLD DEL 99999999999999999999"),
+            Err("invalid hold time")
+        );
+        assert_eq!(
+            parse_command("HOLD DEL 18446744073709551615"),
+            Err("hold time too long")
+        );
+    }
+
+    #[test]
+    fn unknown_command_is_rejected() {
+        assert_eq!(parse_command("JUMP"), Err("unknown command"));
+    }
+
+    #[test]
+    fn lines_end_with_newline() {
+        let mut reader = LineReader::new();
+        let results: std::vec::Vec<_> = b"\r\n  \nPRESS A\r\nTYPE \"x\"\n"
+            .iter()
+            .filter_map(|byte| reader.push(*byte))
+            .collect();
+        assert_eq!(
+            results,
+            [Ok(Command::Press(chord(&[0x04]))), Ok(text(b"x"))]
+        );
+    }
+
+    #[test]
+    fn overlong_line_is_rejected_once_it_ends() {
+        let mut reader = LineReader::new();
+        for _ in 0..MAX_LINE + 1 {
+            assert_eq!(reader.push(b'A'), None);
+        }
+        assert_eq!(reader.push(b'\n'), Some(Err("line too long")));
+        // The next line is taken again
+        for byte in b"PRESS A" {
+            reader.push(*byte);
+        }
+        assert_eq!(reader.push(b'\n'), Some(Ok(Command::Press(chord(&[0x04])))));
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        let mut reader = LineReader::new();
+        reader.push(0xFF);
+        assert_eq!(reader.push(b'\n'), Some(Err("invalid characters")));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::layouts::{DialAction, MouseAction, PhoneAction, SystemAction};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_usb::class::hid::{HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler};
+use embassy_usb::control::OutResponse;
+use portable_atomic::{AtomicU8, Ordering};
+
+pub type ControlsHid = HidReaderWriter<'static, Driver<'static, USB>, 8, 8>;
+pub type ControlsReader = HidReader<'static, Driver<'static, USB>, 8>;
+pub type ControlsWriter = HidWriter<'static, Driver<'static, USB>, 8>;
+
+/// Report ID of the mouse collection
+pub const MOUSE_REPORT_ID: u8 = 1;
+
+/// Report ID of the radial controller collection
+pub const DIAL_REPORT_ID: u8 = 2;
+
+/// Report ID of the telephony collection
+pub const TELEPHONY_REPORT_ID: u8 = 3;
+
+/// Report ID of the system control collection
+pub const SYSTEM_REPORT_ID: u8 = 4;
+
+/// Report ID of the gamepad collection
+pub const GAMEPAD_REPORT_ID: u8 = 5;
+
+/// Bits of the telephony output report
+const PHONE_LED_MUTE: u8 = 0x01;
+const PHONE_LED_OFF_HOOK: u8 = 0x02;
+
+/// Wheel units per scroll step while the host uses the resolution multiplier,
+/// which makes 120 units one wheel detent
+const HIRES_SCROLL_STEP: i8 = 30;
+
+/// Pointer movement per move step
+const POINTER_STEP: i8 = 16;
+
+/// Dial rotation per encoder step in tenths of a degree
+const DIAL_STEP: i16 = 100;
+
+/// Resolution multiplier feature set by the host, bits 0-1 for the wheel and bits 2-3 for the pan
+static RESOLUTION_MULTIPLIER: AtomicU8 = AtomicU8::new(0);
+
+/// Telephony LEDs set by the host, see `PHONE_LED_MUTE` and `PHONE_LED_OFF_HOOK`
+static TELEPHONY_LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Report descriptor of the controls interface, one report ID per top-level collection
+#[rustfmt::skip]
+pub const CONTROLS_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x02,             // Usage (Mouse)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, MOUSE_REPORT_ID,  //   Report ID
+    0x09, 0x01,             //   Usage (Pointer)
+    0xA1, 0x00,             //   Collection (Physical)
+    0x05, 0x09,             //     Usage Page (Button)
+    0x19, 0x01,             //     Usage Minimum (Button 1)
+    0x29, 0x03,             //     Usage Maximum (Button 3)
+    0x15, 0x00,             //     Logical Minimum (0)
+    0x25, 0x01,             //     Logical Maximum (1)
+    0x75, 0x01,             //     Report Size (1)
+    0x95, 0x03,             //     Report Count (3)
+    0x81, 0x02,             //     Input (Data, Variable, Absolute)
+    0x75, 0x05,             //     Report Size (5)
+    0x95, 0x01,             //     Report Count (1)
+    0x81, 0x03,             //     Input (Constant)
+    0x05, 0x01,             //     Usage Page (Generic Desktop)
+    0x09, 0x30,             //     Usage (X)
+    0x09, 0x31,             //     Usage (Y)
+    0x15, 0x81,             //     Logical Minimum (-127)
+    0x25, 0x7F,             //     Logical Maximum (127)
+    0x75, 0x08,             //     Report Size (8)
+    0x95, 0x02,             //     Report Count (2)
+    0x81, 0x06,             //     Input (Data, Variable, Relative)
+    0xA1, 0x02,             //     Collection (Logical)
+    0x09, 0x48,             //       Usage (Resolution Multiplier)
+    0x15, 0x00,             //       Logical Minimum (0)
+    0x25, 0x01,             //       Logical Maximum (1)
+    0x35, 0x01,             //       Physical Minimum (1)
+    0x45, 0x78,             //       Physical Maximum (120)
+    0x75, 0x02,             //       Report Size (2)
+    0x95, 0x01,             //       Report Count (1)
+    0xB1, 0x02,             //       Feature (Data, Variable, Absolute)
+    0x35, 0x00,             //       Physical Minimum (0)
+    0x45, 0x00,             //       Physical Maximum (0)
+    0x09, 0x38,             //       Usage (Wheel)
+    0x15, 0x81,             //       Logical Minimum (-127)
+    0x25, 0x7F,             //       Logical Maximum (127)
+    0x75, 0x08,             //       Report Size (8)
+    0x95, 0x01,             //       Report Count (1)
+    0x81, 0x06,             //       Input (Data, Variable, Relative)
+    0xC0,                   //     End Collection
+    0xA1, 0x02,             //     Collection (Logical)
+    0x09, 0x48,             //       Usage (Resolution Multiplier)
+    0x15, 0x00,             //       Logical Minimum (0)
+    0x25, 0x01,             //       Logical Maximum (1)
+    0x35, 0x01,             //       Physical Minimum (1)
+    0x45, 0x78,             //       Physical Maximum (120)
+    0x75, 0x02,             //       Report Size (2)
+    0x95, 0x01,             //       Report Count (1)
+    0xB1, 0x02,             //       Feature (Data, Variable, Absolute)
+    0x35, 0x00,             //       Physical Minimum (0)
+    0x45, 0x00,             //       Physical Maximum (0)
+    0x75, 0x04,             //       Report Size (4)
+    0xB1, 0x03,             //       Feature (Constant)
+    0x05, 0x0C,             //       Usage Page (Consumer)
+    0x0A, 0x38, 0x02,       //       Usage (AC Pan)
+    0x15, 0x81,             //       Logical Minimum (-127)
+    0x25, 0x7F,             //       Logical Maximum (127)
+    0x75, 0x08,             //       Report Size (8)
+    0x95, 0x01,             //       Report Count (1)
+    0x81, 0x06,             //       Input (Data, Variable, Relative)
+    0xC0,                   //     End Collection
+    0xC0,                   //   End Collection
+    0xC0,                   // End Collection
+
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x0E,             // Usage (System Multi-Axis Controller)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, DIAL_REPORT_ID,   //   Report ID
+    0x05, 0x0D,             //   Usage Page (Digitizers)
+    0x09, 0x21,             //   Usage (Puck)
+    0xA1, 0x00,             //   Collection (Physical)
+    0x05, 0x09,             //     Usage Page (Button)
+    0x09, 0x01,             //     Usage (Button 1)
+    0x15, 0x00,             //     Logical Minimum (0)
+    0x25, 0x01,             //     Logical Maximum (1)
+    0x75, 0x01,             //     Report Size (1)
+    0x95, 0x01,             //     Report Count (1)
+    0x81, 0x02,             //     Input (Data, Variable, Absolute)
+    0x05, 0x01,             //     Usage Page (Generic Desktop)
+    0x09, 0x37,             //     Usage (Dial)
+    0x55, 0x0F,             //     Unit Exponent (-1)
+    0x65, 0x14,             //     Unit (Degrees)
+    0x36, 0xF0, 0xF1,       //     Physical Minimum (-3600)
+    0x46, 0x10, 0x0E,       //     Physical Maximum (3600)
+    0x16, 0xF0, 0xF1,       //     Logical Minimum (-3600)
+    0x26, 0x10, 0x0E,       //     Logical Maximum (3600)
+    0x75, 0x0F,             //     Report Size (15)
+    0x95, 0x01,             //     Report Count (1)
+    0x81, 0x06,             //     Input (Data, Variable, Relative)
+    0x55, 0x00,             //     Unit Exponent (0)
+    0x65, 0x00,             //     Unit (None)
+    0xC0,                   //   End Collection
+    0xC0,                   // End Collection
+
+    0x05, 0x0B,             // Usage Page (Telephony)
+    0x09, 0x05,             // Usage (Headset)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, TELEPHONY_REPORT_ID, //   Report ID
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x25, 0x01,             //   Logical Maximum (1)
+    0x75, 0x01,             //   Report Size (1)
+    0x09, 0x20,             //   Usage (Hook Switch)
+    0x95, 0x01,             //   Report Count (1)
+    0x81, 0x22,             //   Input (Data, Variable, Absolute, No Preferred State)
+    0x09, 0x2F,             //   Usage (Phone Mute)
+    0x09, 0x21,             //   Usage (Flash)
+    0x95, 0x02,             //   Report Count (2)
+    0x81, 0x06,             //   Input (Data, Variable, Relative)
+    0x95, 0x05,             //   Report Count (5)
+    0x81, 0x03,             //   Input (Constant)
+    0x05, 0x08,             //   Usage Page (LEDs)
+    0x09, 0x09,             //   Usage (Mute)
+    0x09, 0x17,             //   Usage (Off-Hook)
+    0x95, 0x02,             //   Report Count (2)
+    0x91, 0x22,             //   Output (Data, Variable, Absolute, No Preferred State)
+    0x95, 0x06,             //   Report Count (6)
+    0x91, 0x03,             //   Output (Constant)
+    0xC0,                   // End Collection
+
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x80,             // Usage (System Control)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, SYSTEM_REPORT_ID, //   Report ID
+    0x19, 0x81,             //   Usage Minimum (System Power Down)
+    0x29, 0x83,             //   Usage Maximum (System Wake Up)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x25, 0x01,             //   Logical Maximum (1)
+    0x75, 0x01,             //   Report Size (1)
+    0x95, 0x03,             //   Report Count (3)
+    0x81, 0x06,             //   Input (Data, Variable, Relative)
+    0x95, 0x05,             //   Report Count (5)
+    0x81, 0x03,             //   Input (Constant)
+    0xC0,                   // End Collection
+
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x05,             // Usage (Game Pad)
+    0xA1, 0x01,             // Collection (Application)
+    0x85, GAMEPAD_REPORT_ID, //   Report ID
+    0x05, 0x09,             //   Usage Page (Button)
+    0x19, 0x01,             //   Usage Minimum (Button 1)
+    0x29, 0x08,             //   Usage Maximum (Button 8)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x25, 0x01,             //   Logical Maximum (1)
+    0x75, 0x01,             //   Report Size (1)
+    0x95, 0x08,             //   Report Count (8)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute)
+    0x05, 0x01,             //   Usage Page (Generic Desktop)
+    0x09, 0x30,             //   Usage (X)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x25, 0x7F,             //   Logical Maximum (127)
+    0x75, 0x08,             //   Report Size (8)
+    0x95, 0x01,             //   Report Count (1)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute)
+    0xC0,                   // End Collection
+];
+
+/// Answers the feature reports and takes the output reports of the controls interface
+pub struct ControlsRequestHandler;
+
+impl RequestHandler for ControlsRequestHandler {
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::Feature(MOUSE_REPORT_ID) if buf.len() >= 2 => {
+                buf[0] = MOUSE_REPORT_ID;
+                buf[1] = RESOLUTION_MULTIPLIER.load(Ordering::Relaxed);
+                Some(2)
+            }
+            ReportId::Out(TELEPHONY_REPORT_ID) if buf.len() >= 2 => {
+                buf[0] = TELEPHONY_REPORT_ID;
+                buf[1] = TELEPHONY_LEDS.load(Ordering::Relaxed);
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data) {
+            (ReportId::Feature(MOUSE_REPORT_ID), [MOUSE_REPORT_ID, multiplier, ..]) => {
+                log::debug!("[HID]: Resolution multiplier {:#04x}", multiplier);
+                RESOLUTION_MULTIPLIER.store(multiplier & 0x0F, Ordering::Relaxed);
+                OutResponse::Accepted
+            }
+            (ReportId::Out(TELEPHONY_REPORT_ID), [TELEPHONY_REPORT_ID, leds, ..]) => {
+                log::debug!("[HID]: Telephony LEDs {:#04x}", leds);
+                let leds = leds & (PHONE_LED_MUTE | PHONE_LED_OFF_HOOK);
+                if TELEPHONY_LEDS.swap(leds, Ordering::Relaxed) != leds {
+                    crate::led::INDICATORS_CHANGED.signal(());
+                }
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+}
+
+/// Passes the output reports received on the interrupt endpoint to the request handler
+#[embassy_executor::task]
+pub async fn controls_output_task(reader: ControlsReader) -> ! {
+    reader.run(true, &mut ControlsRequestHandler).await
+}
+
+/// Whether the host reports the microphone of the current call as muted
+pub fn host_muted() -> bool {
+    TELEPHONY_LEDS.load(Ordering::Relaxed) & PHONE_LED_MUTE != 0
+}
+
+/// Whether the host reports a call in progress
+pub fn host_off_hook() -> bool {
+    TELEPHONY_LEDS.load(Ordering::Relaxed) & PHONE_LED_OFF_HOOK != 0
+}
+
+/// Mouse input report with the held buttons and one pointer, wheel or pan movement
+pub fn mouse_report(buttons: u8, action: Option<MouseAction>) -> [u8; 6] {
+    let (x, y) = match action {
+        Some(MouseAction::MoveUp) => (0, -POINTER_STEP),
+        Some(MouseAction::MoveDown) => (0, POINTER_STEP),
+        Some(MouseAction::MoveLeft) => (-POINTER_STEP, 0),
+        Some(MouseAction::MoveRight) => (POINTER_STEP, 0),
+        _ => (0, 0),
+    };
+    let (wheel, pan) = match action {
+        Some(MouseAction::ScrollUp) => (scroll_step(false), 0),
+        Some(MouseAction::ScrollDown) => (-scroll_step(false), 0),
+        Some(MouseAction::ScrollLeft) => (0, -scroll_step(true)),
+        Some(MouseAction::ScrollRight) => (0, scroll_step(true)),
+        _ => (0, 0),
+    };
+
+    [
+        MOUSE_REPORT_ID,
+        buttons,
+        x as u8,
+        y as u8,
+        wheel as u8,
+        pan as u8,
+    ]
+}
+
+/// Radial controller input report with the button state and one rotation step
+pub fn dial_report(pressed: bool, action: Option<DialAction>) -> [u8; 3] {
+    let rotation = match action {
+        Some(DialAction::RotateLeft) => -DIAL_STEP,
+        Some(DialAction::RotateRight) => DIAL_STEP,
+        _ => 0,
+    };
+
+    // Button in bit 0, followed by the 15 bit rotation
+    let value = ((rotation as u16) << 1) | pressed as u16;
+    let [low, high] = value.to_le_bytes();
+    [DIAL_REPORT_ID, low, high]
+}
+
+/// Telephony input report with the hook switch state and a pressed mute or flash button
+///
+/// The hook switch is absolute, so every report carries the call state: the host's
+/// off-hook state, or its inverse when the hook switch key is pressed to answer or hang up.
+pub fn telephony_report(action: Option<PhoneAction>) -> [u8; 2] {
+    let off_hook = host_off_hook() != (action == Some(PhoneAction::HookSwitch));
+    let buttons = match action {
+        Some(PhoneAction::Mute) => 0x02,
+        Some(PhoneAction::Flash) => 0x04,
+        _ => 0x00,
+    };
+
+    [TELEPHONY_REPORT_ID, buttons | off_hook as u8]
+}
+
+/// System control input report with one pressed control
+pub fn system_report(action: Option<SystemAction>) -> [u8; 2] {
+    let controls = match action {
+        Some(SystemAction::PowerDown) => 0x01,
+        Some(SystemAction::Sleep) => 0x02,
+        Some(SystemAction::WakeUp) => 0x04,
+        None => 0x00,
+    };
+
+    [SYSTEM_REPORT_ID, controls]
+}
+
+/// Gamepad input report with the held buttons and the axis value
+pub fn gamepad_report(buttons: u8, axis: u8) -> [u8; 3] {
+    [GAMEPAD_REPORT_ID, buttons, axis]
+}
+
+/// Wheel units of one scroll step, finer when the host enabled the resolution multiplier
+fn scroll_step(horizontal: bool) -> i8 {
+    let multiplier = RESOLUTION_MULTIPLIER.load(Ordering::Relaxed);
+    let enabled = match horizontal {
+        false => multiplier & 0b0011 != 0,
+        true => multiplier & 0b1100 != 0,
+    };
+
+    if enabled { HIRES_SCROLL_STEP } else { 1 }
+}
@@ -0,0 +1,74 @@
+use crate::DeviceMode;
+use crate::controls::gamepad_report;
+use crate::keys::{Event, Key, KeyEvent};
+use crate::layouts::GamepadLayout;
+
+/// Gamepad used by selector positions with the gamepad backend, e.g. as a sim trim wheel
+pub static GAMEPAD_LAYOUT: GamepadLayout = GamepadLayout {
+    axis_step: 4,
+    encoder_button: 1,
+    key1: 2,
+    key2: 3,
+    key3: 4,
+};
+
+/// Gamepad state driven by the key events of positions using the gamepad backend
+pub struct Gamepad {
+    /// Buttons currently held
+    buttons: u8,
+    /// Keys holding a button, bit n = key with index n
+    held: u8,
+    /// Axis value per mode, like the MIDI encoder values
+    /// [Mode1/Keyboard, Mode2/Picoprog, Mode3/Universal]
+    axes: [u8; 3],
+}
+
+impl Gamepad {
+    pub const fn new() -> Self {
+        Self {
+            buttons: 0,
+            held: 0,
+            axes: [64, 64, 64], // Start at middle (64)
+        }
+    }
+
+    /// Whether `key` holds a gamepad button, so its release belongs to the gamepad
+    pub fn holds(&self, key: Key) -> bool {
+        self.held & (1 << key.index()) != 0
+    }
+
+    /// Report of the held buttons and the axis of `mode`
+    pub fn report(&self, mode: DeviceMode) -> [u8; 3] {
+        gamepad_report(self.buttons, self.axes[mode.index()])
+    }
+
+    /// Update the gamepad for a key event and get the report to send
+    pub fn process(
+        &mut self,
+        layout: &GamepadLayout,
+        key_event: KeyEvent,
+        mode: DeviceMode,
+    ) -> [u8; 3] {
+        let axis = &mut self.axes[mode.index()];
+
+        match key_event.key {
+            Key::EncoderRight => *axis = axis.saturating_add(layout.axis_step).min(127),
+            Key::EncoderLeft => *axis = axis.saturating_sub(layout.axis_step),
+            key => {
+                let button = layout.button(key);
+                match key_event.event {
+                    Event::Pressed => {
+                        self.buttons |= button;
+                        self.held |= 1 << key.index();
+                    }
+                    Event::Released => {
+                        self.buttons &= !button;
+                        self.held &= !(1 << key.index());
+                    }
+                }
+            }
+        }
+
+        gamepad_report(self.buttons, *axis)
+    }
+}
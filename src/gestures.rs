@@ -0,0 +1,234 @@
+use crate::keys::{Event, Key, NUM_KEYS};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// A second press within this time after releasing a key makes a double tap
+pub const DOUBLE_TAP_TERM: Duration = Duration::from_millis(250);
+
+/// Keeping a key pressed for this time makes a long press
+pub const HOLD_TERM: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    Hold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GestureEvent {
+    pub key: Key,
+    pub gesture: Gesture,
+}
+
+/// Gestures recognized from a single key event or timeout
+pub type Gestures = Vec<GestureEvent, NUM_KEYS>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Pressed for the first time, becomes a hold after `HOLD_TERM`
+    Down {
+        since: Instant,
+    },
+    /// Released after a short press, becomes a tap unless pressed again within `DOUBLE_TAP_TERM`
+    Up {
+        since: Instant,
+    },
+    /// Gesture already reported, waiting for the key to be released
+    Done,
+}
+
+/// Recognizes single taps, double taps and long presses from raw key events
+///
+/// Only keys with gesture bindings should be fed to `process`, encoder steps have
+/// no release and can't be recognized. Single taps are reported once the double
+/// tap term expired or another key was pressed, double taps on the second press
+/// and long presses once the hold term expired.
+pub struct GestureRecognizer {
+    double_tap_term: Duration,
+    hold_term: Duration,
+    states: [State; NUM_KEYS],
+}
+
+impl GestureRecognizer {
+    pub const fn new(double_tap_term: Duration, hold_term: Duration) -> Self {
+        Self {
+            double_tap_term,
+            hold_term,
+            states: [State::Idle; NUM_KEYS],
+        }
+    }
+
+    /// Whether a gesture of `key` is in progress, so its events have to go to `process`
+    pub fn is_active(&self, key: Key) -> bool {
+        self.states[key.index()] != State::Idle
+    }
+
+    /// Time at which the next gesture is decided by a timeout, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.states
+            .iter()
+            .filter_map(|state| match *state {
+                State::Down { since } => Some(since + self.hold_term),
+                State::Up { since } => Some(since + self.double_tap_term),
+                State::Idle | State::Done => None,
+            })
+            .min()
+    }
+
+    /// Advance time, reporting gestures decided by their timeouts
+    pub fn tick(&mut self, now: Instant) -> Gestures {
+        let mut gestures = Gestures::new();
+        for (index, state) in self.states.iter_mut().enumerate() {
+            match *state {
+                State::Down { since } if now >= since + self.hold_term => {
+                    *state = State::Done;
+                    let _ = gestures.push(GestureEvent {
+                        key: Key::ALL[index],
+                        gesture: Gesture::Hold,
+                    });
+                }
+                State::Up { since } if now >= since + self.double_tap_term => {
+                    *state = State::Idle;
+                    let _ = gestures.push(GestureEvent {
+                        key: Key::ALL[index],
+                        gesture: Gesture::Tap,
+                    });
+                }
+                _ => {}
+            }
+        }
+        gestures
+    }
+
+    /// Report single taps of all keys other than `key` that are waiting for a second press
+    ///
+    /// Call this when a key without gesture bindings is pressed, to keep the order of key presses.
+    pub fn interrupt(&mut self, key: Key) -> Gestures {
+        let mut gestures = Gestures::new();
+        for (index, state) in self.states.iter_mut().enumerate() {
+            if index != key.index() && matches!(*state, State::Up { .. }) {
+                *state = State::Idle;
+                let _ = gestures.push(GestureEvent {
+                    key: Key::ALL[index],
+                    gesture: Gesture::Tap,
+                });
+            }
+        }
+        gestures
+    }
+
+    /// Feed a raw event of a key with gesture bindings
+    pub fn process(&mut self, key: Key, event: Event, now: Instant) -> Gestures {
+        let mut gestures = self.tick(now);
+        if event == Event::Pressed {
+            for gesture in self.interrupt(key) {
+                let _ = gestures.push(gesture);
+            }
+        }
+
+        let state = &mut self.states[key.index()];
+        match (event, *state) {
+            (Event::Pressed, State::Idle) => *state = State::Down { since: now },
+            (Event::Pressed, State::Up { .. }) => {
+                *state = State::Done;
+                let _ = gestures.push(GestureEvent {
+                    key,
+                    gesture: Gesture::DoubleTap,
+                });
+            }
+            (Event::Released, State::Down { .. }) => *state = State::Up { since: now },
+            (Event::Released, State::Done) => *state = State::Idle,
+            _ => {}
+        }
+
+        gestures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Event::{Pressed, Released};
+    use crate::keys::Key::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn gesture(key: Key, gesture: Gesture) -> GestureEvent {
+        GestureEvent { key, gesture }
+    }
+
+    #[test]
+    fn tap_is_reported_once_double_tap_term_expired() {
+        let mut recognizer = GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM);
+        assert!(recognizer.process(Key1, Pressed, at(0)).is_empty());
+        assert!(recognizer.process(Key1, Released, at(100)).is_empty());
+        assert_eq!(recognizer.deadline(), Some(at(350)));
+        assert!(recognizer.tick(at(349)).is_empty());
+        assert_eq!(recognizer.tick(at(350)), [gesture(Key1, Gesture::Tap)]);
+        assert!(!recognizer.is_active(Key1));
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn second_press_within_double_tap_term_is_double_tap() {
+        let mut recognizer = GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM);
+        recognizer.process(Key1, Pressed, at(0));
+        recognizer.process(Key1, Released, at(100));
+        assert_eq!(
+            recognizer.process(Key1, Pressed, at(349)),
+            [gesture(Key1, Gesture::DoubleTap)]
+        );
+        // Holding the second press doesn't make it a hold as well
+        assert!(recognizer.tick(at(2000)).is_empty());
+        assert!(recognizer.process(Key1, Released, at(2100)).is_empty());
+        assert!(!recognizer.is_active(Key1));
+    }
+
+    #[test]
+    fn second_press_after_double_tap_term_is_another_tap() {
+        let mut recognizer = GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM);
+        recognizer.process(Key1, Pressed, at(0));
+        recognizer.process(Key1, Released, at(100));
+        assert_eq!(
+            recognizer.process(Key1, Pressed, at(350)),
+            [gesture(Key1, Gesture::Tap)]
+        );
+        recognizer.process(Key1, Released, at(400));
+        assert_eq!(recognizer.tick(at(650)), [gesture(Key1, Gesture::Tap)]);
+    }
+
+    #[test]
+    fn press_for_hold_term_is_hold() {
+        let mut recognizer = GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM);
+        recognizer.process(Key1, Pressed, at(0));
+        assert_eq!(recognizer.deadline(), Some(at(500)));
+        assert!(recognizer.tick(at(499)).is_empty());
+        assert_eq!(recognizer.tick(at(500)), [gesture(Key1, Gesture::Hold)]);
+        // The release only ends the gesture
+        assert!(recognizer.is_active(Key1));
+        assert!(recognizer.process(Key1, Released, at(800)).is_empty());
+        assert!(!recognizer.is_active(Key1));
+        assert!(recognizer.tick(at(2000)).is_empty());
+    }
+
+    #[test]
+    fn other_key_press_reports_waiting_tap_first() {
+        let mut recognizer = GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM);
+        recognizer.process(Key1, Pressed, at(0));
+        recognizer.process(Key1, Released, at(100));
+        assert_eq!(
+            recognizer.process(Key2, Pressed, at(150)),
+            [gesture(Key1, Gesture::Tap)]
+        );
+        assert!(!recognizer.is_active(Key1));
+        assert!(recognizer.is_active(Key2));
+
+        // Same for a key without gesture bindings
+        recognizer.process(Key2, Released, at(200));
+        assert_eq!(recognizer.interrupt(Key3), [gesture(Key2, Gesture::Tap)]);
+    }
+}
@@ -1,270 +1,516 @@
-use crate::{EncoderResources, ButtonResources};
-use crate::layouts::{KeyLayout};
-use defmt::unreachable;
+use crate::DeviceMode;
+use crate::automation::{AUTOMATION_EVENTS, AutomationEvent};
+use crate::combos::{COMBO_TERM, Combo, ComboDetector, ComboEvent};
+use crate::controls::{ControlsWriter, dial_report, mouse_report, system_report, telephony_report};
+use crate::gamepad::{GAMEPAD_LAYOUT, Gamepad};
+use crate::gestures::{DOUBLE_TAP_TERM, GestureRecognizer, HOLD_TERM};
+use crate::keyboard::{KeyboardWriter, PressedKeys};
+use crate::keycodes;
+use crate::keys::{Event, KEY_EVENT_QUEUE, Key, KeyEvent};
+use crate::layers::{LayerResolver, Resolved};
+use crate::layouts::{
+    AutoRepeat, Backend, DialAction, KeyAction, KeyLayout, KeyType, MouseAction, PhoneAction,
+    PresentationLayout, TapDance,
+};
+use crate::macros::MacroStep;
+use crate::presentation::{self, PRESENTATION_LAYOUT};
+use crate::presenter::Presenter;
+use crate::usb_state;
+use crate::via;
+use core::future::pending;
 use defmt_rtt as _;
-use embassy_executor::{InterruptExecutor, Spawner};
-use embassy_futures::select::select_array;
-use embassy_rp::gpio::{Input, Level, Pull};
-use embassy_rp::interrupt;
-use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_futures::select::{Either4, select4};
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::Driver;
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::class::hid::HidReaderWriter;
 use usbd_hid::descriptor::*;
-use embassy_sync::pubsub::PubSubChannel;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-type CustomHid = HidReaderWriter<'static, Driver<'static, USB>, 1, 8>;
-static KEY_EVENT_QUEUE: PubSubChannel::<CriticalSectionRawMutex, KeyEvent, 2, 2, 2> = PubSubChannel::new();
-
-#[derive(Clone)]
-#[derive(PartialEq)]
-enum Key {
-    EncoderLeft,
-    EncoderRight,
-    EncoderButton,
-    Key1,
-    Key2,
-    Key3,
-}
 
-#[derive(Clone)]
-#[derive(PartialEq)]
-enum Event {
-    Pressed,
-    Released,
-}
-#[derive(Clone)]
-struct KeyEvent {
-    key: Key,
-    event: Event,
-}
+pub type CustomHid = HidReaderWriter<'static, Driver<'static, USB>, 1, 8>;
 
-pub enum KeyType {
-    Media(MediaKey),
-    Keycode(KeyboardUsage),
-}
+/// Layer-tap keys released within this time send their tap code, otherwise they hold their layer
+const TAPPING_TERM: Duration = Duration::from_millis(200);
 
-const KEYLAYOUT:KeyLayout = KeyLayout {
-    encoder_left: KeyType::Media(MediaKey::VolumeDecrement),
-    encoder_right: KeyType::Media(MediaKey::VolumeIncrement),
-    encoder_button: KeyType::Media(MediaKey::Mute),
-    key1: KeyType::Keycode(KeyboardUsage::KeyboardOo),
-    key2: KeyType::Keycode(KeyboardUsage::KeyboardSs),
-    key3: KeyType::Keycode(KeyboardUsage::KeyboardFf),
+/// Timing of the volume keys on layer 3
+const VOLUME_REPEAT: AutoRepeat = AutoRepeat {
+    delay: Duration::from_millis(400),
+    interval: Duration::from_millis(80),
 };
 
+/// Number of layers of the HID keymap
+pub const NUM_LAYERS: usize = 4;
+
+/// HID keymap, layer 0 is the base layer, VIA can replace the actions of every layer
+pub static KEYMAP: [KeyLayout; NUM_LAYERS] = [
+    // Layer 0: volume knob with mute, keys o s f, holding key3 activates layer 1
+    KeyLayout {
+        encoder_left: KeyAction::Key(KeyType::Media(MediaKey::VolumeDecrement)),
+        encoder_right: KeyAction::Key(KeyType::Media(MediaKey::VolumeIncrement)),
+        encoder_button: KeyAction::Key(KeyType::Media(MediaKey::Mute)),
+        key1: KeyAction::Key(KeyType::Keycode(KeyboardUsage::KeyboardOo)),
+        key2: KeyAction::Key(KeyType::Keycode(KeyboardUsage::KeyboardSs)),
+        key3: KeyAction::LayerTap {
+            layer: 1,
+            tap: KeyType::Keycode(KeyboardUsage::KeyboardFf),
+        },
+    },
+    // Layer 1: media transport, key1 tap = play/pause, double tap = next, hold = previous track,
+    // key2 activates the mouse layer for the next key press
+    KeyLayout {
+        encoder_left: KeyAction::Key(KeyType::Media(MediaKey::PrevTrack)),
+        encoder_right: KeyAction::Key(KeyType::Media(MediaKey::NextTrack)),
+        encoder_button: KeyAction::ToggleLayer(3),
+        key1: KeyAction::TapDance(TapDance {
+            tap: KeyType::Media(MediaKey::PlayPause),
+            double_tap: KeyType::Media(MediaKey::NextTrack),
+            hold: KeyType::Media(MediaKey::PrevTrack),
+        }),
+        key2: KeyAction::OneShotLayer(2),
+        key3: KeyAction::Transparent,
+    },
+    // Layer 2: mouse, the encoder scrolls horizontally, four steps at a time while its button
+    // is held, key3 keeps the layer on until it is pressed again
+    KeyLayout {
+        encoder_left: KeyAction::Key(KeyType::Mouse(MouseAction::ScrollLeft)),
+        encoder_right: KeyAction::Key(KeyType::Mouse(MouseAction::ScrollRight)),
+        encoder_button: KeyAction::EncoderTaps(4),
+        key1: KeyAction::Key(KeyType::Mouse(MouseAction::LeftClick)),
+        key2: KeyAction::Key(KeyType::Mouse(MouseAction::RightClick)),
+        key3: KeyAction::ToggleLayer(2),
+    },
+    // Layer 3: radial controller, key1 and key2 turn the volume down and up and keep changing
+    // it while held, key3 switches the layer off again
+    KeyLayout {
+        encoder_left: KeyAction::Key(KeyType::Dial(DialAction::RotateLeft)),
+        encoder_right: KeyAction::Key(KeyType::Dial(DialAction::RotateRight)),
+        encoder_button: KeyAction::Key(KeyType::Dial(DialAction::Press)),
+        key1: KeyAction::Repeat {
+            code: KeyType::Media(MediaKey::VolumeDecrement),
+            repeat: VOLUME_REPEAT,
+        },
+        key2: KeyAction::Repeat {
+            code: KeyType::Media(MediaKey::VolumeIncrement),
+            repeat: VOLUME_REPEAT,
+        },
+        key3: KeyAction::ToggleLayer(3),
+    },
+];
+
+/// Keys pressed together within `COMBO_TERM`, sending a code instead of their own actions,
+/// per layer of `KEYMAP`
+///
+/// Only the combos of the highest active layer are detected, so the keys of the other
+/// layers aren't held back. The combos of layer 0 leave out key3, its layer-tap key.
+static COMBOS: [&[Combo<KeyType>]; NUM_LAYERS] = [
+    &[
+        Combo {
+            keys: &[Key::EncoderButton, Key::Key1],
+            action: KeyType::Media(MediaKey::PlayPause),
+        },
+        // Microphone mute of the current call, shown on the LEDs once the host confirms it
+        Combo {
+            keys: &[Key::Key1, Key::Key2],
+            action: KeyType::Phone(PhoneAction::Mute),
+        },
+    ],
+    &[],
+    &[],
+    &[],
+];
+
 #[embassy_executor::task]
-pub async fn hid_task(spawner: Spawner, mut keyboard_class: CustomHid, mut multimedia_class: CustomHid, button_resources: ButtonResources, encoder_resources: EncoderResources) -> ! {
+pub async fn hid_task(
+    keyboard: KeyboardWriter,
+    multimedia_class: CustomHid,
+    controls: ControlsWriter,
+) -> ! {
+    let mut sub = KEY_EVENT_QUEUE.subscriber().unwrap();
 
-    interrupt::SWI_IRQ_0.set_priority(Priority::P2);
-    let spawner_encoder: embassy_executor::SendSpawner = EXECUTOR_ENCODER.start(interrupt::SWI_IRQ_0);
-    spawner_encoder.spawn(encoder_task(encoder_resources)).unwrap();
+    let mut interfaces = HidInterfaces {
+        keyboard,
+        pressed_keys: PressedKeys::new(),
+        media: multimedia_class,
+        controls,
+        consumer_usage: 0,
+        mouse_buttons: 0,
+        dial_pressed: false,
+    };
 
-    spawner.spawn(button_task(button_resources)).unwrap();
-
-    let mut sub = KEY_EVENT_QUEUE.subscriber().unwrap();
+    let mut gamepad = Gamepad::new();
+    let mut presenter = Presenter::new();
+    let mut combos = ComboDetector::new(COMBO_TERM);
+    // Codes of active combos, taken from the layer active when they were pressed
+    let mut combo_codes: heapless::FnvIndexMap<usize, KeyType, 2> = heapless::FnvIndexMap::new();
+    let mut resolver = LayerResolver::new(
+        &KEYMAP,
+        TAPPING_TERM,
+        GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM),
+    );
 
     loop {
-        let key_event: KeyEvent = sub.next_message_pure().await;
-
-        match key_event.key {
-            Key::EncoderLeft => {
-                (keyboard_class, multimedia_class) = handle_encoder_interaction(keyboard_class, multimedia_class, KEYLAYOUT.encoder_left).await;
-            },
-            Key::EncoderRight => {
-                (keyboard_class, multimedia_class) = handle_encoder_interaction(keyboard_class, multimedia_class, KEYLAYOUT.encoder_right).await;
-            },
-            Key::EncoderButton => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.encoder_button, key_event.event).await;
-            },
-            Key::Key1 => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.key1, key_event.event).await;
-            },
-            Key::Key2 => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.key2, key_event.event).await;
-            },
-            Key::Key3 => {
-                (keyboard_class, multimedia_class) = send_code(keyboard_class, multimedia_class, KEYLAYOUT.key3,key_event.event).await;
+        // Wake up when buffered combo keys, a pending layer-tap key or a gesture is
+        // decided by its timeout
+        let deadline = [combos.deadline(), resolver.deadline()]
+            .into_iter()
+            .flatten()
+            .min();
+        let timeout = async {
+            match deadline {
+                Some(deadline) => Timer::at(deadline).await,
+                None => pending().await,
             }
-        }
-    }
-}
-
-
-static EXECUTOR_ENCODER: InterruptExecutor = InterruptExecutor::new();
+        };
+        let key_event: Option<KeyEvent> = match select4(
+            sub.next_message_pure(),
+            AUTOMATION_EVENTS.receive(),
+            timeout,
+            usb_state::RESUMED.wait(),
+        )
+        .await
+        {
+            Either4::First(key_event) => Some(key_event),
+            Either4::Second(event) => {
+                interfaces.send_automation(event).await;
+                continue;
+            }
+            Either4::Third(()) => None,
+            Either4::Fourth(()) => {
+                let current_mode = *crate::CURRENT_MODE.lock().await;
+                interfaces.send_state(&gamepad, current_mode).await;
+                continue;
+            }
+        };
 
-#[interrupt]
-unsafe fn SWI_IRQ_0() {
-    unsafe { EXECUTOR_ENCODER.on_interrupt() }
-}
+        // Keys remapped with VIA take effect from the next key event on
+        if via::KEYMAP_CHANGED.try_take().is_some() {
+            for layer in 0..NUM_LAYERS {
+                for key in Key::ALL {
+                    resolver.set_action(layer, key, via::action(layer, key));
+                }
+            }
+        }
 
-#[embassy_executor::task]
-pub async fn encoder_task(r: EncoderResources) -> ! {
+        let now = Instant::now();
+        let layer_combos = COMBOS[resolver.highest_layer()];
+        let events = match key_event {
+            Some(key_event) => {
+                // Any key press wakes up a suspended host if it allowed remote wakeup. The
+                // key events still update the state of the reports, which are sent once the
+                // host resumes, so keys released meanwhile don't get stuck
+                if usb_state::is_suspended() && key_event.event == Event::Pressed {
+                    crate::REMOTE_WAKEUP.signal(());
+                }
 
-    let encoder_left: Input<'_> = Input::new(r.encoder_left, Pull::None);
+                let current_mode = {
+                    let mode = crate::CURRENT_MODE.lock().await;
+                    *mode
+                };
+                let backend = current_mode.backend();
 
-    let mut encoder_right: Input<'_> = Input::new(r.encoder_right, Pull::None);
+                // Positions handled by the MIDI backend don't produce key presses, but keys
+                // pressed before a mode switch still get their matching release
+                if key_event.event == Event::Pressed && backend == Backend::Midi {
+                    continue;
+                }
 
-    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
+                // The gamepad gets the presses of its positions and the releases of its buttons
+                let for_gamepad = match key_event.event {
+                    Event::Pressed => backend == Backend::Gamepad,
+                    Event::Released => gamepad.holds(key_event.key),
+                };
+                if for_gamepad {
+                    let report = gamepad.process(&GAMEPAD_LAYOUT, key_event, current_mode);
+                    interfaces.write_controls(&report, "gamepad").await;
+                    continue;
+                }
 
-    loop {
-        encoder_right.wait_for_falling_edge().await;
+                // Same for the presentation remote
+                let for_presenter = match key_event.event {
+                    Event::Pressed => backend == Backend::Presentation,
+                    Event::Released => presenter.holds(key_event.key),
+                };
+                if for_presenter {
+                    let layout = PresentationLayout {
+                        encoder: via::presentation_encoder(),
+                        ..PRESENTATION_LAYOUT
+                    };
+                    for (code, event) in presenter.process(&layout, key_event, now) {
+                        interfaces.send_code(code, event).await;
+                    }
+                    presentation::set_talk_started(presenter.talk_started());
+                    continue;
+                }
 
-        if encoder_left.get_level() == Level::Low {
-            publisher.publish_immediate(KeyEvent {key: Key::EncoderLeft, event: Event::Pressed});
-        } else {
-            publisher.publish_immediate(KeyEvent {key: Key::EncoderRight, event: Event::Pressed});
+                combos.process(layer_combos, key_event, now)
+            }
+            None => combos.tick(layer_combos, now),
         };
 
-        encoder_right.wait_for_rising_edge().await;
+        for resolved in resolver.tick(now) {
+            interfaces.send_resolved(resolved).await;
+        }
+
+        for event in events {
+            match event {
+                ComboEvent::Key(key_event) => {
+                    for resolved in resolver.process(key_event.key, key_event.event, now) {
+                        interfaces.send_resolved(resolved).await;
+                    }
+                }
+                ComboEvent::Combo { index, event } => {
+                    let code = match event {
+                        Event::Pressed => layer_combos.get(index).map(|combo| {
+                            let _ = combo_codes.insert(index, combo.action);
+                            combo.action
+                        }),
+                        Event::Released => combo_codes.remove(&index),
+                    };
+                    if let Some(code) = code {
+                        interfaces.send_code(code, event).await;
+                    }
+                }
+            }
+        }
     }
 }
 
-#[embassy_executor::task]
-pub async fn button_task(r: ButtonResources) -> ! {
-
-    let mut key1: Input<'_> = Input::new(r.key1, Pull::Up);
-    key1.set_schmitt(true);
-
-    let mut key2: Input<'_> = Input::new(r.key2, Pull::Up);
-    key2.set_schmitt(true);
-
-    let mut key3: Input<'_> = Input::new(r.key3, Pull::Up);
-    key3.set_schmitt(true);
-
-    let mut encoder_button: Input<'_> = Input::new(r.encoder_button, Pull::Up);
-    encoder_button.set_schmitt(true);
-
-    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
-
-    loop {
+/// HID interfaces and the state of their reports
+struct HidInterfaces {
+    keyboard: KeyboardWriter,
+    /// Keyboard usages currently held
+    pressed_keys: PressedKeys,
+    media: CustomHid,
+    /// Consumer usage currently held
+    consumer_usage: u16,
+    controls: ControlsWriter,
+    /// Mouse buttons currently held
+    mouse_buttons: u8,
+    /// Radial controller button currently held
+    dial_pressed: bool,
+}
 
-        let (_, index) = select_array([
-            key1.wait_for_any_edge(),
-            key2.wait_for_any_edge(),
-            key3.wait_for_any_edge(),
-            encoder_button.wait_for_any_edge(),
-        ])
-        .await;
-
-        match index {
-            0 => {
-                match key1.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::Key1, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::Key1, event: Event::Released}),
-                }
+impl HidInterfaces {
+    async fn send_resolved(&mut self, resolved: Resolved) {
+        match resolved {
+            Resolved::Press(code) => self.send_code(code, Event::Pressed).await,
+            Resolved::Release(code) => self.send_code(code, Event::Released).await,
+            Resolved::Tap(code) => {
+                self.send_code(code, Event::Pressed).await;
+                self.send_code(code, Event::Released).await;
             }
-            1 => {
-                match key2.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::Key2, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::Key2, event: Event::Released}),
+        }
+    }
+
+    async fn send_code(&mut self, code: KeyType, event: Event) {
+        match code {
+            // Macros play once per press
+            KeyType::Macro(index) => {
+                if event == Event::Pressed {
+                    self.play_macro(index).await;
                 }
             }
-            2 => {
-                match key3.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::Key3, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::Key3, event: Event::Released}),
-                }
+            code => self.send_report(code, event).await,
+        }
+    }
+
+    /// Play the steps of a VIA macro, stopping if the host suspends meanwhile
+    async fn play_macro(&mut self, index: u8) {
+        for step in via::macro_steps(index) {
+            if usb_state::is_suspended() {
+                return;
             }
-            3 => {
-                match encoder_button.get_level() {
-                    Level::Low => publisher.publish_immediate(KeyEvent {key: Key::EncoderButton, event: Event::Pressed}),
-                    Level::High => publisher.publish_immediate(KeyEvent {key: Key::EncoderButton, event: Event::Released}),
+
+            match step {
+                MacroStep::Char(c) => self.type_char(c).await,
+                MacroStep::Tap(keycode) => {
+                    self.press_keycode(keycode).await;
+                    self.release_keycode(keycode).await;
                 }
+                MacroStep::Press(keycode) => self.press_keycode(keycode).await,
+                MacroStep::Release(keycode) => self.release_keycode(keycode).await,
+                MacroStep::Delay(delay) => Timer::after(delay).await,
             }
-            _ => unreachable!(),
-        };
+        }
     }
-}
 
+    /// Press the modifiers of a macro keycode and then its code
+    async fn press_keycode(&mut self, keycode: u16) {
+        for modifier in keycodes::modifiers(keycode) {
+            self.send_report(modifier, Event::Pressed).await;
+        }
+        if let Some(code) = keycodes::code(keycode) {
+            self.send_report(code, Event::Pressed).await;
+        }
+    }
 
-async fn handle_encoder_interaction(mut keyboard_class: CustomHid, mut media_class: CustomHid, code: KeyType) -> (CustomHid, CustomHid) {
+    /// Release the code of a macro keycode and then its modifiers
+    async fn release_keycode(&mut self, keycode: u16) {
+        if let Some(code) = keycodes::code(keycode) {
+            self.send_report(code, Event::Released).await;
+        }
+        for modifier in keycodes::modifiers(keycode).into_iter().rev() {
+            self.send_report(modifier, Event::Released).await;
+        }
+    }
 
-    match code {
-        KeyType::Media(media_key) =>    {
+    /// Send a key event of the automation commands on the UART header
+    async fn send_automation(&mut self, event: AutomationEvent) {
+        // Like key presses, commands wake up the suspended host and only update the reports
+        if usb_state::is_suspended() && !matches!(event, AutomationEvent::Release(_)) {
+            crate::REMOTE_WAKEUP.signal(());
+        }
 
-            let mut report = MediaKeyboardReport {
-                usage_id: media_key as u16,
-            };
+        match event {
+            AutomationEvent::Press(usage) => self.send_usage(usage, Event::Pressed).await,
+            AutomationEvent::Release(usage) => self.send_usage(usage, Event::Released).await,
+            AutomationEvent::Char(c) => self.type_char(c).await,
+        }
+    }
 
-            if let Err(e) = media_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
+    /// Type an ASCII character with a US layout, adding Shift if needed
+    async fn type_char(&mut self, c: u8) {
+        if let Some((usage, shift)) = keycodes::ascii_usage(c) {
+            let left_shift = KeyboardUsage::KeyboardLeftShift as u8;
+            if shift {
+                self.send_usage(left_shift, Event::Pressed).await;
             }
-
-            report = MediaKeyboardReport {
-                usage_id: 0x00 as u16,
-            };
-
-            if let Err(e) = media_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
+            self.send_usage(usage, Event::Pressed).await;
+            self.send_usage(usage, Event::Released).await;
+            if shift {
+                self.send_usage(left_shift, Event::Released).await;
             }
-        },
-
-        KeyType::Keycode(keyboard_usage) => {
-            let keycodes: [u8; 6] = [keyboard_usage as u8, 0, 0, 0, 0, 0];
+        }
+    }
 
-            let mut report: KeyboardReport = KeyboardReport {
-                keycodes: keycodes,
-                leds: 0,
-                modifier: 0,
-                reserved: 0,
-            };
+    /// Send the report for a code, macros are played by `send_code`
+    async fn send_report(&mut self, code: KeyType, event: Event) {
+        match code {
+            KeyType::Media(media_key) => self.send_consumer(media_key as u16, event).await,
+
+            KeyType::Consumer(usage) => self.send_consumer(usage, event).await,
+
+            KeyType::Keycode(keyboard_usage) => self.send_usage(keyboard_usage as u8, event).await,
+
+            KeyType::Mouse(action) => {
+                let report = match (event, action.button()) {
+                    // Wheel steps and pointer moves are sent on press only
+                    (Event::Pressed, 0) => mouse_report(self.mouse_buttons, Some(action)),
+                    (Event::Released, 0) => return,
+                    (Event::Pressed, button) => {
+                        self.mouse_buttons |= button;
+                        mouse_report(self.mouse_buttons, None)
+                    }
+                    (Event::Released, button) => {
+                        self.mouse_buttons &= !button;
+                        mouse_report(self.mouse_buttons, None)
+                    }
+                };
+
+                self.write_controls(&report, "mouse").await;
+            }
 
-            if let Err(e) = keyboard_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
+            KeyType::Dial(action) => {
+                let report = match (event, action) {
+                    // Rotation steps are sent on press only
+                    (Event::Released, DialAction::RotateLeft | DialAction::RotateRight) => return,
+                    (Event::Pressed, DialAction::RotateLeft | DialAction::RotateRight) => {
+                        dial_report(self.dial_pressed, Some(action))
+                    }
+                    (event, DialAction::Press) => {
+                        self.dial_pressed = event == Event::Pressed;
+                        dial_report(self.dial_pressed, None)
+                    }
+                };
+
+                self.write_controls(&report, "dial").await;
             }
 
-            report.keycodes = [0,0,0,0,0,0];
+            KeyType::Phone(action) => {
+                let report = match (event, action) {
+                    // Keep the new hook state until the host confirms it with its off-hook LED
+                    (Event::Released, PhoneAction::HookSwitch) => return,
+                    (Event::Pressed, action) => telephony_report(Some(action)),
+                    (Event::Released, _) => telephony_report(None),
+                };
 
-            if let Err(e) = keyboard_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
+                self.write_controls(&report, "telephony").await;
             }
-        },
-    };
 
+            KeyType::System(action) => {
+                let report = match event {
+                    Event::Pressed => system_report(Some(action)),
+                    Event::Released => system_report(None),
+                };
 
+                self.write_controls(&report, "system control").await;
+            }
 
-    return (keyboard_class, media_class)
-}
-
-async fn send_code(mut keyboard_class: CustomHid, mut media_class: CustomHid , code: KeyType, event: Event) -> (CustomHid, CustomHid) {
+            KeyType::Macro(_) => {}
+        };
+    }
 
-    match code {
-        KeyType::Media(media_key) =>    {
+    async fn send_usage(&mut self, usage: u8, event: Event) {
+        match event {
+            Event::Pressed => self.pressed_keys.press(usage),
+            Event::Released => self.pressed_keys.release(usage),
+        }
+        self.write_keyboard().await;
+    }
 
-            let code = match event {
-                Event::Pressed => media_key as u16,
-                Event::Released => 0x00 as u16,
-            };
+    async fn send_consumer(&mut self, usage: u16, event: Event) {
+        self.consumer_usage = match event {
+            Event::Pressed => usage,
+            Event::Released => 0x00,
+        };
+        self.write_consumer().await;
+    }
 
-            let report = MediaKeyboardReport {
-                usage_id: code,
-            };
+    /// Send the state of all reports, after the host resumed from a suspend in which the
+    /// reports were only updated
+    async fn send_state(&mut self, gamepad: &Gamepad, mode: DeviceMode) {
+        self.write_keyboard().await;
+        self.write_consumer().await;
+        let report = mouse_report(self.mouse_buttons, None);
+        self.write_controls(&report, "mouse").await;
+        let report = dial_report(self.dial_pressed, None);
+        self.write_controls(&report, "dial").await;
+        self.write_controls(&telephony_report(None), "telephony")
+            .await;
+        self.write_controls(&system_report(None), "system control")
+            .await;
+        self.write_controls(&gamepad.report(mode), "gamepad").await;
+    }
 
-            if let Err(e) = media_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
-            }
-        },
+    /// Send the keyboard report, unless the host is suspended and the endpoint would block
+    async fn write_keyboard(&mut self) {
+        if usb_state::is_suspended() {
+            return;
+        }
+        if let Err(e) = self.keyboard.write(&self.pressed_keys).await {
+            log::error!("Failed to send HID key press: {:?}", e);
+        }
+    }
 
-        KeyType::Keycode(keyboard_usage) => {
-            let keycodes: [u8; 6] = if event == Event::Pressed {
-                [keyboard_usage as u8, 0, 0, 0, 0, 0]
-            } else {
-                [0, 0, 0, 0, 0, 0]
-            };
-
-            let report: KeyboardReport = KeyboardReport {
-                keycodes: keycodes,
-                leds: 0,
-                modifier: 0,
-                reserved: 0,
-            };
-
-            if let Err(e) = keyboard_class.write_serialize(&report).await {
-                log::error!("Failed to send HID key press: {:?}", e);
-            }
-        },
-    };
+    /// Send the consumer report, unless the host is suspended
+    async fn write_consumer(&mut self) {
+        if usb_state::is_suspended() {
+            return;
+        }
+        let report = MediaKeyboardReport {
+            usage_id: self.consumer_usage,
+        };
+        if let Err(e) = self.media.write_serialize(&report).await {
+            log::error!("Failed to send HID key press: {:?}", e);
+        }
+    }
 
-    return (keyboard_class, media_class);
-}
\ No newline at end of file
+    /// Send a report of the controls interface, unless the host is suspended
+    async fn write_controls(&mut self, report: &[u8], name: &str) {
+        if usb_state::is_suspended() {
+            return;
+        }
+        if let Err(e) = self.controls.write(report).await {
+            log::error!("Failed to send HID {} report: {:?}", name, e);
+        }
+    }
+}
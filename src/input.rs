@@ -0,0 +1,103 @@
+use crate::keys::{Event, KEY_EVENT_QUEUE, Key, KeyEvent};
+use crate::{ButtonResources, EncoderResources};
+use defmt::unreachable;
+use embassy_executor::{InterruptExecutor, Spawner};
+use embassy_futures::select::select_array;
+use embassy_rp::gpio::{Input, Level, Pull};
+use embassy_rp::interrupt;
+use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_time::{Duration, Timer};
+
+/// Spawn the button and encoder tasks feeding `KEY_EVENT_QUEUE`
+pub fn spawn_input_tasks(
+    spawner: Spawner,
+    button_resources: ButtonResources,
+    encoder_resources: EncoderResources,
+) {
+    interrupt::SWI_IRQ_0.set_priority(Priority::P2);
+    let spawner_encoder: embassy_executor::SendSpawner =
+        EXECUTOR_ENCODER.start(interrupt::SWI_IRQ_0);
+    spawner_encoder
+        .spawn(encoder_task(encoder_resources))
+        .unwrap();
+
+    spawner.spawn(button_task(button_resources)).unwrap();
+}
+
+static EXECUTOR_ENCODER: InterruptExecutor = InterruptExecutor::new();
+
+#[interrupt]
+unsafe fn SWI_IRQ_0() {
+    unsafe { EXECUTOR_ENCODER.on_interrupt() }
+}
+
+#[embassy_executor::task]
+async fn encoder_task(r: EncoderResources) -> ! {
+    let encoder_left: Input<'_> = Input::new(r.encoder_left, Pull::None);
+    let mut encoder_right: Input<'_> = Input::new(r.encoder_right, Pull::None);
+
+    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
+
+    loop {
+        encoder_right.wait_for_falling_edge().await;
+
+        if encoder_left.get_level() == Level::Low {
+            publisher.publish_immediate(KeyEvent {
+                key: Key::EncoderLeft,
+                event: Event::Pressed,
+            });
+        } else {
+            publisher.publish_immediate(KeyEvent {
+                key: Key::EncoderRight,
+                event: Event::Pressed,
+            });
+        };
+
+        encoder_right.wait_for_rising_edge().await;
+    }
+}
+
+#[embassy_executor::task]
+async fn button_task(r: ButtonResources) -> ! {
+    let mut key1: Input<'_> = Input::new(r.key1, Pull::Up);
+    key1.set_schmitt(true);
+
+    let mut key2: Input<'_> = Input::new(r.key2, Pull::Up);
+    key2.set_schmitt(true);
+
+    let mut key3: Input<'_> = Input::new(r.key3, Pull::Up);
+    key3.set_schmitt(true);
+
+    let mut encoder_button: Input<'_> = Input::new(r.encoder_button, Pull::Up);
+    encoder_button.set_schmitt(true);
+
+    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
+
+    loop {
+        let (_, index) = select_array([
+            key1.wait_for_any_edge(),
+            key2.wait_for_any_edge(),
+            key3.wait_for_any_edge(),
+            encoder_button.wait_for_any_edge(),
+        ])
+        .await;
+
+        // Small debounce delay to avoid reading bounce
+        Timer::after(Duration::from_millis(5)).await;
+
+        let (key, level) = match index {
+            0 => (Key::Key1, key1.get_level()),
+            1 => (Key::Key2, key2.get_level()),
+            2 => (Key::Key3, key3.get_level()),
+            3 => (Key::EncoderButton, encoder_button.get_level()),
+            _ => unreachable!(),
+        };
+
+        let event = match level {
+            Level::Low => Event::Pressed,
+            Level::High => Event::Released,
+        };
+
+        publisher.publish_immediate(KeyEvent { key, event });
+    }
+}
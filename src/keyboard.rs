@@ -0,0 +1,321 @@
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Endpoint, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+use static_cell::StaticCell;
+
+type EpIn = <Driver<'static, USB> as embassy_usb::driver::Driver<'static>>::EndpointIn;
+type EpOut = <Driver<'static, USB> as embassy_usb::driver::Driver<'static>>::EndpointOut;
+
+const USB_CLASS_HID: u8 = 0x03;
+const USB_SUBCLASS_BOOT: u8 = 0x01;
+const USB_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+
+/// Report types in the high byte of GET_REPORT and SET_REPORT
+const HID_REPORT_TYPE_OUTPUT: u8 = 0x02;
+
+const PROTOCOL_BOOT: u8 = 0;
+const PROTOCOL_REPORT: u8 = 1;
+
+const MAX_PACKET_SIZE: u16 = 32;
+const POLL_MS: u8 = 10;
+
+/// Usages 0x00-0xDF reported as a bitmap, the modifiers 0xE0-0xE7 have their own byte
+const NKRO_KEYS: usize = 0xE0;
+
+/// Modifier byte followed by the key bitmap
+const NKRO_REPORT_SIZE: usize = 1 + NKRO_KEYS / 8;
+
+/// Modifier byte, reserved byte and up to 6 keys
+const BOOT_REPORT_SIZE: usize = 8;
+
+/// Usage reported in all key slots of a boot report when more than 6 keys are pressed
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Lock LEDs set by the host, bit 0 = Num Lock, bit 1 = Caps Lock, bit 2 = Scroll Lock
+static KEYBOARD_LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Whether the host switched the keyboard to the boot protocol, e.g. in a BIOS setup
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+/// N-key-rollover report descriptor used with the report protocol
+#[rustfmt::skip]
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,             // Usage Page (Generic Desktop)
+    0x09, 0x06,             // Usage (Keyboard)
+    0xA1, 0x01,             // Collection (Application)
+    0x05, 0x07,             //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,             //   Usage Minimum (Left Control)
+    0x29, 0xE7,             //   Usage Maximum (Right GUI)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x25, 0x01,             //   Logical Maximum (1)
+    0x75, 0x01,             //   Report Size (1)
+    0x95, 0x08,             //   Report Count (8)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute)
+    0x19, 0x00,             //   Usage Minimum (0)
+    0x29, 0xDF,             //   Usage Maximum (0xDF)
+    0x95, 0xE0,             //   Report Count (224)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute)
+    0x05, 0x08,             //   Usage Page (LEDs)
+    0x19, 0x01,             //   Usage Minimum (Num Lock)
+    0x29, 0x05,             //   Usage Maximum (Kana)
+    0x95, 0x05,             //   Report Count (5)
+    0x91, 0x02,             //   Output (Data, Variable, Absolute)
+    0x75, 0x03,             //   Report Size (3)
+    0x95, 0x01,             //   Report Count (1)
+    0x91, 0x03,             //   Output (Constant)
+    0xC0,                   // End Collection
+];
+
+/// HID class descriptor without its length and type
+#[rustfmt::skip]
+const HID_DESCRIPTOR: [u8; 7] = [
+    0x11, 0x01,             // HID version 1.11
+    0x00,                   // Country code not supported
+    0x01,                   // One report descriptor
+    HID_DESC_DESCTYPE_HID_REPORT,
+    KEYBOARD_REPORT_DESCRIPTOR.len() as u8,
+    (KEYBOARD_REPORT_DESCRIPTOR.len() >> 8) as u8,
+];
+
+/// Keyboard usages currently pressed, one bit per usage
+pub struct PressedKeys {
+    bits: [u8; 32],
+}
+
+impl PressedKeys {
+    pub const fn new() -> Self {
+        Self { bits: [0; 32] }
+    }
+
+    pub fn press(&mut self, usage: u8) {
+        // Usage 0 means no key and never shows up in a report
+        if usage != 0 {
+            self.bits[usage as usize / 8] |= 1 << (usage % 8);
+        }
+    }
+
+    pub fn release(&mut self, usage: u8) {
+        self.bits[usage as usize / 8] &= !(1 << (usage % 8));
+    }
+
+    /// Bits of the pressed modifiers, bit 0 = Left Control to bit 7 = Right GUI
+    fn modifiers(&self) -> u8 {
+        self.bits[NKRO_KEYS / 8]
+    }
+
+    /// Report with every pressed key, for the report protocol
+    fn nkro_report(&self) -> [u8; NKRO_REPORT_SIZE] {
+        let mut report = [0; NKRO_REPORT_SIZE];
+        report[0] = self.modifiers();
+        report[1..].copy_from_slice(&self.bits[..NKRO_KEYS / 8]);
+        report
+    }
+
+    /// Report with up to 6 pressed keys, for the boot protocol
+    fn boot_report(&self) -> [u8; BOOT_REPORT_SIZE] {
+        let mut report = [0; BOOT_REPORT_SIZE];
+        report[0] = self.modifiers();
+
+        let mut slots = 0;
+        for usage in 0..NKRO_KEYS {
+            if self.bits[usage / 8] & (1 << (usage % 8)) == 0 {
+                continue;
+            }
+            if slots == BOOT_REPORT_SIZE - 2 {
+                report[2..].fill(ERROR_ROLL_OVER);
+                break;
+            }
+            report[2 + slots] = usage as u8;
+            slots += 1;
+        }
+        report
+    }
+}
+
+/// Sends the pressed keys in the format of the protocol selected by the host
+pub struct KeyboardWriter {
+    ep_in: EpIn,
+}
+
+impl KeyboardWriter {
+    pub async fn write(&mut self, keys: &PressedKeys) -> Result<(), EndpointError> {
+        if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+            self.ep_in.write(&keys.boot_report()).await
+        } else {
+            self.ep_in.write(&keys.nkro_report()).await
+        }
+    }
+}
+
+/// Receives the lock LED output reports sent on the interrupt endpoint
+pub struct KeyboardReader {
+    ep_out: EpOut,
+}
+
+/// Keyboard interface that supports the boot protocol, which embassy's HID class doesn't
+///
+/// The interface uses the boot subclass so BIOS and UEFI setups pick it up. With the
+/// report protocol it sends a bitmap of all pressed keys, with the boot protocol the
+/// standard 8 byte report.
+pub fn new_keyboard(
+    builder: &mut Builder<'static, Driver<'static, USB>>,
+) -> (KeyboardReader, KeyboardWriter) {
+    static CONTROL: StaticCell<KeyboardControl> = StaticCell::new();
+
+    let mut func = builder.function(USB_CLASS_HID, USB_SUBCLASS_BOOT, USB_PROTOCOL_KEYBOARD);
+    let mut iface = func.interface();
+    let if_num = iface.interface_number();
+    let mut alt = iface.alt_setting(
+        USB_CLASS_HID,
+        USB_SUBCLASS_BOOT,
+        USB_PROTOCOL_KEYBOARD,
+        None,
+    );
+    alt.descriptor(HID_DESC_DESCTYPE_HID, &HID_DESCRIPTOR);
+    let ep_in = alt.endpoint_interrupt_in(MAX_PACKET_SIZE, POLL_MS);
+    let ep_out = alt.endpoint_interrupt_out(MAX_PACKET_SIZE, POLL_MS);
+    drop(func);
+
+    let mut hid_descriptor = [0; 9];
+    hid_descriptor[0] = hid_descriptor.len() as u8;
+    hid_descriptor[1] = HID_DESC_DESCTYPE_HID;
+    hid_descriptor[2..].copy_from_slice(&HID_DESCRIPTOR);
+
+    builder.handler(CONTROL.init(KeyboardControl {
+        if_num,
+        hid_descriptor,
+        idle: 0,
+    }));
+
+    (KeyboardReader { ep_out }, KeyboardWriter { ep_in })
+}
+
+/// Passes the lock LED output reports received on the interrupt endpoint to `led_task`
+#[embassy_executor::task]
+pub async fn keyboard_output_task(mut reader: KeyboardReader) -> ! {
+    let mut buf = [0; MAX_PACKET_SIZE as usize];
+    loop {
+        match reader.ep_out.read(&mut buf).await {
+            Ok(len) if len > 0 => set_host_leds(buf[0]),
+            Ok(_) => {}
+            Err(EndpointError::Disabled) => reader.ep_out.wait_enabled().await,
+            Err(EndpointError::BufferOverflow) => {
+                log::warn!("[HID]: Keyboard output report too long");
+            }
+        }
+    }
+}
+
+/// Lock LEDs currently set by the host, see `KEYBOARD_LEDS`
+pub fn host_leds() -> u8 {
+    KEYBOARD_LEDS.load(Ordering::Relaxed)
+}
+
+fn set_host_leds(leds: u8) {
+    log::debug!("[HID]: Keyboard LEDs {:#04x}", leds);
+    if KEYBOARD_LEDS.swap(leds, Ordering::Relaxed) != leds {
+        crate::led::INDICATORS_CHANGED.signal(());
+    }
+}
+
+/// Answers the HID class requests of the keyboard interface
+struct KeyboardControl {
+    if_num: InterfaceNumber,
+    hid_descriptor: [u8; 9],
+    /// Idle rate set by the host, reports are only sent on changes anyway
+    idle: u8,
+}
+
+impl Handler for KeyboardControl {
+    fn reset(&mut self) {
+        // The host has to select the boot protocol again after a reset
+        BOOT_PROTOCOL.store(false, Ordering::Relaxed);
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.if_num.0 as u16,
+            )
+        {
+            return None;
+        }
+
+        match req.request {
+            HID_REQ_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                Some(OutResponse::Accepted)
+            }
+            HID_REQ_SET_REPORT => match ((req.value >> 8) as u8, data) {
+                (HID_REPORT_TYPE_OUTPUT, [leds, ..]) => {
+                    set_host_leds(*leds);
+                    Some(OutResponse::Accepted)
+                }
+                _ => Some(OutResponse::Rejected),
+            },
+            HID_REQ_SET_PROTOCOL => match req.value as u8 {
+                protocol @ (PROTOCOL_BOOT | PROTOCOL_REPORT) => {
+                    log::info!("[HID]: Keyboard protocol {}", protocol);
+                    BOOT_PROTOCOL.store(protocol == PROTOCOL_BOOT, Ordering::Relaxed);
+                    Some(OutResponse::Accepted)
+                }
+                _ => Some(OutResponse::Rejected),
+            },
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.index != self.if_num.0 as u16 {
+            return None;
+        }
+
+        match (req.request_type, req.recipient) {
+            (RequestType::Standard, Recipient::Interface) => match req.request {
+                Request::GET_DESCRIPTOR => match (req.value >> 8) as u8 {
+                    HID_DESC_DESCTYPE_HID_REPORT => {
+                        Some(InResponse::Accepted(KEYBOARD_REPORT_DESCRIPTOR))
+                    }
+                    HID_DESC_DESCTYPE_HID => Some(InResponse::Accepted(&self.hid_descriptor)),
+                    _ => Some(InResponse::Rejected),
+                },
+                _ => Some(InResponse::Rejected),
+            },
+            (RequestType::Class, Recipient::Interface) => match req.request {
+                HID_REQ_GET_REPORT if (req.value >> 8) as u8 == HID_REPORT_TYPE_OUTPUT => {
+                    buf[0] = host_leds();
+                    Some(InResponse::Accepted(&buf[..1]))
+                }
+                HID_REQ_GET_IDLE => {
+                    buf[0] = self.idle;
+                    Some(InResponse::Accepted(&buf[..1]))
+                }
+                HID_REQ_GET_PROTOCOL => {
+                    buf[0] = match BOOT_PROTOCOL.load(Ordering::Relaxed) {
+                        true => PROTOCOL_BOOT,
+                        false => PROTOCOL_REPORT,
+                    };
+                    Some(InResponse::Accepted(&buf[..1]))
+                }
+                _ => Some(InResponse::Rejected),
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::layouts::{DialAction, KeyAction, KeyType, MouseAction, PhoneAction, SystemAction};
+use heapless::Vec;
+use usbd_hid::descriptor::KeyboardUsage;
+
+/// No action and transparent
+const KC_NO: u16 = 0x0000;
+const KC_TRNS: u16 = 0x0001;
+
+/// Keyboard usages share their numbers with the basic keycodes
+const KC_BASIC_LAST: u16 = 0x00A4;
+const KC_MODIFIER_FIRST: u16 = 0x00E0;
+const KC_MODIFIER_LAST: u16 = 0x00E7;
+
+/// Basic keycodes with modifiers, e.g. `LSFT(KC_A)`: Ctrl, Shift, Alt and GUI in bits 8-11,
+/// the right-hand ones if bit 12 is set
+const QK_MODS: u16 = 0x0100;
+const QK_MODS_LAST: u16 = 0x1FFF;
+const QK_MODS_RIGHT: u16 = 0x1000;
+
+const KC_SYSTEM_POWER: u16 = 0x00A5;
+const KC_SYSTEM_SLEEP: u16 = 0x00A6;
+const KC_SYSTEM_WAKE: u16 = 0x00A7;
+
+/// First media keycode, see `CONSUMER_KEYCODES`
+const KC_AUDIO_MUTE: u16 = 0x00A8;
+
+/// Consumer usages of the media keycodes, starting at `KC_AUDIO_MUTE`
+const CONSUMER_KEYCODES: [u16; 23] = [
+    0x00E2, // Mute
+    0x00E9, // Volume Increment
+    0x00EA, // Volume Decrement
+    0x00B5, // Scan Next Track
+    0x00B6, // Scan Previous Track
+    0x00B7, // Stop
+    0x00CD, // Play/Pause
+    0x0183, // AL Consumer Control Configuration
+    0x00B8, // Eject
+    0x018A, // AL Email Reader
+    0x0192, // AL Calculator
+    0x0194, // AL Local Machine Browser
+    0x0221, // AC Search
+    0x0223, // AC Home
+    0x0224, // AC Back
+    0x0225, // AC Forward
+    0x0226, // AC Stop
+    0x0227, // AC Refresh
+    0x022A, // AC Bookmarks
+    0x00B3, // Fast Forward
+    0x00B4, // Rewind
+    0x006F, // Display Brightness Increment
+    0x0070, // Display Brightness Decrement
+];
+
+const KC_MS_UP: u16 = 0x00CD;
+const KC_MS_DOWN: u16 = 0x00CE;
+const KC_MS_LEFT: u16 = 0x00CF;
+const KC_MS_RIGHT: u16 = 0x00D0;
+const KC_MS_BTN1: u16 = 0x00D1;
+const KC_MS_BTN2: u16 = 0x00D2;
+const KC_MS_BTN3: u16 = 0x00D3;
+const KC_MS_WH_UP: u16 = 0x00D9;
+const KC_MS_WH_DOWN: u16 = 0x00DA;
+const KC_MS_WH_LEFT: u16 = 0x00DB;
+const KC_MS_WH_RIGHT: u16 = 0x00DC;
+
+/// Layer keycodes, the layer is in the low bits
+const QK_LAYER_TAP: u16 = 0x4000;
+const QK_LAYER_TAP_LAST: u16 = 0x4FFF;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_ONE_SHOT_LAYER: u16 = 0x5280;
+const LAYER_MASK: u16 = 0x001F;
+
+/// Macro keycodes, the macro number is in the low bits
+const QK_MACRO: u16 = 0x7700;
+const QK_MACRO_LAST: u16 = 0x777F;
+
+/// Keyboard specific keycodes, listed as `customKeycodes` in `via/oskar.json` in this order
+const QK_KB: u16 = 0x7E00;
+/// Keeps the action of the firmware keymap, for actions that have no keycode
+pub const KB_DEFAULT: u16 = QK_KB;
+const KB_DIAL_PRESS: u16 = QK_KB + 1;
+const KB_DIAL_LEFT: u16 = QK_KB + 2;
+const KB_DIAL_RIGHT: u16 = QK_KB + 3;
+const KB_PHONE_MUTE: u16 = QK_KB + 4;
+const KB_PHONE_HOOK: u16 = QK_KB + 5;
+const KB_PHONE_FLASH: u16 = QK_KB + 6;
+
+/// Keycode of a keymap action, `KB_DEFAULT` if it has none, e.g. for tap dances
+pub fn keycode(action: KeyAction) -> u16 {
+    match action {
+        KeyAction::Key(code) => code_keycode(code).unwrap_or(KB_DEFAULT),
+        // Layer-tap keycodes only have room for layers 0-15 and basic keycodes
+        KeyAction::LayerTap { layer, tap } => match code_keycode(tap) {
+            Some(keycode) if keycode <= 0xFF && layer < 16 => {
+                QK_LAYER_TAP | ((layer as u16) << 8) | keycode
+            }
+            _ => KB_DEFAULT,
+        },
+        KeyAction::MomentaryLayer(layer) => QK_MOMENTARY | (layer as u16 & LAYER_MASK),
+        KeyAction::ToggleLayer(layer) => QK_TOGGLE_LAYER | (layer as u16 & LAYER_MASK),
+        KeyAction::OneShotLayer(layer) => QK_ONE_SHOT_LAYER | (layer as u16 & LAYER_MASK),
+        KeyAction::TapDance(_) | KeyAction::Repeat { .. } | KeyAction::EncoderTaps(_) => KB_DEFAULT,
+        KeyAction::Transparent => KC_TRNS,
+        KeyAction::NoAction => KC_NO,
+    }
+}
+
+/// Keymap action of a keycode, `None` for `KB_DEFAULT`
+///
+/// Keycodes without a matching action are ignored like `KC_NO`.
+pub fn action(keycode: u16) -> Option<KeyAction> {
+    let action = match keycode {
+        KB_DEFAULT => return None,
+        KC_NO => KeyAction::NoAction,
+        KC_TRNS => KeyAction::Transparent,
+        QK_LAYER_TAP..=QK_LAYER_TAP_LAST => match code(keycode & 0xFF) {
+            Some(tap) => KeyAction::LayerTap {
+                layer: ((keycode >> 8) & 0x0F) as u8,
+                tap,
+            },
+            None => KeyAction::NoAction,
+        },
+        _ if keycode & !LAYER_MASK == QK_MOMENTARY => {
+            KeyAction::MomentaryLayer((keycode & LAYER_MASK) as u8)
+        }
+        _ if keycode & !LAYER_MASK == QK_TOGGLE_LAYER => {
+            KeyAction::ToggleLayer((keycode & LAYER_MASK) as u8)
+        }
+        _ if keycode & !LAYER_MASK == QK_ONE_SHOT_LAYER => {
+            KeyAction::OneShotLayer((keycode & LAYER_MASK) as u8)
+        }
+        QK_MACRO..=QK_MACRO_LAST => KeyAction::Key(KeyType::Macro((keycode - QK_MACRO) as u8)),
+        // A key action sends a single code, which can't hold the modifiers
+        QK_MODS..=QK_MODS_LAST => KeyAction::NoAction,
+        _ => code(keycode).map_or(KeyAction::NoAction, KeyAction::Key),
+    };
+    Some(action)
+}
+
+/// Code sent for a keycode that stands for a single code, e.g. a key or media keycode
+///
+/// Keycodes with modifiers give the code of their key, see `modifiers`.
+pub fn code(keycode: u16) -> Option<KeyType> {
+    let code = match keycode {
+        QK_MODS..=QK_MODS_LAST => return code(keycode & 0xFF),
+        0x0004..=KC_BASIC_LAST | KC_MODIFIER_FIRST..=KC_MODIFIER_LAST => {
+            KeyType::Keycode(KeyboardUsage::from(keycode as u8))
+        }
+        KC_SYSTEM_POWER => KeyType::System(SystemAction::PowerDown),
+        KC_SYSTEM_SLEEP => KeyType::System(SystemAction::Sleep),
+        KC_SYSTEM_WAKE => KeyType::System(SystemAction::WakeUp),
+        _ if (KC_AUDIO_MUTE..KC_AUDIO_MUTE + CONSUMER_KEYCODES.len() as u16).contains(&keycode) => {
+            KeyType::Consumer(CONSUMER_KEYCODES[(keycode - KC_AUDIO_MUTE) as usize])
+        }
+        KC_MS_UP => KeyType::Mouse(MouseAction::MoveUp),
+        KC_MS_DOWN => KeyType::Mouse(MouseAction::MoveDown),
+        KC_MS_LEFT => KeyType::Mouse(MouseAction::MoveLeft),
+        KC_MS_RIGHT => KeyType::Mouse(MouseAction::MoveRight),
+        KC_MS_BTN1 => KeyType::Mouse(MouseAction::LeftClick),
+        KC_MS_BTN2 => KeyType::Mouse(MouseAction::RightClick),
+        KC_MS_BTN3 => KeyType::Mouse(MouseAction::MiddleClick),
+        KC_MS_WH_UP => KeyType::Mouse(MouseAction::ScrollUp),
+        KC_MS_WH_DOWN => KeyType::Mouse(MouseAction::ScrollDown),
+        KC_MS_WH_LEFT => KeyType::Mouse(MouseAction::ScrollLeft),
+        KC_MS_WH_RIGHT => KeyType::Mouse(MouseAction::ScrollRight),
+        KB_DIAL_PRESS => KeyType::Dial(DialAction::Press),
+        KB_DIAL_LEFT => KeyType::Dial(DialAction::RotateLeft),
+        KB_DIAL_RIGHT => KeyType::Dial(DialAction::RotateRight),
+        KB_PHONE_MUTE => KeyType::Phone(PhoneAction::Mute),
+        KB_PHONE_HOOK => KeyType::Phone(PhoneAction::HookSwitch),
+        KB_PHONE_FLASH => KeyType::Phone(PhoneAction::Flash),
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Modifiers held around the code of a keycode with modifiers, in the order Ctrl, Shift,
+/// Alt, GUI
+pub fn modifiers(keycode: u16) -> Vec<KeyType, 4> {
+    let mut modifiers = Vec::new();
+    if let QK_MODS..=QK_MODS_LAST = keycode {
+        let first = if keycode & QK_MODS_RIGHT != 0 {
+            KeyboardUsage::KeyboardRightControl
+        } else {
+            KeyboardUsage::KeyboardLeftControl
+        } as u8;
+        for bit in 0..4 {
+            if keycode & (QK_MODS << bit) != 0 {
+                let _ = modifiers.push(KeyType::Keycode(KeyboardUsage::from(first + bit)));
+            }
+        }
+    }
+    modifiers
+}
+
+/// Keyboard usage typing an ASCII character with a US layout, and whether it needs Shift
+pub fn ascii_usage(c: u8) -> Option<(u8, bool)> {
+    let usage = match c {
+        b'a'..=b'z' => (0x04 + c - b'a', false),
+        b'A'..=b'Z' => (0x04 + c - b'A', true),
+        b'1'..=b'9' => (0x1E + c - b'1', false),
+        b'0' => (0x27, false),
+        b'!' => (0x1E, true),
+        b'@' => (0x1F, true),
+        b'#' => (0x20, true),
+        b'$' => (0x21, true),
+        b'%' => (0x22, true),
+        b'^' => (0x23, true),
+        b'&' => (0x24, true),
+        b'*' => (0x25, true),
+        b'(' => (0x26, true),
+        b')' => (0x27, true),
+        b'\n' => (0x28, false),
+        0x1B => (0x29, false),
+        0x08 => (0x2A, false),
+        b'\t' => (0x2B, false),
+        b' ' => (0x2C, false),
+        b'-' => (0x2D, false),
+        b'_' => (0x2D, true),
+        b'=' => (0x2E, false),
+        b'+' => (0x2E, true),
+        b'[' => (0x2F, false),
+        b'{' => (0x2F, true),
+        b']' => (0x30, false),
+        b'}' => (0x30, true),
+        b'\\' => (0x31, false),
+        b'|' => (0x31, true),
+        b';' => (0x33, false),
+        b':' => (0x33, true),
+        b'\'' => (0x34, false),
+        b'"' => (0x34, true),
+        b'`' => (0x35, false),
+        b'~' => (0x35, true),
+        b',' => (0x36, false),
+        b'<' => (0x36, true),
+        b'.' => (0x37, false),
+        b'>' => (0x37, true),
+        b'/' => (0x38, false),
+        b'?' => (0x38, true),
+        _ => return None,
+    };
+    Some(usage)
+}
+
+/// Keycode of a code, if there is one
+fn code_keycode(code: KeyType) -> Option<u16> {
+    let keycode = match code {
+        KeyType::Keycode(usage) => match usage as u16 {
+            usage @ (0x0004..=KC_BASIC_LAST | KC_MODIFIER_FIRST..=KC_MODIFIER_LAST) => usage,
+            _ => return None,
+        },
+        KeyType::Media(media_key) => consumer_keycode(media_key as u16)?,
+        KeyType::Consumer(usage) => consumer_keycode(usage)?,
+        KeyType::System(SystemAction::PowerDown) => KC_SYSTEM_POWER,
+        KeyType::System(SystemAction::Sleep) => KC_SYSTEM_SLEEP,
+        KeyType::System(SystemAction::WakeUp) => KC_SYSTEM_WAKE,
+        KeyType::Mouse(MouseAction::MoveUp) => KC_MS_UP,
+        KeyType::Mouse(MouseAction::MoveDown) => KC_MS_DOWN,
+        KeyType::Mouse(MouseAction::MoveLeft) => KC_MS_LEFT,
+        KeyType::Mouse(MouseAction::MoveRight) => KC_MS_RIGHT,
+        KeyType::Mouse(MouseAction::LeftClick) => KC_MS_BTN1,
+        KeyType::Mouse(MouseAction::RightClick) => KC_MS_BTN2,
+        KeyType::Mouse(MouseAction::MiddleClick) => KC_MS_BTN3,
+        KeyType::Mouse(MouseAction::ScrollUp) => KC_MS_WH_UP,
+        KeyType::Mouse(MouseAction::ScrollDown) => KC_MS_WH_DOWN,
+        KeyType::Mouse(MouseAction::ScrollLeft) => KC_MS_WH_LEFT,
+        KeyType::Mouse(MouseAction::ScrollRight) => KC_MS_WH_RIGHT,
+        KeyType::Dial(DialAction::Press) => KB_DIAL_PRESS,
+        KeyType::Dial(DialAction::RotateLeft) => KB_DIAL_LEFT,
+        KeyType::Dial(DialAction::RotateRight) => KB_DIAL_RIGHT,
+        KeyType::Phone(PhoneAction::Mute) => KB_PHONE_MUTE,
+        KeyType::Phone(PhoneAction::HookSwitch) => KB_PHONE_HOOK,
+        KeyType::Phone(PhoneAction::Flash) => KB_PHONE_FLASH,
+        KeyType::Macro(index) => QK_MACRO + index as u16,
+    };
+    Some(keycode)
+}
+
+/// Media keycode sending a consumer usage
+fn consumer_keycode(usage: u16) -> Option<u16> {
+    CONSUMER_KEYCODES
+        .iter()
+        .position(|consumer| *consumer == usage)
+        .map(|position| KC_AUDIO_MUTE + position as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardAa);
+    const LEFT_SHIFT: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardLeftShift);
+    const RIGHT_CONTROL: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardRightControl);
+    const RIGHT_GUI: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardRightGUI);
+
+    #[test]
+    fn keycode_with_modifiers_gives_its_key_and_modifiers() {
+        // LSFT(KC_A)
+        assert!(code(0x0204) == Some(A));
+        assert!(modifiers(0x0204) == [LEFT_SHIFT]);
+        // RCTL(RGUI(KC_A))
+        assert!(code(0x1904) == Some(A));
+        assert!(modifiers(0x1904) == [RIGHT_CONTROL, RIGHT_GUI]);
+    }
+
+    #[test]
+    fn plain_keycode_has_no_modifiers() {
+        assert!(code(0x0004) == Some(A));
+        assert!(modifiers(0x0004).is_empty());
+        assert!(modifiers(QK_MACRO).is_empty());
+    }
+
+    #[test]
+    fn keycode_with_modifiers_is_no_key_action() {
+        assert!(action(0x0204) == Some(KeyAction::NoAction));
+    }
+}
@@ -0,0 +1,53 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+
+/// Raw key events from the buttons and the encoder, consumed by the HID and MIDI backends
+pub static KEY_EVENT_QUEUE: PubSubChannel<CriticalSectionRawMutex, KeyEvent, 8, 2, 2> =
+    PubSubChannel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    EncoderLeft,
+    EncoderRight,
+    EncoderButton,
+    Key1,
+    Key2,
+    Key3,
+}
+
+/// Number of physical inputs, used to size per-key state
+pub const NUM_KEYS: usize = 6;
+
+impl Key {
+    /// All keys, ordered by `index`
+    pub const ALL: [Key; NUM_KEYS] = [
+        Key::EncoderLeft,
+        Key::EncoderRight,
+        Key::EncoderButton,
+        Key::Key1,
+        Key::Key2,
+        Key::Key3,
+    ];
+
+    /// Index of the key for per-key state arrays
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Encoder steps only produce `Pressed` events, without a matching release
+    pub const fn is_rotation(self) -> bool {
+        matches!(self, Key::EncoderLeft | Key::EncoderRight)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Pressed,
+    Released,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub event: Event,
+}
@@ -0,0 +1,538 @@
+use crate::gestures::{GestureRecognizer, Gestures};
+use crate::keys::{Event, Key, NUM_KEYS};
+use crate::layouts::{KeyAction, KeyLayout, KeyType, TapDance};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// Maximum number of layers in a keymap, one bit per layer in the layer masks
+pub const MAX_LAYERS: usize = 8;
+
+/// Key code press or release produced by the resolver
+#[derive(Clone, Copy, PartialEq)]
+pub enum Resolved {
+    Press(KeyType),
+    Release(KeyType),
+    /// Press immediately followed by a release, e.g. for encoder steps and gestures
+    Tap(KeyType),
+}
+
+/// Codes resolved from a single key event or timeout
+pub type Output = Vec<Resolved, 8>;
+
+/// Layer-tap key that is neither a tap nor a hold yet
+#[derive(Clone, Copy)]
+struct PendingTap {
+    key: Key,
+    layer: u8,
+    tap: KeyType,
+    pressed_at: Instant,
+}
+
+/// Held key that keeps tapping its code
+#[derive(Clone, Copy)]
+struct Repeating {
+    code: KeyType,
+    interval: Duration,
+    next: Instant,
+}
+
+/// Resolves raw key events into key codes through a stack of keymap layers
+///
+/// Layer 0 is always active. Higher layers are activated by held layer-tap keys,
+/// toggle keys and one-shot keys, and the highest active layer with a
+/// non-transparent action for a key wins.
+///
+/// A layer-tap key becomes a hold once the tapping term expires or another key
+/// is pressed while it is down, otherwise releasing it sends its tap code.
+/// Tap-dance keys are handed to the gesture recognizer and send the code of the
+/// recognized gesture. Repeat keys tap their code on press and again after their
+/// delay and interval while held, and encoder steps are sent multiple times while
+/// an encoder taps key is held.
+pub struct LayerResolver {
+    keymap: &'static [KeyLayout],
+    tapping_term: Duration,
+    gestures: GestureRecognizer,
+    /// Tap-dance bindings of each key, taken from the layer active when its gesture started
+    dances: [Option<TapDance>; NUM_KEYS],
+    /// Layers switched on by toggle keys, bit n = layer n
+    toggled: u8,
+    /// Layer activated by each held layer-tap key
+    held: [Option<u8>; NUM_KEYS],
+    /// Layer armed by a one-shot key for the next key press
+    oneshot: Option<u8>,
+    pending: Option<PendingTap>,
+    /// Actions set at runtime, replacing the action of the keymap, per layer and key
+    overrides: [[Option<KeyAction>; NUM_KEYS]; MAX_LAYERS],
+    /// Code sent for each pressed key, so the release matches even if layers changed meanwhile
+    pressed: [Option<KeyType>; NUM_KEYS],
+    /// Held repeat keys
+    repeating: [Option<Repeating>; NUM_KEYS],
+    /// Taps per encoder step set by each held encoder taps key, 0 if none
+    encoder_taps: [u8; NUM_KEYS],
+}
+
+const fn layer_bit(layer: u8) -> u8 {
+    if (layer as usize) < MAX_LAYERS {
+        1 << layer
+    } else {
+        0
+    }
+}
+
+impl LayerResolver {
+    pub const fn new(
+        keymap: &'static [KeyLayout],
+        tapping_term: Duration,
+        gestures: GestureRecognizer,
+    ) -> Self {
+        assert!(keymap.len() <= MAX_LAYERS);
+        Self {
+            keymap,
+            tapping_term,
+            gestures,
+            dances: [None; NUM_KEYS],
+            toggled: 0,
+            held: [None; NUM_KEYS],
+            oneshot: None,
+            pending: None,
+            overrides: [[None; NUM_KEYS]; MAX_LAYERS],
+            pressed: [None; NUM_KEYS],
+            repeating: [None; NUM_KEYS],
+            encoder_taps: [0; NUM_KEYS],
+        }
+    }
+
+    /// Replace the keymap action of `key` on `layer`, `None` restores the keymap action
+    pub fn set_action(&mut self, layer: usize, key: Key, action: Option<KeyAction>) {
+        if layer < self.keymap.len() {
+            self.overrides[layer][key.index()] = action;
+        }
+    }
+
+    /// Currently active layers, bit n = layer n
+    pub fn active_layers(&self) -> u8 {
+        let mut layers = layer_bit(0) | self.toggled;
+        for layer in self.held.iter().flatten() {
+            layers |= layer_bit(*layer);
+        }
+        if let Some(layer) = self.oneshot {
+            layers |= layer_bit(layer);
+        }
+        layers
+    }
+
+    /// Highest of the active layers
+    pub fn highest_layer(&self) -> usize {
+        (u8::BITS - 1 - self.active_layers().leading_zeros()) as usize
+    }
+
+    /// Time at which a pending layer-tap key or a gesture is decided by a timeout or a
+    /// repeat key taps again, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        let hold = self
+            .pending
+            .map(|pending| pending.pressed_at + self.tapping_term);
+        let repeat = self
+            .repeating
+            .iter()
+            .flatten()
+            .map(|repeating| repeating.next)
+            .min();
+        [hold, self.gestures.deadline(), repeat]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Advance time, deciding pending layer-tap keys and gestures whose timeouts expired
+    /// and tapping held repeat keys
+    pub fn tick(&mut self, now: Instant) -> Output {
+        if self
+            .pending
+            .is_some_and(|pending| now >= pending.pressed_at + self.tapping_term)
+        {
+            self.resolve_hold();
+        }
+
+        let mut output = Output::new();
+        let gestures = self.gestures.tick(now);
+        self.push_gestures(gestures, &mut output);
+
+        for repeating in self.repeating.iter_mut().flatten() {
+            if now >= repeating.next {
+                let _ = output.push(Resolved::Tap(repeating.code));
+                // Skip missed taps instead of sending them in a burst
+                let next = repeating.next + repeating.interval;
+                repeating.next = if next > now {
+                    next
+                } else {
+                    now + repeating.interval
+                };
+            }
+        }
+        output
+    }
+
+    /// Feed a raw key event and get the resulting key code presses and releases
+    pub fn process(&mut self, key: Key, event: Event, now: Instant) -> Output {
+        let mut output = self.tick(now);
+
+        // Pressing another key while a layer-tap key is down makes it a hold
+        if event == Event::Pressed && self.pending.is_some_and(|pending| pending.key != key) {
+            self.resolve_hold();
+        }
+
+        // Events of a started gesture belong to it, whatever the layers are now
+        if self.gestures.is_active(key) {
+            let gestures = self.gestures.process(key, event, now);
+            self.push_gestures(gestures, &mut output);
+            return output;
+        }
+
+        match event {
+            Event::Pressed => self.press(key, now, &mut output),
+            Event::Released => self.release(key, &mut output),
+        }
+        output
+    }
+
+    fn press(&mut self, key: Key, now: Instant, output: &mut Output) {
+        let action = self.action(key);
+        if !matches!(action, KeyAction::TapDance(_)) || key.is_rotation() {
+            let gestures = self.gestures.interrupt(key);
+            self.push_gestures(gestures, output);
+        }
+
+        match action {
+            KeyAction::Key(code) => {
+                self.oneshot = None;
+                if key.is_rotation() {
+                    self.push_step(code, output);
+                } else {
+                    self.pressed[key.index()] = Some(code);
+                    let _ = output.push(Resolved::Press(code));
+                }
+            }
+            KeyAction::LayerTap { layer, tap } => {
+                self.oneshot = None;
+                if key.is_rotation() {
+                    // Encoder steps have no release, so they can only tap
+                    self.push_step(tap, output);
+                } else {
+                    self.pending = Some(PendingTap {
+                        key,
+                        layer,
+                        tap,
+                        pressed_at: now,
+                    });
+                }
+            }
+            KeyAction::TapDance(dance) => {
+                self.oneshot = None;
+                if key.is_rotation() {
+                    // Encoder steps have no release, so they can only tap
+                    self.push_step(dance.tap, output);
+                } else {
+                    self.dances[key.index()] = Some(dance);
+                    let gestures = self.gestures.process(key, Event::Pressed, now);
+                    self.push_gestures(gestures, output);
+                }
+            }
+            KeyAction::Repeat { code, repeat } => {
+                self.oneshot = None;
+                if key.is_rotation() {
+                    self.push_step(code, output);
+                } else {
+                    self.repeating[key.index()] = Some(Repeating {
+                        code,
+                        interval: repeat.interval,
+                        next: now + repeat.delay,
+                    });
+                    let _ = output.push(Resolved::Tap(code));
+                }
+            }
+            KeyAction::EncoderTaps(taps) => {
+                if !key.is_rotation() {
+                    self.encoder_taps[key.index()] = taps;
+                }
+            }
+            KeyAction::MomentaryLayer(layer) => {
+                if !key.is_rotation() {
+                    self.held[key.index()] = Some(layer);
+                }
+            }
+            KeyAction::ToggleLayer(layer) => self.toggled ^= layer_bit(layer),
+            KeyAction::OneShotLayer(layer) => self.oneshot = Some(layer),
+            KeyAction::Transparent | KeyAction::NoAction => {}
+        }
+    }
+
+    fn release(&mut self, key: Key, output: &mut Output) {
+        if let Some(pending) = self.pending.filter(|pending| pending.key == key) {
+            self.pending = None;
+            let _ = output.push(Resolved::Tap(pending.tap));
+        }
+
+        self.held[key.index()] = None;
+        self.repeating[key.index()] = None;
+        self.encoder_taps[key.index()] = 0;
+
+        if let Some(code) = self.pressed[key.index()].take() {
+            let _ = output.push(Resolved::Release(code));
+        }
+    }
+
+    /// Tap the code of an encoder step, multiple times while an encoder taps key is held
+    fn push_step(&self, code: KeyType, output: &mut Output) {
+        let taps = self.encoder_taps.iter().copied().max().unwrap_or(0).max(1);
+        for _ in 0..taps {
+            if output.push(Resolved::Tap(code)).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn push_gestures(&self, gestures: Gestures, output: &mut Output) {
+        for event in gestures {
+            if let Some(dance) = self.dances[event.key.index()] {
+                let _ = output.push(Resolved::Tap(dance.code(event.gesture)));
+            }
+        }
+    }
+
+    fn resolve_hold(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.held[pending.key.index()] = Some(pending.layer);
+        }
+    }
+
+    /// Action for `key` on the highest active layer that doesn't pass it through
+    fn action(&self, key: Key) -> KeyAction {
+        let layers = self.active_layers();
+        for (layer, layout) in self.keymap.iter().enumerate().rev() {
+            if layers & layer_bit(layer as u8) == 0 {
+                continue;
+            }
+            let action = self.overrides[layer][key.index()].unwrap_or(layout.action(key));
+            match action {
+                KeyAction::Transparent => continue,
+                action => return action,
+            }
+        }
+        KeyAction::NoAction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gestures::{DOUBLE_TAP_TERM, HOLD_TERM};
+    use crate::keys::Event::{Pressed, Released};
+    use crate::keys::Key::*;
+    use crate::layouts::AutoRepeat;
+    use usbd_hid::descriptor::{KeyboardUsage, MediaKey};
+
+    const TAPPING_TERM: Duration = Duration::from_millis(200);
+
+    const A: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardAa);
+    const F: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardFf);
+    const O: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardOo);
+    const PLAY: KeyType = KeyType::Media(MediaKey::PlayPause);
+    const PREV: KeyType = KeyType::Media(MediaKey::PrevTrack);
+    const VOLUME_UP: KeyType = KeyType::Media(MediaKey::VolumeIncrement);
+    const NEXT: KeyType = KeyType::Media(MediaKey::NextTrack);
+
+    static KEYMAP: [KeyLayout; 3] = [
+        // Layer 0: key3 holds layer 1, key2 toggles layer 2, the encoder button arms it once
+        KeyLayout {
+            encoder_left: KeyAction::Key(KeyType::Media(MediaKey::VolumeDecrement)),
+            encoder_right: KeyAction::Key(VOLUME_UP),
+            encoder_button: KeyAction::OneShotLayer(2),
+            key1: KeyAction::Key(O),
+            key2: KeyAction::ToggleLayer(2),
+            key3: KeyAction::LayerTap { layer: 1, tap: F },
+        },
+        KeyLayout {
+            encoder_left: KeyAction::Key(PREV),
+            encoder_right: KeyAction::Transparent,
+            encoder_button: KeyAction::Transparent,
+            key1: KeyAction::Key(PLAY),
+            key2: KeyAction::Transparent,
+            key3: KeyAction::Transparent,
+        },
+        KeyLayout {
+            encoder_left: KeyAction::Transparent,
+            encoder_right: KeyAction::Transparent,
+            encoder_button: KeyAction::Transparent,
+            key1: KeyAction::Key(A),
+            key2: KeyAction::Transparent,
+            key3: KeyAction::Transparent,
+        },
+    ];
+
+    static REPEAT_KEYMAP: [KeyLayout; 1] = [KeyLayout {
+        encoder_left: KeyAction::Key(PREV),
+        encoder_right: KeyAction::Key(NEXT),
+        encoder_button: KeyAction::EncoderTaps(3),
+        key1: KeyAction::Repeat {
+            code: VOLUME_UP,
+            repeat: AutoRepeat {
+                delay: Duration::from_millis(300),
+                interval: Duration::from_millis(50),
+            },
+        },
+        key2: KeyAction::EncoderTaps(2),
+        key3: KeyAction::NoAction,
+    }];
+
+    fn resolver(keymap: &'static [KeyLayout]) -> LayerResolver {
+        LayerResolver::new(
+            keymap,
+            TAPPING_TERM,
+            GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM),
+        )
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn layer_tap_released_within_tapping_term_taps() {
+        let mut resolver = resolver(&KEYMAP);
+        assert!(resolver.process(Key3, Pressed, at(0)).is_empty());
+        assert_eq!(resolver.deadline(), Some(at(200)));
+        assert!(resolver.tick(at(199)).is_empty());
+        assert_eq!(resolver.active_layers(), 0b001);
+        assert_eq!(resolver.highest_layer(), 0);
+        assert!(resolver.process(Key3, Released, at(199)) == [Resolved::Tap(F)]);
+        assert_eq!(resolver.deadline(), None);
+    }
+
+    #[test]
+    fn layer_tap_held_for_tapping_term_holds() {
+        let mut resolver = resolver(&KEYMAP);
+        resolver.process(Key3, Pressed, at(0));
+        assert!(resolver.tick(at(200)).is_empty());
+        assert_eq!(resolver.active_layers(), 0b011);
+        assert!(resolver.process(Key1, Pressed, at(300)) == [Resolved::Press(PLAY)]);
+        assert!(resolver.process(Key1, Released, at(310)) == [Resolved::Release(PLAY)]);
+
+        // No tap code once it is a hold
+        assert!(resolver.process(Key3, Released, at(400)).is_empty());
+        assert_eq!(resolver.active_layers(), 0b001);
+        assert!(resolver.process(Key1, Pressed, at(500)) == [Resolved::Press(O)]);
+    }
+
+    #[test]
+    fn other_key_press_makes_layer_tap_hold() {
+        let mut resolver = resolver(&KEYMAP);
+        resolver.process(Key3, Pressed, at(0));
+        assert!(resolver.process(Key1, Pressed, at(10)) == [Resolved::Press(PLAY)]);
+        assert!(resolver.process(Key3, Released, at(20)).is_empty());
+        assert!(resolver.process(Key1, Released, at(30)) == [Resolved::Release(PLAY)]);
+    }
+
+    #[test]
+    fn toggle_layer() {
+        let mut resolver = resolver(&KEYMAP);
+        resolver.process(Key2, Pressed, at(0));
+        resolver.process(Key2, Released, at(10));
+        assert_eq!(resolver.active_layers(), 0b101);
+        assert!(resolver.process(Key1, Pressed, at(20)) == [Resolved::Press(A)]);
+        resolver.process(Key1, Released, at(30));
+
+        // Key2 is transparent on layer 2, so it switches the layer off again
+        resolver.process(Key2, Pressed, at(40));
+        resolver.process(Key2, Released, at(50));
+        assert_eq!(resolver.active_layers(), 0b001);
+        assert!(resolver.process(Key1, Pressed, at(60)) == [Resolved::Press(O)]);
+    }
+
+    #[test]
+    fn one_shot_layer_applies_to_next_press_only() {
+        let mut resolver = resolver(&KEYMAP);
+        resolver.process(EncoderButton, Pressed, at(0));
+        resolver.process(EncoderButton, Released, at(10));
+        assert_eq!(resolver.active_layers(), 0b101);
+        assert!(resolver.process(Key1, Pressed, at(20)) == [Resolved::Press(A)]);
+        assert_eq!(resolver.active_layers(), 0b001);
+        assert!(resolver.process(Key1, Released, at(30)) == [Resolved::Release(A)]);
+        assert!(resolver.process(Key1, Pressed, at(40)) == [Resolved::Press(O)]);
+    }
+
+    #[test]
+    fn transparent_uses_next_lower_active_layer() {
+        let mut resolver = resolver(&KEYMAP);
+        // Layer 2 over layer 1 over layer 0
+        resolver.process(Key2, Pressed, at(0));
+        resolver.process(Key2, Released, at(10));
+        resolver.process(Key3, Pressed, at(20));
+        resolver.tick(at(220));
+        assert_eq!(resolver.active_layers(), 0b111);
+        assert_eq!(resolver.highest_layer(), 2);
+
+        assert!(resolver.process(Key1, Pressed, at(300)) == [Resolved::Press(A)]);
+        assert!(resolver.process(EncoderLeft, Pressed, at(310)) == [Resolved::Tap(PREV)]);
+        assert!(resolver.process(EncoderRight, Pressed, at(320)) == [Resolved::Tap(VOLUME_UP)]);
+    }
+
+    #[test]
+    fn release_matches_press_after_layer_change() {
+        let mut resolver = resolver(&KEYMAP);
+        resolver.process(Key3, Pressed, at(0));
+        assert!(resolver.process(Key1, Pressed, at(10)) == [Resolved::Press(PLAY)]);
+        // Layer 1 goes away while key1 is still down
+        resolver.process(Key3, Released, at(20));
+        assert!(resolver.process(Key1, Released, at(30)) == [Resolved::Release(PLAY)]);
+
+        // Same for a layer switched on while the key is down
+        assert!(resolver.process(Key1, Pressed, at(40)) == [Resolved::Press(O)]);
+        resolver.process(Key2, Pressed, at(50));
+        resolver.process(Key2, Released, at(60));
+        assert!(resolver.process(Key1, Released, at(70)) == [Resolved::Release(O)]);
+    }
+
+    #[test]
+    fn repeat_key_taps_after_delay_and_every_interval() {
+        let mut resolver = resolver(&REPEAT_KEYMAP);
+        assert!(resolver.process(Key1, Pressed, at(0)) == [Resolved::Tap(VOLUME_UP)]);
+        assert_eq!(resolver.deadline(), Some(at(300)));
+        assert!(resolver.tick(at(299)).is_empty());
+        assert!(resolver.tick(at(300)) == [Resolved::Tap(VOLUME_UP)]);
+        assert_eq!(resolver.deadline(), Some(at(350)));
+        assert!(resolver.tick(at(349)).is_empty());
+        assert!(resolver.tick(at(350)) == [Resolved::Tap(VOLUME_UP)]);
+        assert_eq!(resolver.deadline(), Some(at(400)));
+
+        assert!(resolver.process(Key1, Released, at(399)).is_empty());
+        assert_eq!(resolver.deadline(), None);
+        assert!(resolver.tick(at(1000)).is_empty());
+    }
+
+    #[test]
+    fn repeat_key_skips_missed_taps() {
+        let mut resolver = resolver(&REPEAT_KEYMAP);
+        resolver.process(Key1, Pressed, at(0));
+        // Woken up late, a single tap and the next one an interval later
+        assert!(resolver.tick(at(470)) == [Resolved::Tap(VOLUME_UP)]);
+        assert_eq!(resolver.deadline(), Some(at(520)));
+    }
+
+    #[test]
+    fn encoder_taps_key_multiplies_encoder_steps() {
+        let mut resolver = resolver(&REPEAT_KEYMAP);
+        assert!(resolver.process(EncoderRight, Pressed, at(0)) == [Resolved::Tap(NEXT)]);
+
+        resolver.process(EncoderButton, Pressed, at(10));
+        assert!(resolver.process(EncoderRight, Pressed, at(20)) == [Resolved::Tap(NEXT); 3]);
+        // The most taps of all held keys
+        resolver.process(Key2, Pressed, at(30));
+        assert!(resolver.process(EncoderLeft, Pressed, at(40)) == [Resolved::Tap(PREV); 3]);
+        resolver.process(EncoderButton, Released, at(50));
+        assert!(resolver.process(EncoderLeft, Pressed, at(60)) == [Resolved::Tap(PREV); 2]);
+
+        resolver.process(Key2, Released, at(70));
+        assert!(resolver.process(EncoderLeft, Pressed, at(80)) == [Resolved::Tap(PREV)]);
+    }
+}
@@ -1,10 +1,257 @@
+use crate::combos::Combo;
+use crate::gestures::Gesture;
+use crate::keys::Key;
+use embassy_time::Duration;
+use usbd_hid::descriptor::{KeyboardUsage, MediaKey};
+
+/// Backend that turns key events into USB messages for a selector position
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// HID keyboard and media keys, see `KEYMAP` in `hid.rs`
+    Hid,
+    /// MIDI notes and control changes, see the `MIDI_LAYOUT_*` constants in `midi.rs`
+    Midi,
+    /// Gamepad buttons and an encoder axis, see `GAMEPAD_LAYOUT` in `gamepad.rs`
+    Gamepad,
+    /// Slide keys, a pointer or zoom on the encoder and a talk timer on the LEDs, see
+    /// `PRESENTATION_LAYOUT` in `presentation.rs`
+    Presentation,
+}
+
+/// Backend per selector position [Keyboard, Picoprog, Universal], until changed with VIA
+pub const MODE_BACKENDS: [Backend; 3] = [Backend::Hid, Backend::Midi, Backend::Midi];
+
+/// HID code sent for a key
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Media(MediaKey),
+    /// Any usage of the consumer page, e.g. one of the constants in `consumer`
+    Consumer(u16),
+    Keycode(KeyboardUsage),
+    Mouse(MouseAction),
+    Dial(DialAction),
+    Phone(PhoneAction),
+    System(SystemAction),
+    /// Macro `n` of the macro buffer edited with VIA
+    Macro(u8),
+}
+
+/// Consumer page usages missing from `MediaKey`, for `KeyType::Consumer`
+pub mod consumer {
+    pub const DISPLAY_BRIGHTNESS_INCREMENT: u16 = 0x006F;
+    pub const DISPLAY_BRIGHTNESS_DECREMENT: u16 = 0x0070;
+    pub const AL_EMAIL_READER: u16 = 0x018A;
+    pub const AL_CALCULATOR: u16 = 0x0192;
+    pub const AL_INTERNET_BROWSER: u16 = 0x0196;
+    /// AL Command Line Processor/Run, opens a terminal
+    pub const AL_TERMINAL: u16 = 0x01A0;
+    pub const AL_FILE_BROWSER: u16 = 0x01B4;
+    pub const AC_SEARCH: u16 = 0x0221;
+    pub const AC_HOME: u16 = 0x0223;
+    pub const AC_BACK: u16 = 0x0224;
+    pub const AC_FORWARD: u16 = 0x0225;
+    pub const AC_REFRESH: u16 = 0x0227;
+}
+
+/// Mouse button held while the key is pressed, or one wheel step or pointer move per press
+#[derive(Clone, Copy, PartialEq)]
+pub enum MouseAction {
+    LeftClick,
+    RightClick,
+    MiddleClick,
+    ScrollUp,
+    ScrollDown,
+    ScrollLeft,
+    ScrollRight,
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+}
+
+/// Radial controller button held while the key is pressed, or one rotation step per press
+#[derive(Clone, Copy, PartialEq)]
+pub enum DialAction {
+    Press,
+    RotateLeft,
+    RotateRight,
+}
+
+/// Headset control of the telephony collection
+#[derive(Clone, Copy, PartialEq)]
+pub enum PhoneAction {
+    /// Toggle the microphone mute of the call
+    Mute,
+    /// Answer or hang up, depending on the off-hook state reported by the host
+    HookSwitch,
+    /// Put the call on hold or switch between calls
+    Flash,
+}
+
+/// System control of the generic desktop page, sent once per press
+#[derive(Clone, Copy, PartialEq)]
+pub enum SystemAction {
+    PowerDown,
+    Sleep,
+    WakeUp,
+}
+
+impl MouseAction {
+    /// Bit of the button in the mouse report, 0 for wheel steps
+    pub const fn button(self) -> u8 {
+        match self {
+            MouseAction::LeftClick => 0x01,
+            MouseAction::RightClick => 0x02,
+            MouseAction::MiddleClick => 0x04,
+            _ => 0,
+        }
+    }
+}
+
+/// Action bound to a key on one layer of the HID keymap
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeyAction {
+    /// Send the code while the key is held
+    Key(KeyType),
+    /// Activate `layer` while held, send `tap` when released within the tapping term
+    LayerTap { layer: u8, tap: KeyType },
+    /// Activate `layer` while held
+    MomentaryLayer(u8),
+    /// Switch `layer` on or off on every press
+    ToggleLayer(u8),
+    /// Activate `layer` for the next key press only
+    OneShotLayer(u8),
+    /// Send different codes for a single tap, a double tap and a long press
+    TapDance(TapDance),
+    /// Tap the code on press and keep tapping it while held, independent of the host's repeat
+    Repeat { code: KeyType, repeat: AutoRepeat },
+    /// While held, every encoder step sends its code this many times, e.g. to scrub long lists
+    EncoderTaps(u8),
+    /// Use the action of the next lower active layer
+    Transparent,
+    /// Ignore the key
+    NoAction,
+}
+
+/// Timing of a repeating key
+#[derive(Clone, Copy, PartialEq)]
+pub struct AutoRepeat {
+    /// Time from the press to the first repeated tap
+    pub delay: Duration,
+    /// Time between repeated taps
+    pub interval: Duration,
+}
+
+/// Codes sent for the gestures of a tap-dance key
+#[derive(Clone, Copy, PartialEq)]
+pub struct TapDance {
+    pub tap: KeyType,
+    pub double_tap: KeyType,
+    pub hold: KeyType,
+}
+
+impl TapDance {
+    /// Code for a recognized gesture
+    pub const fn code(&self, gesture: Gesture) -> KeyType {
+        match gesture {
+            Gesture::Tap => self.tap,
+            Gesture::DoubleTap => self.double_tap,
+            Gesture::Hold => self.hold,
+        }
+    }
+}
+
+/// One layer of the HID keymap
+pub struct KeyLayout {
+    pub encoder_left: KeyAction,
+    pub encoder_right: KeyAction,
+    pub encoder_button: KeyAction,
+    pub key1: KeyAction,
+    pub key2: KeyAction,
+    pub key3: KeyAction,
+}
+
+impl KeyLayout {
+    /// Action bound to `key` on this layer
+    pub const fn action(&self, key: Key) -> KeyAction {
+        match key {
+            Key::EncoderLeft => self.encoder_left,
+            Key::EncoderRight => self.encoder_right,
+            Key::EncoderButton => self.encoder_button,
+            Key::Key1 => self.key1,
+            Key::Key2 => self.key2,
+            Key::Key3 => self.key3,
+        }
+    }
+}
+
+/// Gamepad backend configuration, buttons are numbered 1-8 and 0 means no button
+pub struct GamepadLayout {
+    /// Amount the encoder moves the absolute axis (0-127) per step, stopping at both ends
+    pub axis_step: u8,
+    pub encoder_button: u8,
+    pub key1: u8,
+    pub key2: u8,
+    pub key3: u8,
+}
+
+impl GamepadLayout {
+    /// Button bit in the gamepad report for a key, 0 for none
+    pub const fn button(&self, key: Key) -> u8 {
+        let button = match key {
+            Key::EncoderButton => self.encoder_button,
+            Key::Key1 => self.key1,
+            Key::Key2 => self.key2,
+            Key::Key3 => self.key3,
+            Key::EncoderLeft | Key::EncoderRight => 0,
+        };
+        match button {
+            1..=8 => 1 << (button - 1),
+            _ => 0,
+        }
+    }
+}
+
+/// Control driven by the encoder in the presentation backend
+#[derive(Clone, Copy, PartialEq)]
+pub enum PresentationEncoder {
+    /// Move the mouse pointer like a laser pointer, the encoder button switches between
+    /// horizontal and vertical movement
+    Pointer,
+    /// Zoom in and out with Ctrl and the wheel, the encoder button resets the zoom
+    Zoom,
+}
+
+/// Presentation backend configuration
+pub struct PresentationLayout {
+    pub key1: KeyType,
+    pub key2: KeyType,
+    pub key3: KeyType,
+    /// Encoder control until changed with VIA
+    pub encoder: PresentationEncoder,
+    /// Length of the talk, the LEDs turn from green to yellow to red over this time and
+    /// blink once it is over
+    pub talk_time: Duration,
+}
+
+impl PresentationLayout {
+    /// Code sent while `key` is held, `None` for the encoder
+    pub const fn code(&self, key: Key) -> Option<KeyType> {
+        match key {
+            Key::Key1 => Some(self.key1),
+            Key::Key2 => Some(self.key2),
+            Key::Key3 => Some(self.key3),
+            Key::EncoderLeft | Key::EncoderRight | Key::EncoderButton => None,
+        }
+    }
+}
+
 /// MIDI message type for each input
 #[derive(Clone, Copy)]
 pub enum MidiMessageType {
     /// Control Change (CC) message
     ControlChange { cc_number: u8 },
     /// Note On/Off message
-    #[allow(dead_code)]
     Note { note_number: u8, velocity: u8 },
 }
 
@@ -15,6 +262,38 @@ pub struct MidiInputConfig {
     pub channel: u8, // MIDI channel (0-15)
 }
 
+/// Messages for the gestures of a key, sent as a momentary press and release
+#[derive(Clone, Copy)]
+pub struct MidiGestureConfig {
+    pub tap: MidiInputConfig,
+    pub double_tap: MidiInputConfig,
+    pub hold: MidiInputConfig,
+}
+
+impl MidiGestureConfig {
+    /// Message for a recognized gesture
+    pub const fn config(&self, gesture: Gesture) -> MidiInputConfig {
+        match gesture {
+            Gesture::Tap => self.tap,
+            Gesture::DoubleTap => self.double_tap,
+            Gesture::Hold => self.hold,
+        }
+    }
+}
+
+/// Action of keys pressed together on a MIDI layout
+#[derive(Clone, Copy)]
+pub enum MidiComboAction {
+    /// Message sent on press and release like the one of a key
+    Message(MidiInputConfig),
+    /// Detect the baud rate of the UART header, see `uart::AUTO_BAUD_REQUESTED`
+    AutoBaud,
+    /// Switch the UART header between bridging and sniffing, see `sniffer::sniff`
+    Sniffer,
+    /// Send the capture of the UART header to the host, see `capture::dump`
+    CaptureDump,
+}
+
 /// Complete layout configuration for all inputs
 pub struct MidiLayout {
     pub encoder_left: MidiInputConfig,
@@ -23,6 +302,20 @@ pub struct MidiLayout {
     pub key1: MidiInputConfig,
     pub key2: MidiInputConfig,
     pub key3: MidiInputConfig,
+    /// Keys sending gesture messages instead of their press/release message
+    pub gestures: &'static [(Key, MidiGestureConfig)],
+    /// Keys pressed together sending a message instead of their own messages
+    pub combos: &'static [Combo<MidiComboAction>],
+}
+
+impl MidiLayout {
+    /// Gesture messages bound to `key`, if any
+    pub fn gesture_config(&self, key: Key) -> Option<MidiGestureConfig> {
+        self.gestures
+            .iter()
+            .find(|(gesture_key, _)| *gesture_key == key)
+            .map(|(_, config)| *config)
+    }
 }
 
 impl MidiInputConfig {
@@ -35,7 +328,6 @@ impl MidiInputConfig {
     }
 
     /// Create a Note configuration
-    #[allow(dead_code)]
     pub const fn note(channel: u8, note_number: u8, velocity: u8) -> Self {
         Self {
             message_type: MidiMessageType::Note {
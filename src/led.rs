@@ -1,21 +1,86 @@
+use crate::layouts::Backend;
+use crate::presentation::PRESENTATION_LAYOUT;
+use crate::presenter::talk_timer_color;
+use crate::uart::AutoBaudStatus;
 use crate::{DeviceMode, LedResources};
+use core::future::pending;
+use embassy_futures::select::select3;
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::PIO1;
 use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_rp::pio_programs::ws2812::{PioWs2812, PioWs2812Program};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use smart_leds::RGB8;
 
 bind_interrupts!(struct Irqs {
     PIO1_IRQ_0 => InterruptHandler<PIO1>;
 });
 
+// Signal to notify when an indicator set by the host changes, the host suspends the bus,
+// the talk timer starts or stops or the auto-baud detection progresses
+pub static INDICATORS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Color of all LEDs while the host reports the call as muted
+const MUTE_COLOR: RGB8 = RGB8 { r: 20, g: 0, b: 0 };
+
+/// Color of all LEDs while the auto-baud detection measures the pulses on RX
+const AUTO_BAUD_MEASURING_COLOR: RGB8 = RGB8 { r: 0, g: 0, b: 20 };
+
+/// Color of all LEDs if the auto-baud detection found no baud rate
+const AUTO_BAUD_FAILED_COLOR: RGB8 = RGB8 {
+    r: 10,
+    g: 10,
+    b: 10,
+};
+
+/// Colors of the detected baud rates up to the given one
+const AUTO_BAUD_COLORS: [(u32, RGB8); 4] = [
+    (19200, RGB8 { r: 20, g: 0, b: 0 }),     // Red
+    (115200, RGB8 { r: 0, g: 20, b: 0 }),    // Green
+    (1000000, RGB8 { r: 0, g: 10, b: 10 }),  // Cyan
+    (u32::MAX, RGB8 { r: 10, g: 0, b: 20 }), // Violet
+];
+
+/// Time the result of the auto-baud detection is shown
+const AUTO_BAUD_SHOWN: Duration = Duration::from_secs(3);
+
+const NUM_LEDS: usize = 4;
+
+/// Lock state reported by the host keyboard LED output report
+#[derive(Clone, Copy)]
+pub enum LockLed {
+    NumLock,
+    CapsLock,
+    ScrollLock,
+}
+
+impl LockLed {
+    /// Bit of the lock in the keyboard LED output report
+    const fn bit(self) -> u8 {
+        match self {
+            LockLed::NumLock => 0x01,
+            LockLed::CapsLock => 0x02,
+            LockLed::ScrollLock => 0x04,
+        }
+    }
+}
+
+/// Lock shown by each LED and its color while the lock is on, `None` keeps the mode color
+static LOCK_INDICATORS: [Option<(LockLed, RGB8)>; NUM_LEDS] = [
+    None,
+    Some((LockLed::NumLock, RGB8 { r: 0, g: 0, b: 20 })), // Blue
+    Some((LockLed::ScrollLock, RGB8 { r: 5, g: 0, b: 10 })), // Violet
+    Some((LockLed::CapsLock, RGB8 { r: 10, g: 5, b: 0 })), // Amber
+];
+
 #[embassy_executor::task]
 pub async fn led_task(r: LedResources, _initial_mode: DeviceMode) -> ! {
     let Pio {
         mut common, sm0, ..
     } = Pio::new(r.peripheral, Irqs);
 
-    const NUM_LEDS: usize = 4;
     let mut data = [RGB8::default(); NUM_LEDS];
 
     let program = PioWs2812Program::new(&mut common);
@@ -29,22 +94,82 @@ pub async fn led_task(r: LedResources, _initial_mode: DeviceMode) -> ! {
         };
 
         // Set colors based on mode: Position 1=Teal, Position 2=Orange, Position 3=Pink
-        let color = match current_mode {
+        let mut color = match current_mode {
             DeviceMode::Keyboard => RGB8 { r: 0, g: 10, b: 8 }, // Teal
             DeviceMode::Picoprog => RGB8 { r: 10, g: 3, b: 0 }, // Orange
             DeviceMode::Universal => RGB8 { r: 10, g: 0, b: 5 }, // Pink
         };
 
-        // Set all 4 LEDs to the same mode color
-        data[0] = color;
-        data[1] = color;
-        data[2] = color;
-        data[3] = color;
+        // The running talk timer of the presentation backend replaces the mode color
+        let mut next_update = None;
+        let talk_elapsed = match current_mode.backend() {
+            Backend::Presentation => crate::presentation::talk_elapsed(),
+            _ => None,
+        };
+        if let Some(elapsed) = talk_elapsed {
+            let (timer_color, update) = talk_timer_color(elapsed, PRESENTATION_LAYOUT.talk_time);
+            color = timer_color;
+            next_update = Some(Instant::now() + update);
+        }
+
+        // The mute state of a call overrides the mode color
+        if crate::controls::host_muted() {
+            color = MUTE_COLOR;
+        }
+
+        // The auto-baud detection replaces the other colors while it runs and shortly after
+        if let Some((status, elapsed)) = crate::uart::auto_baud_status() {
+            if status == AutoBaudStatus::Measuring || elapsed < AUTO_BAUD_SHOWN {
+                color = auto_baud_color(status);
+            }
+            if status != AutoBaudStatus::Measuring && elapsed < AUTO_BAUD_SHOWN {
+                let hide = Instant::now() + (AUTO_BAUD_SHOWN - elapsed);
+                next_update = Some(next_update.map_or(hide, |next| next.min(hide)));
+            }
+        }
+
+        // Set all 4 LEDs to the same color, except those showing an active lock
+        let host_leds = crate::keyboard::host_leds();
+        for (led, indicator) in data.iter_mut().zip(LOCK_INDICATORS.iter()) {
+            *led = match indicator {
+                Some((lock, lock_color)) if host_leds & lock.bit() != 0 => *lock_color,
+                _ => color,
+            };
+        }
+
+        // All LEDs are off while the host is suspended, to stay within the suspend current
+        if crate::usb_state::is_suspended() {
+            data.fill(RGB8::default());
+        }
 
         // Write the updated colors
         ws2812.write(&data).await;
 
-        // Wait for mode change signal
-        crate::MODE_CHANGED.wait().await;
+        // Wait for a mode change, a host indicator change or the next talk timer or auto-baud
+        // step
+        let timer_step = async {
+            match next_update {
+                Some(next_update) => Timer::at(next_update).await,
+                None => pending().await,
+            }
+        };
+        select3(
+            crate::MODE_CHANGED.wait(),
+            INDICATORS_CHANGED.wait(),
+            timer_step,
+        )
+        .await;
+    }
+}
+
+/// Color of an auto-baud detection status
+fn auto_baud_color(status: AutoBaudStatus) -> RGB8 {
+    match status {
+        AutoBaudStatus::Measuring => AUTO_BAUD_MEASURING_COLOR,
+        AutoBaudStatus::Failed => AUTO_BAUD_FAILED_COLOR,
+        AutoBaudStatus::Detected(baud) => AUTO_BAUD_COLORS
+            .iter()
+            .find(|(max_baud, _)| baud <= *max_baud)
+            .map_or(AUTO_BAUD_FAILED_COLOR, |(_, color)| *color),
     }
 }
@@ -0,0 +1,19 @@
+//! Hardware independent parts of the firmware: the key types, the keymap layouts, the
+//! resolvers turning key events into codes, the presentation remote, the keymap edited with
+//! VIA and the parser of the automation commands
+//!
+//! They are built for the host as well to run their tests with
+//! `cargo +stable test --lib --target x86_64-unknown-linux-gnu`.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod combos;
+pub mod commands;
+pub mod gestures;
+pub mod keycodes;
+pub mod keys;
+pub mod layers;
+pub mod layouts;
+pub mod macros;
+pub mod presenter;
+pub mod via_config;
@@ -0,0 +1,158 @@
+use embassy_time::Duration;
+use heapless::Vec;
+
+/// Size of the macro buffer holding all macros as null-terminated strings
+pub const MACRO_BUFFER_SIZE: usize = 512;
+
+// Macro actions in the macro buffer, all other bytes are typed as ASCII characters
+const SS_QMK_PREFIX: u8 = 0x01;
+const SS_TAP_CODE: u8 = 0x01;
+const SS_DOWN_CODE: u8 = 0x02;
+const SS_UP_CODE: u8 = 0x03;
+const SS_DELAY_CODE: u8 = 0x04;
+const SS_TAP_CODE16: u8 = 0x05;
+const SS_DOWN_CODE16: u8 = 0x06;
+const SS_UP_CODE16: u8 = 0x07;
+
+/// Step of a macro
+#[derive(Clone, Copy, PartialEq)]
+pub enum MacroStep {
+    /// Type an ASCII character
+    Char(u8),
+    /// Press and release the code of a keycode
+    Tap(u16),
+    Press(u16),
+    Release(u16),
+    Delay(Duration),
+}
+
+/// Steps of a macro from the macro buffer
+///
+/// A macro is a string typed character by character. `SS_QMK_PREFIX` starts an
+/// action instead: a tap, press or release followed by a one byte keycode, or
+/// by a two byte keycode in the `*_CODE16` variants, or a delay in milliseconds
+/// given as decimal digits terminated by `|`.
+pub struct MacroSteps {
+    bytes: Vec<u8, MACRO_BUFFER_SIZE>,
+    position: usize,
+}
+
+impl MacroSteps {
+    /// Steps of macro `index` in a macro buffer, none if it doesn't have that many macros
+    pub fn new(buffer: &[u8], index: u8) -> Self {
+        let mut bytes = Vec::new();
+        if let Some(macro_bytes) = buffer.split(|byte| *byte == 0).nth(index as usize) {
+            let _ = bytes.extend_from_slice(macro_bytes);
+        }
+        Self { bytes, position: 0 }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        let byte = self.bytes.get(self.position).copied();
+        self.position += 1;
+        byte
+    }
+
+    /// Little-endian keycode, VIA writes a zero high byte as 0xFF to keep the string terminated
+    fn next_keycode16(&mut self) -> Option<u16> {
+        let low = self.next_byte()?;
+        let high = match self.next_byte()? {
+            0xFF => 0x00,
+            high => high,
+        };
+        Some(u16::from_le_bytes([low, high]))
+    }
+}
+
+impl Iterator for MacroSteps {
+    type Item = MacroStep;
+
+    fn next(&mut self) -> Option<MacroStep> {
+        let byte = self.next_byte()?;
+        if byte != SS_QMK_PREFIX {
+            return Some(MacroStep::Char(byte));
+        }
+
+        let step = match self.next_byte()? {
+            SS_TAP_CODE => MacroStep::Tap(self.next_byte()? as u16),
+            SS_DOWN_CODE => MacroStep::Press(self.next_byte()? as u16),
+            SS_UP_CODE => MacroStep::Release(self.next_byte()? as u16),
+            SS_TAP_CODE16 => MacroStep::Tap(self.next_keycode16()?),
+            SS_DOWN_CODE16 => MacroStep::Press(self.next_keycode16()?),
+            SS_UP_CODE16 => MacroStep::Release(self.next_keycode16()?),
+            SS_DELAY_CODE => {
+                let mut ms: u64 = 0;
+                loop {
+                    match self.next_byte()? {
+                        b'|' => break,
+                        digit @ b'0'..=b'9' => ms = ms * 10 + (digit - b'0') as u64,
+                        _ => {}
+                    }
+                }
+                MacroStep::Delay(Duration::from_millis(ms))
+            }
+            // Unknown action, the rest of the macro can't be parsed
+            _ => return None,
+        };
+        Some(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(buffer: &[u8], index: u8) -> std::vec::Vec<MacroStep> {
+        MacroSteps::new(buffer, index).collect()
+    }
+
+    #[test]
+    fn parses_characters_and_actions() {
+        let buffer = [
+            &b"hi"[..],
+            // Tap Enter
+            &[SS_QMK_PREFIX, SS_TAP_CODE, 0x28],
+            &[SS_QMK_PREFIX, SS_DELAY_CODE, b'2', b'5', b'0', b'|'],
+            // Press Left Shift, the zero high byte is written as 0xFF
+            &[SS_QMK_PREFIX, SS_DOWN_CODE16, 0xE1, 0xFF],
+            // Tap Shift + A
+            &[SS_QMK_PREFIX, SS_TAP_CODE16, 0x04, 0x02],
+            &[SS_QMK_PREFIX, SS_UP_CODE, 0xE1],
+            &[0],
+        ]
+        .concat();
+        assert!(
+            steps(&buffer, 0)
+                == [
+                    MacroStep::Char(b'h'),
+                    MacroStep::Char(b'i'),
+                    MacroStep::Tap(0x28),
+                    MacroStep::Delay(Duration::from_millis(250)),
+                    MacroStep::Press(0x00E1),
+                    MacroStep::Tap(0x0204),
+                    MacroStep::Release(0xE1),
+                ]
+        );
+    }
+
+    #[test]
+    fn selects_macro_by_index() {
+        let buffer = *b"first\0\0third\0";
+        assert!(steps(&buffer, 0).len() == 5);
+        assert!(steps(&buffer, 1).is_empty());
+        assert!(steps(&buffer, 2) == b"third".map(MacroStep::Char));
+        assert!(steps(&buffer, 4).is_empty());
+    }
+
+    #[test]
+    fn stops_at_truncated_or_unknown_action() {
+        let truncated = [b'a', SS_QMK_PREFIX, SS_TAP_CODE16, 0x04];
+        assert!(steps(&truncated, 0) == [MacroStep::Char(b'a')]);
+
+        let unfinished_delay = [SS_QMK_PREFIX, SS_DELAY_CODE, b'1', b'0'];
+        assert!(steps(&unfinished_delay, 0).is_empty());
+
+        let unknown = [SS_QMK_PREFIX, 0x7F, b'a'];
+        assert!(steps(&unknown, 0).is_empty());
+    }
+}
@@ -10,6 +10,7 @@ use core::panic::PanicInfo;
 use cortex_m::peripheral::SCB;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_rp::bind_interrupts;
 use embassy_rp::flash::{Async, Flash};
 use embassy_rp::gpio::{Input, Level, Pull};
@@ -19,11 +20,16 @@ use embassy_rp::usb::{Driver, InterruptHandler as USBInterruptHandler};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
+use embassy_usb::class::hid::{Config as HidConfig, HidReaderWriter, State as HidState};
 use embassy_usb::class::midi::MidiClass;
 use embassy_usb::{Config as UsbConfig, UsbDevice};
 use heapless::String;
+use oskar::{
+    combos, commands, gestures, keycodes, keys, layers, layouts, macros, presenter, via_config,
+};
 use static_cell::StaticCell;
 use ufmt::uwrite;
+use usbd_hid::descriptor::{MediaKeyboardReport, SerializedDescriptor};
 
 // Global mutex to share current mode between tasks
 pub static CURRENT_MODE: Mutex<CriticalSectionRawMutex, DeviceMode> =
@@ -32,9 +38,25 @@ pub static CURRENT_MODE: Mutex<CriticalSectionRawMutex, DeviceMode> =
 // Signal to notify when mode changes
 pub static MODE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-mod layouts;
+// Signal to wake up the suspended host
+pub static REMOTE_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+mod automation;
+mod capture;
+mod cdc_acm;
+mod controls;
+mod gamepad;
+mod hid;
+mod input;
+mod keyboard;
 mod led;
 mod midi;
+mod pio_uart;
+mod presentation;
+mod sniffer;
+mod uart;
+mod usb_state;
+mod via;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => USBInterruptHandler<USB>;
@@ -60,6 +82,18 @@ assign_resources! {
         led_dma: DMA_CH0,
     }
 
+    uart: UartResources{
+        peripheral: PIO0,
+        tx: PIN_0,
+        rx: PIN_1,
+        rx_dma: DMA_CH1,
+        sniff_dma: DMA_CH2,
+        dtr: PIN_6,
+        rts: PIN_7,
+        flow_rts: PIN_8,
+        flow_cts: PIN_9,
+    }
+
     selector_switch: ModeSwitchRessources{
         selector_kb: PIN_16,
         selector_picocprog: PIN_17,
@@ -73,6 +107,22 @@ pub enum DeviceMode {
     Universal,
 }
 
+impl DeviceMode {
+    /// Index of the selector position, for per-position settings
+    pub fn index(self) -> usize {
+        match self {
+            DeviceMode::Keyboard => 0,
+            DeviceMode::Picoprog => 1,
+            DeviceMode::Universal => 2,
+        }
+    }
+
+    /// Backend handling key events while the selector is in this position
+    pub fn backend(self) -> layouts::Backend {
+        via::backend(self.index())
+    }
+}
+
 // According to Serial Flasher Protocol Specification - version 1
 const FLASH_SIZE: usize = 2 * 1024 * 1024;
 
@@ -113,6 +163,10 @@ async fn main(spawner: Spawner) {
         uwrite!(uid_str, "{:02X}", *byte).unwrap_or_default();
     }
 
+    // Shared by VIA and the UART capture, which have their own sectors
+    static FLASH: StaticCell<via::SharedFlash> = StaticCell::new();
+    let flash = FLASH.init(Mutex::new(flash));
+
     let config = {
         let mut config = UsbConfig::new(0x1ced, 0xc0fe);
         config.manufacturer = Some("9elements");
@@ -120,6 +174,7 @@ async fn main(spawner: Spawner) {
         config.serial_number = Some(uid_str.as_str());
         config.max_power = 100;
         config.max_packet_size_0 = 64;
+        config.supports_remote_wakeup = true;
 
         // Required for windows compatibility.
         // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
@@ -131,7 +186,7 @@ async fn main(spawner: Spawner) {
     };
 
     let mut builder: embassy_usb::Builder<'_, Driver<'_, USB>> = {
-        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 512]> = StaticCell::new();
         static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
         static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
         static MSOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
@@ -139,7 +194,7 @@ async fn main(spawner: Spawner) {
         let builder = embassy_usb::Builder::new(
             driver,
             config,
-            CONFIG_DESCRIPTOR.init([0; 256]),
+            CONFIG_DESCRIPTOR.init([0; 512]),
             BOS_DESCRIPTOR.init([0; 256]),
             MSOS_DESCRIPTOR.init([0; 256]), // no msos descriptors
             CONTROL_BUF.init([0; 64]),
@@ -156,14 +211,92 @@ async fn main(spawner: Spawner) {
         .spawn(midi::midi_task(
             spawner,
             midi_class,
-            r.hid,
-            r.encoder,
             mode,
             selector_keyboard,
             selector_picoprog,
         ))
         .unwrap();
 
+    // CDC-ACM port bridged to the UART header in picoprog and universal mode
+    let (cdc_rx, cdc_tx, cdc_notifier) = cdc_acm::new_cdc_acm(&mut builder);
+
+    spawner
+        .spawn(uart::uart_task(cdc_rx, cdc_tx, cdc_notifier, r.uart, flash))
+        .unwrap();
+
+    // Records the header while no terminal has the CDC-ACM port open
+    spawner.spawn(capture::capture_task(flash)).unwrap();
+
+    // HID keyboard and media key interfaces
+    let (keyboard_reader, keyboard_writer) = keyboard::new_keyboard(&mut builder);
+
+    spawner
+        .spawn(keyboard::keyboard_output_task(keyboard_reader))
+        .unwrap();
+
+    static MULTIMEDIA_STATE: StaticCell<HidState> = StaticCell::new();
+    let multimedia_config = HidConfig {
+        report_descriptor: MediaKeyboardReport::desc(),
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: 8,
+    };
+    let multimedia_class = HidReaderWriter::new(
+        &mut builder,
+        MULTIMEDIA_STATE.init(HidState::new()),
+        multimedia_config,
+    );
+
+    // HID interface for the mouse and other controls, with feature and output reports
+    static CONTROLS_STATE: StaticCell<HidState> = StaticCell::new();
+    static CONTROLS_HANDLER: StaticCell<controls::ControlsRequestHandler> = StaticCell::new();
+    let controls_config = HidConfig {
+        report_descriptor: controls::CONTROLS_REPORT_DESCRIPTOR,
+        request_handler: Some(CONTROLS_HANDLER.init(controls::ControlsRequestHandler)),
+        poll_ms: 10,
+        max_packet_size: 16,
+    };
+    let controls_class: controls::ControlsHid = HidReaderWriter::new(
+        &mut builder,
+        CONTROLS_STATE.init(HidState::new()),
+        controls_config,
+    );
+    let (controls_reader, controls_writer) = controls_class.split();
+
+    spawner
+        .spawn(controls::controls_output_task(controls_reader))
+        .unwrap();
+
+    spawner
+        .spawn(hid::hid_task(
+            keyboard_writer,
+            multimedia_class,
+            controls_writer,
+        ))
+        .unwrap();
+
+    // Raw HID interface for remapping keys and editing macros with VIA
+    static VIA_STATE: StaticCell<HidState> = StaticCell::new();
+    let via_config = HidConfig {
+        report_descriptor: via::VIA_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: via::REPORT_SIZE as u16,
+    };
+    let via_class: via::ViaHid =
+        HidReaderWriter::new(&mut builder, VIA_STATE.init(HidState::new()), via_config);
+
+    spawner.spawn(via::via_task(via_class, flash)).unwrap();
+
+    input::spawn_input_tasks(spawner, r.hid, r.encoder);
+
+    // Keystrokes sent as commands to the UART header in keyboard mode
+    spawner.spawn(automation::automation_task()).unwrap();
+
+    // Suspend state for the other tasks
+    static USB_STATE_HANDLER: StaticCell<usb_state::UsbStateHandler> = StaticCell::new();
+    builder.handler(USB_STATE_HANDLER.init(usb_state::UsbStateHandler));
+
     let usb = builder.build();
     // We can't really recover here so just unwrap
     spawner.spawn(usb_task(usb)).unwrap();
@@ -178,7 +311,20 @@ type CustomUsbDevice = UsbDevice<'static, CustomUsbDriver>;
 
 #[embassy_executor::task]
 async fn usb_task(mut usb: CustomUsbDevice) -> ! {
-    usb.run().await
+    loop {
+        usb.run_until_suspend().await;
+        // Only key presses during this suspend should wake up the host
+        REMOTE_WAKEUP.reset();
+
+        match select(usb.wait_resume(), REMOTE_WAKEUP.wait()).await {
+            Either::First(()) => {}
+            Either::Second(()) => {
+                if let Err(e) = usb.remote_wakeup().await {
+                    log::warn!("Remote wakeup failed: {:?}", e);
+                }
+            }
+        }
+    }
 }
 
 #[panic_handler]
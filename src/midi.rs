@@ -1,47 +1,23 @@
-use crate::layouts::{MidiInputConfig, MidiLayout, MidiMessageType};
-use crate::{ButtonResources, EncoderResources};
-use defmt::unreachable;
+use crate::combos::{COMBO_TERM, Combo, ComboDetector, ComboEvent};
+use crate::gestures::{DOUBLE_TAP_TERM, GestureEvent, GestureRecognizer, HOLD_TERM};
+use crate::keys::{Event, KEY_EVENT_QUEUE, Key, KeyEvent};
+use crate::layouts::{
+    Backend, MidiComboAction, MidiGestureConfig, MidiInputConfig, MidiLayout, MidiMessageType,
+};
+use crate::usb_state;
 use defmt_rtt as _;
-use embassy_executor::{InterruptExecutor, Spawner};
-use embassy_futures::select::select_array;
-use embassy_rp::gpio::{Input, Level, Pull};
-use embassy_rp::interrupt;
-use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
+use embassy_rp::gpio::Input;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_sync::pubsub::PubSubChannel;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::class::midi::{MidiClass, Sender};
 
-static KEY_EVENT_QUEUE: PubSubChannel<CriticalSectionRawMutex, KeyEvent, 8, 2, 2> =
-    PubSubChannel::new();
-
 // Encoder value counters (0-127) for absolute mode - one per mode
 // [Mode1/Keyboard, Mode2/Picoprog, Mode3/Universal]
 static ENCODER_VALUES: Mutex<CriticalSectionRawMutex, [u8; 3]> = Mutex::new([64, 64, 64]); // Start at middle (64)
 
-#[derive(Clone, PartialEq, Eq, Hash)]
-enum Key {
-    EncoderLeft,
-    EncoderRight,
-    EncoderButton,
-    Key1,
-    Key2,
-    Key3,
-}
-
-#[derive(Clone, Copy, PartialEq)]
-enum Event {
-    Pressed,
-    Released,
-}
-
-#[derive(Clone)]
-struct KeyEvent {
-    key: Key,
-    event: Event,
-}
-
 /// MIDI Layout 1 - Position 1 (Keyboard mode selector - Teal LED)
 /// Channel 15, Notes for keys (C1, C#1, D1), free CCs for encoder
 const MIDI_LAYOUT_1: MidiLayout = MidiLayout {
@@ -51,10 +27,14 @@ const MIDI_LAYOUT_1: MidiLayout = MidiLayout {
     key1: MidiInputConfig::note(14, 36, 127),   // Note C1 (MIDI note 36)
     key2: MidiInputConfig::note(14, 37, 127),   // Note C#1 (MIDI note 37)
     key3: MidiInputConfig::note(14, 38, 127),   // Note D1 (MIDI note 38)
+    gestures: &[],
+    combos: &[],
 };
 
 /// MIDI Layout 2 - Position 2 (Picoprog mode selector - Orange LED)
-/// Channel 15, First set of CC values
+/// Channel 15, First set of CC values, the encoder button together with a key controls the
+/// UART header: Key1 detects the baud rate, Key2 switches the sniffer on or off and Key3
+/// sends the capture. Chords of the keys stay free.
 const MIDI_LAYOUT_2: MidiLayout = MidiLayout {
     encoder_left: MidiInputConfig::cc(14, 104), // CC 104 (undefined/free)
     encoder_right: MidiInputConfig::cc(14, 104), // CC 104 (undefined/free)
@@ -62,10 +42,25 @@ const MIDI_LAYOUT_2: MidiLayout = MidiLayout {
     key1: MidiInputConfig::cc(14, 20),          // CC 20 (General Purpose 1)
     key2: MidiInputConfig::cc(14, 21),          // CC 21 (General Purpose 2)
     key3: MidiInputConfig::cc(14, 22),          // CC 22 (General Purpose 3)
+    gestures: &[],
+    combos: &[
+        Combo {
+            keys: &[Key::EncoderButton, Key::Key1],
+            action: MidiComboAction::AutoBaud,
+        },
+        Combo {
+            keys: &[Key::EncoderButton, Key::Key2],
+            action: MidiComboAction::Sniffer,
+        },
+        Combo {
+            keys: &[Key::EncoderButton, Key::Key3],
+            action: MidiComboAction::CaptureDump,
+        },
+    ],
 };
 
 /// MIDI Layout 3 - Position 3 (Universal/neutral mode selector - Pink LED)
-/// Channel 15, Second set of CC values
+/// Channel 15, Second set of CC values, the encoder button sends a CC per gesture
 const MIDI_LAYOUT_3: MidiLayout = MidiLayout {
     encoder_left: MidiInputConfig::cc(14, 106), // CC 106 (undefined/free)
     encoder_right: MidiInputConfig::cc(14, 106), // CC 106 (undefined/free)
@@ -73,6 +68,15 @@ const MIDI_LAYOUT_3: MidiLayout = MidiLayout {
     key1: MidiInputConfig::cc(14, 23),          // CC 23 (General Purpose 4)
     key2: MidiInputConfig::cc(14, 24),          // CC 24 (General Purpose 5)
     key3: MidiInputConfig::cc(14, 25),          // CC 25 (General Purpose 6)
+    gestures: &[(
+        Key::EncoderButton,
+        MidiGestureConfig {
+            tap: MidiInputConfig::cc(14, 107),        // CC 107 (undefined/free)
+            double_tap: MidiInputConfig::cc(14, 108), // CC 108 (undefined/free)
+            hold: MidiInputConfig::cc(14, 109),       // CC 109 (undefined/free)
+        },
+    )],
+    combos: &[],
 };
 
 /// Encode a MIDI message into a USB-MIDI packet (4 bytes)
@@ -152,6 +156,7 @@ async fn mode_monitor_task(
             }
             // Signal that mode changed
             crate::MODE_CHANGED.signal(());
+            crate::uart::HEADER_CHANGED.signal(());
             last_mode = current_mode;
         }
     }
@@ -161,8 +166,6 @@ async fn mode_monitor_task(
 pub async fn midi_task(
     spawner: Spawner,
     midi_class: MidiClass<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>>,
-    button_resources: ButtonResources,
-    encoder_resources: EncoderResources,
     _initial_mode: crate::DeviceMode,
     selector_keyboard: Input<'static>,
     selector_picoprog: Input<'static>,
@@ -175,23 +178,35 @@ pub async fn midi_task(
     // Split MIDI class into sender and receiver
     let (mut sender, _) = midi_class.split();
 
-    interrupt::SWI_IRQ_0.set_priority(Priority::P2);
-    let spawner_encoder: embassy_executor::SendSpawner =
-        EXECUTOR_ENCODER.start(interrupt::SWI_IRQ_0);
-    spawner_encoder
-        .spawn(encoder_task(encoder_resources))
-        .unwrap();
-
-    spawner.spawn(button_task(button_resources)).unwrap();
-
     let mut sub = KEY_EVENT_QUEUE.subscriber().unwrap();
 
     // Track which layout each key was pressed with to ensure matching release
     let mut pressed_configs: heapless::FnvIndexMap<Key, MidiInputConfig, 4> =
         heapless::FnvIndexMap::new();
 
+    // Gesture messages of each key, taken from the layout active when its gesture started
+    let mut gestures = GestureRecognizer::new(DOUBLE_TAP_TERM, HOLD_TERM);
+    let mut gesture_configs: heapless::FnvIndexMap<Key, MidiGestureConfig, 4> =
+        heapless::FnvIndexMap::new();
+
+    // Combo messages of active combos, taken from the layout active when they were pressed
+    let mut combos = ComboDetector::new(COMBO_TERM);
+    let mut combo_configs: heapless::FnvIndexMap<usize, MidiInputConfig, 2> =
+        heapless::FnvIndexMap::new();
+
     loop {
-        let key_event: KeyEvent = sub.next_message_pure().await;
+        // Wake up when buffered combo keys or a gesture is decided by its timeout
+        let deadline = [combos.deadline(), gestures.deadline()]
+            .into_iter()
+            .flatten()
+            .min();
+        let key_event: Option<KeyEvent> = match deadline {
+            Some(deadline) => match select(sub.next_message_pure(), Timer::at(deadline)).await {
+                Either::First(key_event) => Some(key_event),
+                Either::Second(()) => None,
+            },
+            None => Some(sub.next_message_pure().await),
+        };
 
         // Read current mode from shared mutex
         let current_mode = {
@@ -205,187 +220,187 @@ pub async fn midi_task(
             crate::DeviceMode::Universal => &MIDI_LAYOUT_3,
         };
 
-        match key_event.key {
-            Key::EncoderLeft => {
-                sender =
-                    handle_encoder_interaction(sender, &layout.encoder_left, false, current_mode)
-                        .await;
-            }
-            Key::EncoderRight => {
-                sender =
-                    handle_encoder_interaction(sender, &layout.encoder_right, true, current_mode)
-                        .await;
+        let now = Instant::now();
+        let events = match key_event {
+            Some(key_event) => {
+                // Presses on positions handled by the HID backend can't start combos
+                if current_mode.backend() != Backend::Midi && key_event.event == Event::Pressed {
+                    continue;
+                }
+                combos.process(layout.combos, key_event, now)
             }
-            Key::EncoderButton => {
-                let config = match key_event.event {
-                    Event::Pressed => {
-                        // Store the config for this press
-                        let _ =
-                            pressed_configs.insert(key_event.key.clone(), layout.encoder_button);
-                        &layout.encoder_button
-                    }
-                    Event::Released => {
-                        // Use the stored config from when it was pressed
-                        pressed_configs
-                            .get(&key_event.key)
-                            .unwrap_or(&layout.encoder_button)
+            None => combos.tick(layout.combos, now),
+        };
+
+        for gesture in gestures.tick(now) {
+            sender = send_midi_gesture(sender, &gesture_configs, gesture).await;
+        }
+
+        for event in events {
+            let key_event = match event {
+                ComboEvent::Key(key_event) => key_event,
+                ComboEvent::Combo { index, event } => {
+                    let config = match event {
+                        Event::Pressed => {
+                            match layout.combos.get(index).map(|combo| combo.action) {
+                                Some(MidiComboAction::Message(config)) => {
+                                    let _ = combo_configs.insert(index, config);
+                                    Some(config)
+                                }
+                                Some(MidiComboAction::AutoBaud) => {
+                                    crate::uart::AUTO_BAUD_REQUESTED.signal(());
+                                    None
+                                }
+                                Some(MidiComboAction::Sniffer) => {
+                                    crate::sniffer::toggle();
+                                    None
+                                }
+                                Some(MidiComboAction::CaptureDump) => {
+                                    crate::capture::DUMP_REQUESTED.signal(());
+                                    None
+                                }
+                                None => None,
+                            }
+                        }
+                        Event::Released => combo_configs.remove(&index),
+                    };
+                    if let Some(config) = config {
+                        sender = send_midi_message(sender, &config, event).await;
                     }
-                };
-                sender = send_midi_message(sender, config, key_event.event).await;
-                if key_event.event == Event::Released {
-                    pressed_configs.remove(&key_event.key);
+                    continue;
                 }
+            };
+
+            // Positions handled by the HID backend don't produce MIDI messages, but keys
+            // pressed before a mode switch still get their matching release
+            if current_mode.backend() != Backend::Midi
+                && !pressed_configs.contains_key(&key_event.key)
+                && !gestures.is_active(key_event.key)
+            {
+                continue;
             }
-            Key::Key1 => {
-                let config = match key_event.event {
-                    Event::Pressed => {
-                        let _ = pressed_configs.insert(key_event.key.clone(), layout.key1);
-                        &layout.key1
+
+            // Keys with gesture messages go through the gesture recognizer instead of
+            // sending their press/release message
+            let gesture_config = if gestures.is_active(key_event.key) {
+                gesture_configs.get(&key_event.key).copied()
+            } else if key_event.event == Event::Pressed && !key_event.key.is_rotation() {
+                layout.gesture_config(key_event.key)
+            } else {
+                None
+            };
+
+            let recognized = match gesture_config {
+                Some(config) => {
+                    let _ = gesture_configs.insert(key_event.key, config);
+                    gestures.process(key_event.key, key_event.event, now)
+                }
+                None if key_event.event == Event::Pressed => {
+                    let mut recognized = gestures.tick(now);
+                    for gesture in gestures.interrupt(key_event.key) {
+                        let _ = recognized.push(gesture);
                     }
-                    Event::Released => pressed_configs.get(&key_event.key).unwrap_or(&layout.key1),
-                };
-                sender = send_midi_message(sender, config, key_event.event).await;
-                if key_event.event == Event::Released {
-                    pressed_configs.remove(&key_event.key);
+                    recognized
                 }
+                None => gestures.tick(now),
+            };
+
+            for gesture in recognized {
+                sender = send_midi_gesture(sender, &gesture_configs, gesture).await;
             }
-            Key::Key2 => {
-                let config = match key_event.event {
-                    Event::Pressed => {
-                        let _ = pressed_configs.insert(key_event.key.clone(), layout.key2);
-                        &layout.key2
+
+            if gesture_config.is_some() {
+                continue;
+            }
+
+            match key_event.key {
+                Key::EncoderLeft => {
+                    sender = handle_encoder_interaction(
+                        sender,
+                        &layout.encoder_left,
+                        false,
+                        current_mode,
+                    )
+                    .await;
+                }
+                Key::EncoderRight => {
+                    sender = handle_encoder_interaction(
+                        sender,
+                        &layout.encoder_right,
+                        true,
+                        current_mode,
+                    )
+                    .await;
+                }
+                Key::EncoderButton => {
+                    let config = match key_event.event {
+                        Event::Pressed => {
+                            // Store the config for this press
+                            let _ = pressed_configs.insert(key_event.key, layout.encoder_button);
+                            &layout.encoder_button
+                        }
+                        Event::Released => {
+                            // Use the stored config from when it was pressed
+                            pressed_configs
+                                .get(&key_event.key)
+                                .unwrap_or(&layout.encoder_button)
+                        }
+                    };
+                    sender = send_midi_message(sender, config, key_event.event).await;
+                    if key_event.event == Event::Released {
+                        pressed_configs.remove(&key_event.key);
                     }
-                    Event::Released => pressed_configs.get(&key_event.key).unwrap_or(&layout.key2),
-                };
-                sender = send_midi_message(sender, config, key_event.event).await;
-                if key_event.event == Event::Released {
-                    pressed_configs.remove(&key_event.key);
                 }
-            }
-            Key::Key3 => {
-                let config = match key_event.event {
-                    Event::Pressed => {
-                        let _ = pressed_configs.insert(key_event.key.clone(), layout.key3);
-                        &layout.key3
+                Key::Key1 => {
+                    let config = match key_event.event {
+                        Event::Pressed => {
+                            let _ = pressed_configs.insert(key_event.key, layout.key1);
+                            &layout.key1
+                        }
+                        Event::Released => {
+                            pressed_configs.get(&key_event.key).unwrap_or(&layout.key1)
+                        }
+                    };
+                    sender = send_midi_message(sender, config, key_event.event).await;
+                    if key_event.event == Event::Released {
+                        pressed_configs.remove(&key_event.key);
+                    }
+                }
+                Key::Key2 => {
+                    let config = match key_event.event {
+                        Event::Pressed => {
+                            let _ = pressed_configs.insert(key_event.key, layout.key2);
+                            &layout.key2
+                        }
+                        Event::Released => {
+                            pressed_configs.get(&key_event.key).unwrap_or(&layout.key2)
+                        }
+                    };
+                    sender = send_midi_message(sender, config, key_event.event).await;
+                    if key_event.event == Event::Released {
+                        pressed_configs.remove(&key_event.key);
+                    }
+                }
+                Key::Key3 => {
+                    let config = match key_event.event {
+                        Event::Pressed => {
+                            let _ = pressed_configs.insert(key_event.key, layout.key3);
+                            &layout.key3
+                        }
+                        Event::Released => {
+                            pressed_configs.get(&key_event.key).unwrap_or(&layout.key3)
+                        }
+                    };
+                    sender = send_midi_message(sender, config, key_event.event).await;
+                    if key_event.event == Event::Released {
+                        pressed_configs.remove(&key_event.key);
                     }
-                    Event::Released => pressed_configs.get(&key_event.key).unwrap_or(&layout.key3),
-                };
-                sender = send_midi_message(sender, config, key_event.event).await;
-                if key_event.event == Event::Released {
-                    pressed_configs.remove(&key_event.key);
                 }
             }
         }
     }
 }
 
-static EXECUTOR_ENCODER: InterruptExecutor = InterruptExecutor::new();
-
-#[interrupt]
-unsafe fn SWI_IRQ_0() {
-    unsafe { EXECUTOR_ENCODER.on_interrupt() }
-}
-
-#[embassy_executor::task]
-pub async fn encoder_task(r: EncoderResources) -> ! {
-    let encoder_left: Input<'_> = Input::new(r.encoder_left, Pull::None);
-    let mut encoder_right: Input<'_> = Input::new(r.encoder_right, Pull::None);
-
-    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
-
-    loop {
-        encoder_right.wait_for_falling_edge().await;
-
-        if encoder_left.get_level() == Level::Low {
-            publisher.publish_immediate(KeyEvent {
-                key: Key::EncoderLeft,
-                event: Event::Pressed,
-            });
-        } else {
-            publisher.publish_immediate(KeyEvent {
-                key: Key::EncoderRight,
-                event: Event::Pressed,
-            });
-        };
-
-        encoder_right.wait_for_rising_edge().await;
-    }
-}
-
-#[embassy_executor::task]
-pub async fn button_task(r: ButtonResources) -> ! {
-    let mut key1: Input<'_> = Input::new(r.key1, Pull::Up);
-    key1.set_schmitt(true);
-
-    let mut key2: Input<'_> = Input::new(r.key2, Pull::Up);
-    key2.set_schmitt(true);
-
-    let mut key3: Input<'_> = Input::new(r.key3, Pull::Up);
-    key3.set_schmitt(true);
-
-    let mut encoder_button: Input<'_> = Input::new(r.encoder_button, Pull::Up);
-    encoder_button.set_schmitt(true);
-
-    let publisher = KEY_EVENT_QUEUE.publisher().unwrap();
-
-    loop {
-        let (_, index) = select_array([
-            key1.wait_for_any_edge(),
-            key2.wait_for_any_edge(),
-            key3.wait_for_any_edge(),
-            encoder_button.wait_for_any_edge(),
-        ])
-        .await;
-
-        // Small debounce delay to avoid reading bounce
-        Timer::after(Duration::from_millis(5)).await;
-
-        match index {
-            0 => match key1.get_level() {
-                Level::Low => publisher.publish_immediate(KeyEvent {
-                    key: Key::Key1,
-                    event: Event::Pressed,
-                }),
-                Level::High => publisher.publish_immediate(KeyEvent {
-                    key: Key::Key1,
-                    event: Event::Released,
-                }),
-            },
-            1 => match key2.get_level() {
-                Level::Low => publisher.publish_immediate(KeyEvent {
-                    key: Key::Key2,
-                    event: Event::Pressed,
-                }),
-                Level::High => publisher.publish_immediate(KeyEvent {
-                    key: Key::Key2,
-                    event: Event::Released,
-                }),
-            },
-            2 => match key3.get_level() {
-                Level::Low => publisher.publish_immediate(KeyEvent {
-                    key: Key::Key3,
-                    event: Event::Pressed,
-                }),
-                Level::High => publisher.publish_immediate(KeyEvent {
-                    key: Key::Key3,
-                    event: Event::Released,
-                }),
-            },
-            3 => match encoder_button.get_level() {
-                Level::Low => publisher.publish_immediate(KeyEvent {
-                    key: Key::EncoderButton,
-                    event: Event::Pressed,
-                }),
-                Level::High => publisher.publish_immediate(KeyEvent {
-                    key: Key::EncoderButton,
-                    event: Event::Released,
-                }),
-            },
-            _ => unreachable!(),
-        };
-    }
-}
-
 /// Handle encoder rotation - sends appropriate MIDI value based on direction
 async fn handle_encoder_interaction(
     mut sender: Sender<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>>,
@@ -423,12 +438,33 @@ async fn handle_encoder_interaction(
     sender
 }
 
+/// Send the MIDI message of a recognized gesture as a momentary press and release
+async fn send_midi_gesture(
+    mut sender: Sender<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>>,
+    gesture_configs: &heapless::FnvIndexMap<Key, MidiGestureConfig, 4>,
+    gesture: GestureEvent,
+) -> Sender<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>> {
+    if let Some(config) = gesture_configs.get(&gesture.key) {
+        let config = config.config(gesture.gesture);
+        sender = send_midi_message(sender, &config, Event::Pressed).await;
+        sender = send_midi_message(sender, &config, Event::Released).await;
+    }
+
+    sender
+}
+
 /// Send MIDI message for button press/release
 async fn send_midi_message(
     mut sender: Sender<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>>,
     config: &MidiInputConfig,
     event: Event,
 ) -> Sender<'static, embassy_rp::usb::Driver<'static, embassy_rp::peripherals::USB>> {
+    // Messages are dropped while the host is suspended instead of blocking on the endpoint,
+    // the key events still go through the combos and gestures so they stay in sync
+    if usb_state::is_suspended() {
+        return sender;
+    }
+
     // Map button press/release to MIDI values
     // For CC: 127 = pressed, 0 = released
     // For Notes: 127 = Note On (pressed), 0 = Note Off (released)
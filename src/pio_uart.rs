@@ -0,0 +1,711 @@
+use core::ptr;
+use core::sync::atomic::{Ordering, compiler_fence};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::dma::{AnyChannel, Channel};
+use embassy_rp::gpio::Level;
+use embassy_rp::pac::dma::vals::{DataSize, TreqSel};
+use embassy_rp::peripherals::PIO0;
+use embassy_rp::pio::program::pio_asm;
+use embassy_rp::pio::{
+    Common, Config, Direction, FifoJoin, Instance, LoadedProgram, Pin, PioPin, ShiftDirection,
+    StateMachine,
+};
+use embassy_rp::{Peripheral, PeripheralRef, into_ref, pac};
+use embassy_time::{Duration, Instant, Timer};
+use fixed::FixedU32;
+use fixed::types::extra::U8;
+
+/// Slowest baud rate, the clock divider of the state machines overflows below it
+pub const MIN_BAUD_RATE: u32 = 300;
+
+/// Fastest baud rate, above it the receiver can't sample the bits reliably anymore
+pub const MAX_BAUD_RATE: u32 = 4_000_000;
+
+/// PIO clock cycles per bit of both programs
+const CYCLES_PER_BIT: u32 = 8;
+
+/// Bits of the longest frame, with 9 data bits, parity and 2 stop bits
+const MAX_FRAME_BITS: u64 = 13;
+
+/// `set y, 0` instruction, the low 5 bits hold the value
+const SET_Y: u16 = 0xE040;
+
+/// Frames in the ring buffer of the receiver, 3.4 ms at 3 Mbaud
+const RX_RING_FRAMES: usize = 1024;
+
+/// Size of the ring buffer in bytes as a power of 2, the DMA wraps its write address
+/// at this boundary
+const RX_RING_SIZE_BITS: u8 = 12;
+
+/// DREQ of the RX FIFO of state machine 0 of PIO0, the others follow it
+const PIO0_RX0_DREQ: u8 = 4;
+
+/// Time between checks of the ring buffer while it is empty
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Transfers left at which the DMA channel is restarted, hours before it would stop
+const RX_REARM_COUNT: u32 = 1 << 31;
+
+/// Baud rates the auto-baud detection picks from
+pub const STANDARD_BAUD_RATES: [u32; 23] = [
+    300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 28800, 38400, 57600,
+    // Boot messages of the ESP8266
+    74880, 115200, 230400, 250000, 460800, 500000, 921600, 1000000, 1500000, 2000000, 3000000,
+    4000000,
+];
+
+/// Pulses the auto-baud detection measures before it picks the shortest one
+const AUTO_BAUD_PULSES: usize = 64;
+
+/// Fewest pulses the auto-baud detection needs, a few bytes of text
+const AUTO_BAUD_MIN_PULSES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always 1
+    Mark,
+    /// Parity bit always 0
+    Space,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Frame format of the UART
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Format {
+    /// 5 to 9 data bits, the 9th bit is sent as 0 and dropped when received
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// Only the first stop bit is checked when receiving
+    pub stop_bits: StopBits,
+}
+
+pub const FORMAT_8N1: Format = Format {
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+};
+
+impl Format {
+    pub const fn is_valid(&self) -> bool {
+        self.data_bits >= 5 && self.data_bits <= 9
+    }
+
+    /// Parity bit following `data`, if the format has one
+    fn parity_bit(&self, data: u32) -> Option<u32> {
+        match self.parity {
+            Parity::None => None,
+            Parity::Odd => Some(!data.count_ones() & 1),
+            Parity::Even => Some(data.count_ones() & 1),
+            Parity::Mark => Some(1),
+            Parity::Space => Some(0),
+        }
+    }
+
+    /// Data bits, parity bit and first stop bit pushed by the receiver
+    fn rx_bits(&self) -> u32 {
+        self.data_bits as u32 + self.parity_bit(0).map_or(0, |_| 1) + 1
+    }
+
+    /// Length of a frame in half bits, from the start bit to the end of the stop bits
+    fn half_bits(&self) -> u32 {
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 2,
+            StopBits::OnePointFive => 3,
+            StopBits::Two => 4,
+        };
+        2 * self.rx_bits() + stop_bits
+    }
+
+    /// Frame of a byte for the transmitter program
+    ///
+    /// The low 5 bits are the number of half bits minus one, followed by the half bits
+    /// of the start bit, the data bits, the parity bit and the stop bits. Half bits allow
+    /// 1.5 stop bits, and the longest frame of 26 half bits still fits into a FIFO word.
+    fn tx_frame(&self, data: u8) -> u32 {
+        let data = data as u32 & ((1 << self.data_bits) - 1);
+        let mut frame = 0;
+        let mut half_bits = 0;
+        let mut push = |bit: u32, halves: u32| {
+            for _ in 0..halves {
+                frame |= bit << half_bits;
+                half_bits += 1;
+            }
+        };
+
+        push(0, 2);
+        for i in 0..self.data_bits {
+            push((data >> i) & 1, 2);
+        }
+        if let Some(parity) = self.parity_bit(data) {
+            push(parity, 2);
+        }
+        match self.stop_bits {
+            StopBits::One => push(1, 2),
+            StopBits::OnePointFive => push(1, 3),
+            StopBits::Two => push(1, 4),
+        }
+
+        (frame << 5) | (half_bits - 1)
+    }
+
+    /// Byte of a frame pushed by the receiver program
+    fn rx_byte(&self, frame: u32) -> Result<u8, RxError> {
+        let bits = self.rx_bits();
+        // The bits are shifted in from the left
+        let frame = frame >> (32 - bits);
+
+        let data_bits = self.data_bits as u32;
+        let data = frame & ((1 << data_bits) - 1);
+        if frame == 0 {
+            return Err(RxError::Break);
+        }
+        if frame >> (bits - 1) == 0 {
+            return Err(RxError::Framing);
+        }
+        if let Some(parity) = self.parity_bit(data) {
+            if (frame >> data_bits) & 1 != parity {
+                return Err(RxError::Parity);
+            }
+        }
+        Ok(data as u8)
+    }
+}
+
+/// Errors of received bytes, the byte is dropped
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum RxError {
+    /// The stop bit was 0, e.g. because of a wrong baud rate
+    Framing,
+    Parity,
+    /// The line was low for a whole frame, the other side sends a break
+    Break,
+}
+
+/// Standard baud rate closest to `baud`, relative to the rates
+fn nearest_standard_rate(baud: u32) -> u32 {
+    let distance = |rate: u32| {
+        let (low, high) = (rate.min(baud) as u64, rate.max(baud) as u64);
+        high * 1000 / low.max(1)
+    };
+    STANDARD_BAUD_RATES
+        .into_iter()
+        .min_by_key(|rate| distance(*rate))
+        .unwrap_or(baud)
+}
+
+/// Clock divider of a state machine running a program at `baud`
+///
+/// The fractional part keeps the error well below 1% at fast rates like 1500000 baud,
+/// where an integer divider would be off by several percent.
+fn clock_divider(baud: u32) -> FixedU32<U8> {
+    let baud = baud.clamp(MIN_BAUD_RATE, MAX_BAUD_RATE);
+    let bits = ((clk_sys_freq() as u64) << 8) / (CYCLES_PER_BIT * baud) as u64;
+    FixedU32::from_bits(bits as u32)
+}
+
+/// Stop a state machine and restart its program from the beginning
+fn restart<PIO: Instance, const SM: usize>(
+    sm: &mut StateMachine<'_, PIO, SM>,
+    config: &Config<'_, PIO>,
+) {
+    sm.set_enable(false);
+    sm.set_config(config);
+    sm.restart();
+}
+
+/// Restart a state machine with a new clock divider
+fn reconfigure<PIO: Instance, const SM: usize>(
+    sm: &mut StateMachine<'_, PIO, SM>,
+    config: &mut Config<'_, PIO>,
+    baud: u32,
+) {
+    config.clock_divider = clock_divider(baud);
+    restart(sm, config);
+}
+
+/// UART transmitter on a PIO state machine, whose baud rate and format can be changed
+/// while it runs
+pub struct PioUartTx<'d, PIO: Instance, const SM: usize> {
+    sm: StateMachine<'d, PIO, SM>,
+    config: Config<'d, PIO>,
+    pin: Pin<'d, PIO>,
+    baud: u32,
+    format: Format,
+    /// Whether the line is held low for a break, with the state machine stopped
+    breaking: bool,
+}
+
+impl<'d, PIO: Instance, const SM: usize> PioUartTx<'d, PIO, SM> {
+    pub fn new(
+        baud: u32,
+        format: Format,
+        common: &mut Common<'d, PIO>,
+        sm: StateMachine<'d, PIO, SM>,
+        tx_pin: impl PioPin,
+    ) -> Self {
+        let prg = pio_asm!(
+            r#"
+                ; OUT pin 0 is mapped to the TX pin, the frames are prepared by software
+                    pull                ; Stall with the line idle after the stop bits
+                    out x, 5            ; Number of half bits in the frame, minus one
+                halfbit:
+                    out pins, 1 [2]     ; Shift 1 half bit from OSR to the TX pin
+                    jmp x-- halfbit     ; Each loop iteration is 4 cycles
+            "#
+        );
+        let prg = common.load_program(&prg.program);
+
+        let tx_pin = common.make_pio_pin(tx_pin);
+
+        let mut config = Config::default();
+        config.set_out_pins(&[&tx_pin]);
+        config.use_program(&prg, &[]);
+        config.shift_out.auto_fill = false;
+        config.shift_out.direction = ShiftDirection::Right;
+        config.fifo_join = FifoJoin::TxOnly;
+
+        let mut uart_tx = Self {
+            sm,
+            config,
+            pin: tx_pin,
+            baud,
+            format,
+            breaking: false,
+        };
+        uart_tx.configure(baud, format);
+        uart_tx
+    }
+
+    /// Change the baud rate and format, a byte being sent meanwhile is garbled
+    ///
+    /// This also ends a break.
+    pub fn configure(&mut self, baud: u32, format: Format) {
+        self.baud = baud.clamp(MIN_BAUD_RATE, MAX_BAUD_RATE);
+        self.format = format;
+        self.breaking = false;
+        reconfigure(&mut self.sm, &mut self.config, baud);
+        // Idle high, also after `release` or a break
+        self.sm.set_pins(Level::High, &[&self.pin]);
+        self.sm.set_pin_dirs(Direction::Out, &[&self.pin]);
+        self.sm.set_enable(true);
+    }
+
+    /// Stop sending and leave the pin floating for a receiver, until the next `configure`
+    pub fn release(&mut self) {
+        self.sm.set_enable(false);
+        self.sm.set_pin_dirs(Direction::In, &[&self.pin]);
+    }
+
+    /// TX pin, e.g. to receive on it after `release`
+    pub fn pin(&self) -> &Pin<'d, PIO> {
+        &self.pin
+    }
+
+    /// Hold the line low for a break once the bytes already written are sent, or end it
+    pub async fn set_break(&mut self, on: bool) {
+        if on == self.breaking {
+            return;
+        }
+
+        if on {
+            let frame_time = Duration::from_micros(MAX_FRAME_BITS * 1_000_000 / self.baud as u64);
+            while !self.sm.tx().empty() {
+                Timer::after(frame_time).await;
+            }
+            // The last frame leaves the FIFO when it starts
+            Timer::after(frame_time).await;
+            self.sm.set_enable(false);
+            self.sm.set_pins(Level::Low, &[&self.pin]);
+        } else {
+            self.sm.set_pins(Level::High, &[&self.pin]);
+            restart(&mut self.sm, &self.config);
+            self.sm.set_enable(true);
+        }
+        self.breaking = on;
+    }
+
+    pub async fn write_u8(&mut self, data: u8) {
+        self.sm.tx().wait_push(self.format.tx_frame(data)).await;
+    }
+}
+
+/// Ring buffer the DMA writes the received frames to, aligned to its size
+#[repr(C, align(4096))]
+pub struct RxRing([u32; RX_RING_FRAMES]);
+
+impl RxRing {
+    pub const fn new() -> Self {
+        Self([0; RX_RING_FRAMES])
+    }
+}
+
+/// DMA channel copying the RX FIFO of a state machine of PIO0 into a ring buffer
+///
+/// The channel runs on its own and never raises an interrupt, the receiver polls how far
+/// it got. That keeps up with fast baud rates, where waking a task for every byte
+/// overruns the FIFO.
+struct RxDma<'d> {
+    dma: PeripheralRef<'d, AnyChannel>,
+    ring: &'d mut RxRing,
+    /// Frames received before the channel was last restarted, wrapping
+    offset: u32,
+    /// Frames read from the ring buffer, wrapping
+    consumed: u32,
+    /// Frames overwritten before they were read
+    lost: u32,
+}
+
+impl<'d> RxDma<'d> {
+    fn new<const SM: usize>(
+        dma: impl Peripheral<P = impl Channel> + 'd,
+        ring: &'d mut RxRing,
+    ) -> Self {
+        into_ref!(dma);
+        let ch = dma.regs();
+        ch.read_addr()
+            .write_value(pac::PIO0.rxf(SM).as_ptr() as u32);
+        ch.write_addr().write_value(ring.0.as_mut_ptr() as u32);
+        ch.trans_count().write_value(u32::MAX);
+        compiler_fence(Ordering::SeqCst);
+        ch.ctrl_trig().write(|w| {
+            w.set_treq_sel(TreqSel::from(PIO0_RX0_DREQ + SM as u8));
+            w.set_data_size(DataSize::SIZE_WORD);
+            w.set_incr_read(false);
+            w.set_incr_write(true);
+            w.set_ring_sel(true);
+            w.set_ring_size(RX_RING_SIZE_BITS);
+            // Chaining to itself disables chaining
+            w.set_chain_to(dma.number());
+            w.set_en(true);
+        });
+
+        Self {
+            dma: dma.map_into(),
+            ring,
+            offset: 0,
+            consumed: 0,
+            lost: 0,
+        }
+    }
+
+    /// Frames written by the channel so far, wrapping
+    fn received(&self) -> u32 {
+        let remaining = self.dma.regs().trans_count().read();
+        compiler_fence(Ordering::SeqCst);
+        self.offset.wrapping_add(u32::MAX - remaining)
+    }
+
+    /// Frames received but not read yet
+    fn pending(&self) -> u32 {
+        self.received()
+            .wrapping_sub(self.consumed)
+            .min(RX_RING_FRAMES as u32)
+    }
+
+    /// Drop the frames received so far
+    fn skip(&mut self) {
+        self.consumed = self.received();
+    }
+
+    /// Oldest frame not read yet
+    fn pop(&mut self) -> Option<u32> {
+        let received = self.received();
+        let pending = received.wrapping_sub(self.consumed);
+        if pending == 0 {
+            return None;
+        }
+        if pending > RX_RING_FRAMES as u32 {
+            // The channel went around the ring, the frames left in it are out of order
+            self.lost = self.lost.wrapping_add(pending);
+            self.consumed = received;
+            return None;
+        }
+
+        let index = self.consumed as usize % RX_RING_FRAMES;
+        // Safety: the channel wrote this frame already and only writes it again after
+        // going around the ring, the volatile read keeps the compiler from caching it
+        let frame = unsafe { ptr::read_volatile(&self.ring.0[index]) };
+        self.consumed = self.consumed.wrapping_add(1);
+        Some(frame)
+    }
+
+    /// Restart the channel with a full transfer count before it runs out
+    ///
+    /// The channel continues at its write address, the FIFO holds the frames arriving
+    /// meanwhile.
+    fn rearm(&mut self) {
+        let ch = self.dma.regs();
+        if ch.trans_count().read() > RX_REARM_COUNT {
+            return;
+        }
+
+        let estimate = self.received();
+        pac::DMA
+            .chan_abort()
+            .modify(|m| m.set_chan_abort(1 << self.dma.number()));
+        while ch.ctrl_trig().read().busy() {}
+
+        // Frames written between the estimate and the abort show in the write address
+        let write_index = ch
+            .write_addr()
+            .read()
+            .wrapping_sub(self.ring.0.as_ptr() as u32)
+            / 4;
+        let behind = write_index.wrapping_sub(estimate) % RX_RING_FRAMES as u32;
+        self.offset = estimate.wrapping_add(behind);
+
+        ch.trans_count().write_value(u32::MAX);
+        compiler_fence(Ordering::SeqCst);
+        ch.ctrl_trig().modify(|w| w.set_en(true));
+    }
+}
+
+/// UART receiver on a state machine of PIO0, whose baud rate and format can be changed
+/// while it runs
+///
+/// A DMA channel moves the received frames into a ring buffer, only PIO0 is supported
+/// because the address and DREQ of its FIFO are needed for that.
+pub struct PioUartRx<'d, const SM: usize> {
+    sm: StateMachine<'d, PIO0, SM>,
+    config: Config<'d, PIO0>,
+    /// Configuration of the program measuring pulses for the auto-baud detection
+    pulse_config: Config<'d, PIO0>,
+    baud: u32,
+    format: Format,
+    dma: RxDma<'d>,
+}
+
+/// Programs of the receivers, loaded once for all of them
+pub struct PioUartRxProgram<'d> {
+    prg: LoadedProgram<'d, PIO0>,
+    /// Program measuring pulses for the auto-baud detection
+    pulse_prg: LoadedProgram<'d, PIO0>,
+}
+
+impl<'d> PioUartRxProgram<'d> {
+    pub fn new(common: &mut Common<'d, PIO0>) -> Self {
+        let prg = pio_asm!(
+            r#"
+                ; IN pin 0 and the JMP pin are mapped to the RX pin, Y holds the data and
+                ; parity bits minus one
+                .wrap_target
+                start:
+                    wait 0 pin 0        ; Stall until start bit is asserted
+                    mov x, y    [10]    ; Preload bit counter, then delay until halfway through
+                bitloop:                ; the first data bit (12 cycles incl wait, mov)
+                    in pins, 1          ; Shift data and parity bits into ISR
+                    jmp x-- bitloop [6] ; Each loop iteration is 8 cycles
+                    jmp pin good_stop   ; Check the stop bit halfway through it
+                    in null, 1          ; Either a framing error or a break, push a 0 stop bit
+                    push                ; for software to tell them apart
+                    wait 1 pin 0        ; and wait for the line to return to idle
+                    jmp start
+                good_stop:              ; Back to waiting before the end of the stop bit, so
+                    in x, 1             ; the next start bit is not missed. X ran out to
+                    push                ; all ones, shift in a 1 stop bit.
+                .wrap
+            "#
+        );
+
+        // Pushes the length of every low and high pulse as a count down from 0xFFFFFFFF
+        let pulse_prg = pio_asm!(
+            r#"
+                ; IN pin 0 and the JMP pin are mapped to the RX pin
+                    wait 1 pin 0        ; Only measure whole pulses
+                .wrap_target
+                    wait 0 pin 0        ; Start of a low pulse
+                    mov x, ~null
+                low:
+                    jmp pin low_end     ; Each loop iteration is 2 cycles
+                    jmp x-- low
+                low_end:
+                    mov isr, x
+                    push noblock        ; Lengths are dropped rather than stalling the count
+                    mov x, ~null
+                high:
+                    jmp pin high_more
+                    jmp high_end
+                high_more:
+                    jmp x-- high        ; Also 2 cycles while high
+                high_end:
+                    mov isr, x
+                    push noblock
+                .wrap
+            "#
+        );
+
+        Self {
+            prg: common.load_program(&prg.program),
+            pulse_prg: common.load_program(&pulse_prg.program),
+        }
+    }
+}
+
+impl<'d, const SM: usize> PioUartRx<'d, SM> {
+    /// Receiver on `rx_pin`, which is an input unless a transmitter drives it
+    pub fn new(
+        baud: u32,
+        format: Format,
+        sm: StateMachine<'d, PIO0, SM>,
+        rx_pin: &Pin<'d, PIO0>,
+        program: &PioUartRxProgram<'d>,
+        dma: impl Peripheral<P = impl Channel> + 'd,
+        ring: &'d mut RxRing,
+    ) -> Self {
+        let mut config = Config::default();
+        config.use_program(&program.prg, &[]);
+        config.set_in_pins(&[rx_pin]);
+        config.set_jmp_pin(rx_pin);
+        config.shift_in.auto_fill = false;
+        config.shift_in.direction = ShiftDirection::Right;
+        config.shift_in.threshold = 32;
+        config.fifo_join = FifoJoin::RxOnly;
+
+        // Runs at the system clock, so the count resolves the fastest baud rates
+        let mut pulse_config = Config::default();
+        pulse_config.use_program(&program.pulse_prg, &[]);
+        pulse_config.set_in_pins(&[rx_pin]);
+        pulse_config.set_jmp_pin(rx_pin);
+        pulse_config.fifo_join = FifoJoin::RxOnly;
+
+        let mut uart_rx = Self {
+            sm,
+            config,
+            pulse_config,
+            baud,
+            format,
+            dma: RxDma::new::<SM>(dma, ring),
+        };
+        uart_rx.configure(baud, format);
+        uart_rx
+    }
+
+    /// Change the baud rate and format, a byte being received meanwhile is lost
+    pub fn configure(&mut self, baud: u32, format: Format) {
+        self.baud = baud.clamp(MIN_BAUD_RATE, MAX_BAUD_RATE);
+        self.format = format;
+        reconfigure(&mut self.sm, &mut self.config, baud);
+        self.resume();
+    }
+
+    /// Time the line takes for `frames` frames back to back
+    pub fn frame_time(&self, frames: u32) -> Duration {
+        let half_bits = (frames * self.format.half_bits()) as u64;
+        Duration::from_micros(half_bits * 1_000_000 / (2 * self.baud as u64))
+    }
+
+    /// Frames received but not read yet
+    pub fn pending(&self) -> u32 {
+        self.dma.pending()
+    }
+
+    /// Stop receiving until the next `configure`
+    pub fn stop(&mut self) {
+        self.sm.set_enable(false);
+    }
+
+    /// Start the stopped receiver program
+    fn resume(&mut self) {
+        // Safety: the state machine is stopped and only reads Y at the start of a frame.
+        // The loop samples the bits before the stop bit, which is checked after it.
+        unsafe {
+            self.sm
+                .exec_instr(SET_Y | (self.format.rx_bits() - 2) as u16);
+        }
+        // Bytes of the old format would fail the checks
+        while self.sm.rx().try_pull().is_some() {}
+        self.dma.skip();
+        self.sm.set_enable(true);
+    }
+
+    /// Number of bytes lost since the last call, because the ring buffer was full
+    ///
+    /// A full FIFO counts as one, the state machine doesn't tell how many it missed.
+    pub fn overruns(&mut self) -> u32 {
+        let fifo = self.sm.rx().stalled() as u32;
+        core::mem::take(&mut self.dma.lost) + fifo
+    }
+
+    /// Read the received bytes into `buf` without waiting
+    ///
+    /// Stops at the first byte with an error, which is returned with the number of
+    /// bytes before it.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> (usize, Option<RxError>) {
+        self.dma.rearm();
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(frame) = self.dma.pop() else {
+                break;
+            };
+            match self.format.rx_byte(frame) {
+                Ok(byte) => {
+                    buf[n] = byte;
+                    n += 1;
+                }
+                Err(error) => return (n, Some(error)),
+            }
+        }
+        (n, None)
+    }
+
+    /// Read the received bytes into `buf`, waiting for at least one byte or error
+    pub async fn read(&mut self, buf: &mut [u8]) -> (usize, Option<RxError>) {
+        loop {
+            let (n, error) = self.try_read(buf);
+            if n > 0 || error.is_some() {
+                return (n, error);
+            }
+            Timer::after(RX_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Detect the baud rate of the other side from the shortest pulse on the line
+    ///
+    /// The shortest pulse of some text is a single bit, its length gives the baud rate,
+    /// which is rounded to the nearest standard one. Nothing is received meanwhile, and
+    /// `None` is returned if there weren't enough pulses within `timeout`.
+    pub async fn detect_baud_rate(&mut self, timeout: Duration) -> Option<u32> {
+        restart(&mut self.sm, &self.pulse_config);
+        while self.sm.rx().try_pull().is_some() {}
+        self.dma.skip();
+        self.sm.set_enable(true);
+
+        // Shorter pulses are glitches, not bits
+        let min_cycles = clk_sys_freq() / MAX_BAUD_RATE / 2;
+        let deadline = Instant::now() + timeout;
+        let mut shortest = u32::MAX;
+        let mut pulses = 0;
+        while pulses < AUTO_BAUD_PULSES && Instant::now() < deadline {
+            self.dma.rearm();
+            let Some(count) = self.dma.pop() else {
+                Timer::after(RX_POLL_INTERVAL).await;
+                continue;
+            };
+            // The program counts down from the maximum, 2 cycles per step
+            let cycles = (u32::MAX - count).saturating_mul(2);
+            if cycles >= min_cycles {
+                shortest = shortest.min(cycles);
+                pulses += 1;
+            }
+        }
+
+        restart(&mut self.sm, &self.config);
+        self.resume();
+
+        if pulses < AUTO_BAUD_MIN_PULSES {
+            return None;
+        }
+        let baud = clk_sys_freq() / shortest;
+        defmt::debug!("[UART]: Shortest of {} pulses is {} baud", pulses, baud);
+        Some(nearest_standard_rate(baud))
+    }
+}
@@ -0,0 +1,34 @@
+use crate::layouts::{KeyType, PresentationEncoder, PresentationLayout};
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant};
+use usbd_hid::descriptor::KeyboardUsage;
+
+/// Presentation remote used by selector positions with the presentation backend
+pub static PRESENTATION_LAYOUT: PresentationLayout = PresentationLayout {
+    key1: KeyType::Keycode(KeyboardUsage::KeyboardPageUp),
+    key2: KeyType::Keycode(KeyboardUsage::KeyboardPageDown),
+    // Blanks the screen in PowerPoint, LibreOffice Impress and most PDF viewers
+    key3: KeyType::Keycode(KeyboardUsage::KeyboardBb),
+    encoder: PresentationEncoder::Pointer,
+    talk_time: Duration::from_secs(20 * 60),
+};
+
+/// Start of the talk timer of the presenter, shown on the LEDs
+static TALK_STARTED: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Time since the talk timer was started, `None` while it is stopped
+pub fn talk_elapsed() -> Option<Duration> {
+    TALK_STARTED
+        .lock(|started| started.get())
+        .map(|started| started.elapsed())
+}
+
+/// Publish the talk timer start of the presenter, the LEDs are updated if it changed
+pub fn set_talk_started(started: Option<Instant>) {
+    if TALK_STARTED.lock(|cell| cell.replace(started)) != started {
+        crate::led::INDICATORS_CHANGED.signal(());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::keys::{Event, Key, KeyEvent};
+use crate::layouts::{KeyType, MouseAction, PresentationEncoder, PresentationLayout};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use smart_leds::RGB8;
+use usbd_hid::descriptor::KeyboardUsage;
+
+/// Releasing the encoder button after this time resets the talk timer instead of
+/// switching the pointer direction or resetting the zoom
+const TIMER_RESET_PRESS: Duration = Duration::from_secs(1);
+
+/// Talk timer colors at the start, in the middle and at the end of the talk
+const TIMER_START_COLOR: RGB8 = RGB8 { r: 0, g: 10, b: 0 };
+const TIMER_MIDDLE_COLOR: RGB8 = RGB8 { r: 10, g: 10, b: 0 };
+const TIMER_END_COLOR: RGB8 = RGB8 { r: 10, g: 0, b: 0 };
+
+/// The running talk timer fades its color in steps of this time
+const TIMER_UPDATE: Duration = Duration::from_secs(1);
+
+/// Half period of the blinking once the talk is over time
+const TIMER_BLINK: Duration = Duration::from_millis(500);
+
+/// Codes resulting from a single key event
+pub type PresenterCodes = Vec<(KeyType, Event), 4>;
+
+/// Presentation remote state driven by the key events of positions using the presentation backend
+pub struct Presenter {
+    /// Keys pressed on the presenter, bit n = key with index n
+    held: u8,
+    /// Whether the encoder moves the pointer up and down instead of left and right
+    vertical: bool,
+    encoder_pressed_at: Option<Instant>,
+    /// Start of the talk, set by the first key press while the timer is stopped
+    talk_started: Option<Instant>,
+}
+
+impl Presenter {
+    pub const fn new() -> Self {
+        Self {
+            held: 0,
+            vertical: false,
+            encoder_pressed_at: None,
+            talk_started: None,
+        }
+    }
+
+    /// Start of the talk timer, `None` while it is stopped
+    pub fn talk_started(&self) -> Option<Instant> {
+        self.talk_started
+    }
+
+    /// Whether `key` was pressed on the presenter, so its release belongs to it
+    pub fn holds(&self, key: Key) -> bool {
+        self.held & (1 << key.index()) != 0
+    }
+
+    /// Update the presenter for a key event and get the codes to send
+    pub fn process(
+        &mut self,
+        layout: &PresentationLayout,
+        key_event: KeyEvent,
+        now: Instant,
+    ) -> PresenterCodes {
+        let mut codes = PresenterCodes::new();
+        let bit = 1 << key_event.key.index();
+
+        if key_event.event == Event::Pressed && self.talk_started.is_none() {
+            self.talk_started = Some(now);
+        }
+
+        match key_event.key {
+            Key::EncoderLeft | Key::EncoderRight => {
+                let clockwise = key_event.key == Key::EncoderRight;
+                match layout.encoder {
+                    PresentationEncoder::Pointer => {
+                        let action = match (self.vertical, clockwise) {
+                            (false, false) => MouseAction::MoveLeft,
+                            (false, true) => MouseAction::MoveRight,
+                            (true, false) => MouseAction::MoveUp,
+                            (true, true) => MouseAction::MoveDown,
+                        };
+                        let _ = codes.push((KeyType::Mouse(action), Event::Pressed));
+                    }
+                    PresentationEncoder::Zoom => {
+                        let wheel = if clockwise {
+                            MouseAction::ScrollUp
+                        } else {
+                            MouseAction::ScrollDown
+                        };
+                        push_with_control(&mut codes, KeyType::Mouse(wheel));
+                    }
+                }
+            }
+
+            Key::EncoderButton => match key_event.event {
+                Event::Pressed => {
+                    self.held |= bit;
+                    self.encoder_pressed_at = Some(now);
+                }
+                Event::Released => {
+                    self.held &= !bit;
+                    let Some(pressed_at) = self.encoder_pressed_at.take() else {
+                        return codes;
+                    };
+
+                    if now - pressed_at >= TIMER_RESET_PRESS {
+                        self.talk_started = None;
+                    } else {
+                        match layout.encoder {
+                            PresentationEncoder::Pointer => self.vertical = !self.vertical,
+                            PresentationEncoder::Zoom => push_with_control(
+                                &mut codes,
+                                KeyType::Keycode(KeyboardUsage::Keyboard0CloseParens),
+                            ),
+                        }
+                    }
+                }
+            },
+
+            key => {
+                match key_event.event {
+                    Event::Pressed => self.held |= bit,
+                    Event::Released => self.held &= !bit,
+                }
+                if let Some(code) = layout.code(key) {
+                    let _ = codes.push((code, key_event.event));
+                }
+            }
+        }
+
+        codes
+    }
+}
+
+impl Default for Presenter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tap a code while holding Control, e.g. Ctrl+wheel to zoom
+fn push_with_control(codes: &mut PresenterCodes, code: KeyType) {
+    let control = KeyType::Keycode(KeyboardUsage::KeyboardLeftControl);
+    let _ = codes.push((control, Event::Pressed));
+    let _ = codes.push((code, Event::Pressed));
+    let _ = codes.push((code, Event::Released));
+    let _ = codes.push((control, Event::Released));
+}
+
+/// Talk timer color fading from green over yellow to red, blinking red once the talk is
+/// over time, and the time until it changes
+pub fn talk_timer_color(elapsed: Duration, talk_time: Duration) -> (RGB8, Duration) {
+    if elapsed >= talk_time {
+        let overtime = (elapsed - talk_time).as_millis();
+        let blink = TIMER_BLINK.as_millis();
+        let color = match (overtime / blink) % 2 {
+            0 => TIMER_END_COLOR,
+            _ => RGB8::default(),
+        };
+        return (color, Duration::from_millis(blink - overtime % blink));
+    }
+
+    // Fade the red in over the first half and the green out over the second half
+    let half = talk_time.as_millis() / 2;
+    let elapsed = elapsed.as_millis();
+    let color = if elapsed < half {
+        fade(TIMER_START_COLOR, TIMER_MIDDLE_COLOR, elapsed, half)
+    } else {
+        fade(TIMER_MIDDLE_COLOR, TIMER_END_COLOR, elapsed - half, half)
+    };
+    (color, TIMER_UPDATE)
+}
+
+/// Color `position` of `length` of the way from `from` to `to`
+fn fade(from: RGB8, to: RGB8, position: u64, length: u64) -> RGB8 {
+    let channel = |from: u8, to: u8| {
+        let (from, to) = (from as u64, to as u64);
+        (from * (length - position) + to * position)
+            .checked_div(length)
+            .unwrap_or(to) as u8
+    };
+    RGB8 {
+        r: channel(from.r, to.r),
+        g: channel(from.g, to.g),
+        b: channel(from.b, to.b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::Event::{Pressed, Released};
+    use crate::keys::Key::*;
+
+    const CONTROL: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardLeftControl);
+
+    fn layout(encoder: PresentationEncoder) -> PresentationLayout {
+        PresentationLayout {
+            key1: KeyType::Keycode(KeyboardUsage::KeyboardAa),
+            key2: KeyType::Keycode(KeyboardUsage::KeyboardBb),
+            key3: KeyType::Keycode(KeyboardUsage::KeyboardFf),
+            encoder,
+            talk_time: Duration::from_secs(60),
+        }
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn key(key: Key, event: Event) -> KeyEvent {
+        KeyEvent { key, event }
+    }
+
+    fn pointer(action: MouseAction) -> (KeyType, Event) {
+        (KeyType::Mouse(action), Pressed)
+    }
+
+    fn with_control(code: KeyType) -> [(KeyType, Event); 4] {
+        [
+            (CONTROL, Pressed),
+            (code, Pressed),
+            (code, Released),
+            (CONTROL, Released),
+        ]
+    }
+
+    #[test]
+    fn first_press_starts_the_talk_timer() {
+        let layout = layout(PresentationEncoder::Pointer);
+        let mut presenter = Presenter::new();
+        assert_eq!(presenter.talk_started(), None);
+
+        let codes = presenter.process(&layout, key(Key2, Pressed), at(100));
+        assert!(codes == [(layout.key2, Pressed)]);
+        assert!(presenter.holds(Key2));
+        assert_eq!(presenter.talk_started(), Some(at(100)));
+
+        // Later presses keep the start of the talk
+        let codes = presenter.process(&layout, key(Key2, Released), at(200));
+        assert!(codes == [(layout.key2, Released)]);
+        assert!(!presenter.holds(Key2));
+        presenter.process(&layout, key(EncoderRight, Pressed), at(300));
+        assert_eq!(presenter.talk_started(), Some(at(100)));
+    }
+
+    #[test]
+    fn long_encoder_press_resets_the_talk_timer() {
+        let layout = layout(PresentationEncoder::Pointer);
+        let mut presenter = Presenter::new();
+        presenter.process(&layout, key(Key1, Pressed), at(0));
+        presenter.process(&layout, key(Key1, Released), at(100));
+
+        presenter.process(&layout, key(EncoderButton, Pressed), at(1000));
+        assert!(presenter.holds(EncoderButton));
+        let codes = presenter.process(&layout, key(EncoderButton, Released), at(2000));
+        assert!(codes.is_empty());
+        assert!(!presenter.holds(EncoderButton));
+        assert_eq!(presenter.talk_started(), None);
+
+        // The pointer direction is kept
+        let codes = presenter.process(&layout, key(EncoderLeft, Pressed), at(3000));
+        assert!(codes == [pointer(MouseAction::MoveLeft)]);
+        // And the next press starts the timer again
+        assert_eq!(presenter.talk_started(), Some(at(3000)));
+    }
+
+    #[test]
+    fn short_encoder_press_switches_the_pointer_direction() {
+        let layout = layout(PresentationEncoder::Pointer);
+        let mut presenter = Presenter::new();
+
+        let codes = presenter.process(&layout, key(EncoderLeft, Pressed), at(0));
+        assert!(codes == [pointer(MouseAction::MoveLeft)]);
+        let codes = presenter.process(&layout, key(EncoderRight, Pressed), at(10));
+        assert!(codes == [pointer(MouseAction::MoveRight)]);
+
+        presenter.process(&layout, key(EncoderButton, Pressed), at(100));
+        let codes = presenter.process(&layout, key(EncoderButton, Released), at(300));
+        assert!(codes.is_empty());
+        assert_eq!(presenter.talk_started(), Some(at(0)));
+
+        let codes = presenter.process(&layout, key(EncoderLeft, Pressed), at(400));
+        assert!(codes == [pointer(MouseAction::MoveUp)]);
+        let codes = presenter.process(&layout, key(EncoderRight, Pressed), at(410));
+        assert!(codes == [pointer(MouseAction::MoveDown)]);
+
+        // Switches back with the next short press
+        presenter.process(&layout, key(EncoderButton, Pressed), at(500));
+        presenter.process(&layout, key(EncoderButton, Released), at(600));
+        let codes = presenter.process(&layout, key(EncoderRight, Pressed), at(700));
+        assert!(codes == [pointer(MouseAction::MoveRight)]);
+    }
+
+    #[test]
+    fn zoom_encoder_sends_control_sequences() {
+        let layout = layout(PresentationEncoder::Zoom);
+        let mut presenter = Presenter::new();
+
+        let codes = presenter.process(&layout, key(EncoderRight, Pressed), at(0));
+        assert!(codes == with_control(KeyType::Mouse(MouseAction::ScrollUp)));
+        let codes = presenter.process(&layout, key(EncoderLeft, Pressed), at(10));
+        assert!(codes == with_control(KeyType::Mouse(MouseAction::ScrollDown)));
+
+        // A short press resets the zoom with Ctrl+0
+        presenter.process(&layout, key(EncoderButton, Pressed), at(100));
+        let codes = presenter.process(&layout, key(EncoderButton, Released), at(200));
+        let reset = KeyType::Keycode(KeyboardUsage::Keyboard0CloseParens);
+        assert!(codes == with_control(reset));
+    }
+
+    #[test]
+    fn encoder_release_without_press_is_ignored() {
+        let layout = layout(PresentationEncoder::Zoom);
+        let mut presenter = Presenter::new();
+        let codes = presenter.process(&layout, key(EncoderButton, Released), at(0));
+        assert!(codes.is_empty());
+        assert_eq!(presenter.talk_started(), None);
+    }
+
+    #[test]
+    fn talk_timer_fades_from_green_over_yellow_to_red() {
+        let talk_time = Duration::from_secs(60);
+        let color = |secs| talk_timer_color(Duration::from_secs(secs), talk_time);
+
+        assert_eq!(color(0), (TIMER_START_COLOR, TIMER_UPDATE));
+        assert_eq!(color(15), (RGB8 { r: 5, g: 10, b: 0 }, TIMER_UPDATE));
+        assert_eq!(color(30), (TIMER_MIDDLE_COLOR, TIMER_UPDATE));
+        assert_eq!(color(45), (RGB8 { r: 10, g: 5, b: 0 }, TIMER_UPDATE));
+        assert_eq!(color(59).0, RGB8 { r: 10, g: 0, b: 0 });
+    }
+
+    #[test]
+    fn talk_timer_blinks_red_over_time() {
+        let talk_time = Duration::from_secs(60);
+        let color = |ms| talk_timer_color(Duration::from_millis(ms), talk_time);
+
+        assert_eq!(color(60_000), (TIMER_END_COLOR, TIMER_BLINK));
+        assert_eq!(color(60_200), (TIMER_END_COLOR, Duration::from_millis(300)));
+        assert_eq!(color(60_500), (RGB8::default(), TIMER_BLINK));
+        assert_eq!(color(61_000), (TIMER_END_COLOR, TIMER_BLINK));
+    }
+
+    #[test]
+    fn fade_handles_the_ends_and_empty_lengths() {
+        let from = RGB8 { r: 0, g: 20, b: 8 };
+        let to = RGB8 { r: 20, g: 0, b: 8 };
+        assert_eq!(fade(from, to, 0, 10), from);
+        assert_eq!(fade(from, to, 5, 10), RGB8 { r: 10, g: 10, b: 8 });
+        assert_eq!(fade(from, to, 10, 10), to);
+        // A talk time too short to fade shows the end color right away
+        assert_eq!(fade(from, to, 0, 0), to);
+        assert_eq!(
+            talk_timer_color(Duration::from_millis(0), Duration::from_millis(1)).0,
+            TIMER_END_COLOR
+        );
+    }
+}
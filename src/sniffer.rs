@@ -0,0 +1,148 @@
+use crate::pio_uart::{PioUartRx, RxError};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_time::{Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, Ordering};
+
+/// First byte of every record, the decoder synchronizes on it
+const RECORD_SYNC: u8 = 0xA5;
+
+/// Sync byte, flags, 32 bit timestamp and length
+const RECORD_HEADER_SIZE: usize = 7;
+
+/// Most data bytes of a record
+const MAX_RECORD_DATA: usize = 64;
+
+/// Flags of a record
+const FLAG_TX: u8 = 1 << 0;
+const FLAG_OVERRUN: u8 = 1 << 1;
+const ERROR_SHIFT: u8 = 2;
+
+/// Time between checks of the receivers, the timestamps are back-dated from the check to
+/// the first byte of a record
+const SNIFF_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+static SNIFFER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether both header pins receive instead of bridging the header, see `sniff`
+pub fn enabled() -> bool {
+    SNIFFER_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Switch the sniffer on or off, the header is reconfigured right away
+pub fn toggle() {
+    let enabled = !SNIFFER_ENABLED.fetch_xor(true, Ordering::Relaxed);
+    defmt::info!("[UART]: Sniffer {}", if enabled { "on" } else { "off" });
+    crate::uart::HEADER_CHANGED.signal(());
+}
+
+/// Stream what both sides of a foreign UART link send to the host
+///
+/// RX (GP1) and TX (GP0) of the header are connected to the two lines of the link, which
+/// run with the line coding of the host. The received bytes are sent as records of the
+/// sync byte `0xA5`, a flags byte, the start of the first byte in µs since boot as a
+/// wrapping little endian u32, the number of data bytes and up to 64 data bytes. The
+/// flags hold the line in bit 0 (1 = TX), whether bytes were lost before the record in
+/// bit 1 and the error after the data in bits 2-3 (1 framing, 2 parity, 3 break).
+///
+/// `tools/sniff.py` decodes them into a hexdump or a pcap file.
+pub async fn sniff<const RX: usize, const TX: usize, const N: usize>(
+    rx: &mut PioUartRx<'_, RX>,
+    tx: &mut PioUartRx<'_, TX>,
+    usb_pipe: &Pipe<NoopRawMutex, N>,
+) -> ! {
+    let mut rx_lost = false;
+    let mut tx_lost = false;
+    loop {
+        loop {
+            let rx_record = Record::read(rx, 0, &mut rx_lost);
+            let tx_record = Record::read(tx, FLAG_TX, &mut tx_lost);
+            // The older record first, so the stream follows the order on the link
+            match (rx_record, tx_record) {
+                (None, None) => break,
+                (Some(rx_record), Some(tx_record)) if tx_record.start < rx_record.start => {
+                    tx_record.send(&mut tx_lost, usb_pipe);
+                    rx_record.send(&mut rx_lost, usb_pipe);
+                }
+                (rx_record, tx_record) => {
+                    if let Some(rx_record) = rx_record {
+                        rx_record.send(&mut rx_lost, usb_pipe);
+                    }
+                    if let Some(tx_record) = tx_record {
+                        tx_record.send(&mut tx_lost, usb_pipe);
+                    }
+                }
+            }
+        }
+        Timer::after(SNIFF_POLL_INTERVAL).await;
+    }
+}
+
+/// Record of the bytes received on a line
+struct Record {
+    /// Start of the first byte
+    start: Instant,
+    buf: [u8; RECORD_HEADER_SIZE + MAX_RECORD_DATA],
+    len: usize,
+}
+
+impl Record {
+    /// Record of the bytes received on a line, if there were any
+    fn read<const SM: usize>(
+        uart_rx: &mut PioUartRx<'_, SM>,
+        line: u8,
+        lost: &mut bool,
+    ) -> Option<Self> {
+        *lost |= uart_rx.overruns() > 0;
+
+        let mut buf = [0; RECORD_HEADER_SIZE + MAX_RECORD_DATA];
+        let (n, error) = uart_rx.try_read(&mut buf[RECORD_HEADER_SIZE..]);
+        if n == 0 && error.is_none() {
+            return None;
+        }
+
+        let error_flags = match error {
+            None => 0,
+            Some(RxError::Framing) => 1,
+            Some(RxError::Parity) => 2,
+            Some(RxError::Break) => 3,
+        };
+        let overrun = if *lost { FLAG_OVERRUN } else { 0 };
+        // The bytes left in the ring buffer and the one with the error came after the
+        // first byte, which started that many frames before the last one ended
+        let frames = (n + error.is_some() as usize) as u32 + uart_rx.pending();
+        let start = Instant::now()
+            .checked_sub(uart_rx.frame_time(frames))
+            .unwrap_or(Instant::MIN);
+        buf[0] = RECORD_SYNC;
+        buf[1] = line | overrun | (error_flags << ERROR_SHIFT);
+        buf[2..6].copy_from_slice(&(start.as_micros() as u32).to_le_bytes());
+        buf[6] = n as u8;
+
+        Some(Self {
+            start,
+            buf,
+            len: RECORD_HEADER_SIZE + n,
+        })
+    }
+
+    /// Send the record to the host
+    ///
+    /// Records that don't fit into the USB pipe are dropped as a whole, so the stream
+    /// stays in sync, and the next record of the line has the overrun flag.
+    fn send<const N: usize>(&self, lost: &mut bool, usb_pipe: &Pipe<NoopRawMutex, N>) {
+        let mut record = &self.buf[..self.len];
+        if usb_pipe.free_capacity() < record.len() {
+            *lost = true;
+            return;
+        }
+        // The pipe only takes the bytes up to its end at once
+        while let Ok(written) = usb_pipe.try_write(record) {
+            record = &record[written..];
+            if record.is_empty() {
+                break;
+            }
+        }
+        *lost = false;
+    }
+}
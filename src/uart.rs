@@ -1,127 +1,587 @@
-use embassy_futures::join::join;
-use embassy_rp::peripherals::USB;
+use crate::automation::{self, AUTOMATION_INPUT, AUTOMATION_OUTPUT};
+use crate::capture;
+use crate::cdc_acm::{
+    self, BREAK_UNTIL_STOPPED, CdcNotifier, CdcReceiver, CdcSender, LineCoding, SERIAL_STATE_BREAK,
+    SERIAL_STATE_FRAMING, SERIAL_STATE_OVERRUN, SERIAL_STATE_PARITY, SERIAL_STATE_RX_CARRIER,
+    SERIAL_STATE_TX_CARRIER,
+};
+use crate::pio_uart::{FORMAT_8N1, PioUartRx, PioUartRxProgram, PioUartTx, RxError, RxRing};
+use crate::sniffer;
+use crate::via::SharedFlash;
+use crate::{DeviceMode, UartResources};
+use core::cell::{Cell, RefCell};
+use core::pin::pin;
+use embassy_futures::join::{join, join3};
+use embassy_futures::select::{Either, Either4, select, select3, select4};
+use embassy_rp::gpio::{Flex, Input, Level, Output, Pin, Pull};
 use embassy_rp::pio::{Instance as PioInstance, Pio};
-use embassy_rp::pio_programs::uart::{PioUartRx, PioUartRxProgram, PioUartTx, PioUartTxProgram};
-use embassy_rp::usb::Driver;
-use embassy_rp::usb::Instance as UsbInstance;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
 use embassy_sync::pipe::{Pipe, Reader, Writer};
-use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::driver::EndpointError;
+use portable_atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use static_cell::StaticCell;
 
-use crate::UartResources;
+/// How a control line of the host drives its GPIO
+struct ControlLineOutput {
+    /// Like on USB-serial adapters the GPIO is low while the line is asserted,
+    /// inverted it is high instead
+    invert: bool,
+    /// Only pull the GPIO low and leave it floating otherwise, for targets with their
+    /// own pull-ups or a different I/O voltage
+    open_drain: bool,
+}
+
+/// DTR on GP6, e.g. to the boot-mode pin of the target, like GPIO0 of an ESP32
+const DTR_OUTPUT: ControlLineOutput = ControlLineOutput {
+    invert: false,
+    open_drain: false,
+};
+
+/// RTS on GP7, e.g. to the reset pin of the target, like EN of an ESP32 or NRST of an STM32
+const RTS_OUTPUT: ControlLineOutput = ControlLineOutput {
+    invert: false,
+    open_drain: true,
+};
+
+/// GPIO driven by a control line of the host
+struct ControlLine<'d> {
+    pin: Flex<'d>,
+    output: ControlLineOutput,
+}
+
+impl<'d> ControlLine<'d> {
+    fn new(pin: impl Pin + 'd, output: ControlLineOutput) -> Self {
+        let mut line = Self {
+            pin: Flex::new(pin),
+            output,
+        };
+        line.set(false);
+        line
+    }
+
+    fn set(&mut self, asserted: bool) {
+        let high = asserted == self.output.invert;
+        if self.output.open_drain {
+            if high {
+                self.pin.set_as_input();
+            } else {
+                self.pin.set_low();
+                self.pin.set_as_output();
+            }
+        } else {
+            self.pin.set_level(Level::from(high));
+            self.pin.set_as_output();
+        }
+    }
+}
+
+/// RTS/CTS hardware flow control with the target on GP8 and GP9
+///
+/// RTS tells the target to stop sending while the data for the host piles up, and
+/// bytes are only sent to the target while it asserts CTS.
+const HARDWARE_FLOW_CONTROL: bool = false;
+
+/// Data from the UART waiting to be sent to the host
+const USB_PIPE_SIZE: usize = 4096;
+
+/// Packet size of the CDC-ACM data endpoints
+const USB_PACKET_SIZE: usize = 64;
+
+/// Fill level of the USB pipe at which RTS tells the target to stop sending
+const RTS_STOP_LEVEL: usize = USB_PIPE_SIZE * 3 / 4;
+
+/// Fill level of the USB pipe at which RTS lets the target send again
+const RTS_RESUME_LEVEL: usize = USB_PIPE_SIZE / 4;
+
+/// RTS output of the hardware flow control, low while the target may send
+struct FlowControlRts<'d> {
+    pin: RefCell<Output<'d>>,
+}
+
+impl<'d> FlowControlRts<'d> {
+    fn new(pin: impl Pin + 'd) -> Self {
+        Self {
+            pin: RefCell::new(Output::new(pin, Level::Low)),
+        }
+    }
+
+    /// Stop or resume the target for the fill level of the USB pipe
+    fn update(&self, level: usize) {
+        let mut pin = self.pin.borrow_mut();
+        if level >= RTS_STOP_LEVEL {
+            pin.set_high();
+        } else if level <= RTS_RESUME_LEVEL {
+            pin.set_low();
+        }
+    }
+}
+
+/// Signal to notify when the mode changed or the sniffer was switched, so the header is
+/// reconfigured
+pub static HEADER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signal to start the auto-baud detection, from the host or a key combo
+pub static AUTO_BAUD_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The auto-baud detection gives up after this time without enough pulses on RX
+const AUTO_BAUD_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoBaudStatus {
+    Measuring,
+    Detected(u32),
+    Failed,
+}
+
+/// Status of the last auto-baud detection and when it was set
+static AUTO_BAUD_STATUS: Mutex<CriticalSectionRawMutex, Cell<Option<(AutoBaudStatus, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Status of the last auto-baud detection and the time since it was set, shown on the LEDs
+pub fn auto_baud_status() -> Option<(AutoBaudStatus, Duration)> {
+    AUTO_BAUD_STATUS
+        .lock(|status| status.get())
+        .map(|(status, since)| (status, since.elapsed()))
+}
+
+fn set_auto_baud_status(status: AutoBaudStatus) {
+    AUTO_BAUD_STATUS.lock(|cell| cell.set(Some((status, Instant::now()))));
+    crate::led::INDICATORS_CHANGED.signal(());
+}
+
+/// Line errors of the header not reported to the host yet, see `cdc_acm::SERIAL_STATE_*`
+static SERIAL_ERRORS: AtomicU16 = AtomicU16::new(0);
+
+/// Signal to notify when a line error was added to `SERIAL_ERRORS`
+static SERIAL_ERRORS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn report_serial_error(error: u16) {
+    SERIAL_ERRORS.fetch_or(error, Ordering::Relaxed);
+    SERIAL_ERRORS_CHANGED.signal(());
+}
+
+/// Bytes from the UART dropped since boot, because the ring buffer or the USB pipe was full
+static DROPPED_BYTES: AtomicU32 = AtomicU32::new(0);
+
+/// Packets from the host dropped since boot, because they didn't fit into the buffer
+static DROPPED_PACKETS: AtomicU32 = AtomicU32::new(0);
+
+fn report_overrun(bytes: u32) {
+    DROPPED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    report_serial_error(SERIAL_STATE_OVERRUN);
+}
+
+/// A packet the host doesn't take within this time means it stopped reading
+const HOST_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Whether the host reads the serial port, what the target sends is captured otherwise if
+/// the capture is switched on with VIA
+///
+/// Only a program with the port open reads it, whatever it does with DTR, e.g. `picocom
+/// --lower-dtr`.
+static HOST_READING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the UART header takes automation commands instead of being bridged to USB,
+/// which it does in keyboard mode
+async fn header_automation() -> bool {
+    *crate::CURRENT_MODE.lock().await == DeviceMode::Keyboard
+}
+
+/// Line coding the header runs with, the automation commands have a fixed one
+async fn header_line_coding() -> LineCoding {
+    if header_automation().await {
+        LineCoding {
+            data_rate: automation::BAUD_RATE,
+            format: FORMAT_8N1,
+        }
+    } else {
+        cdc_acm::line_coding()
+    }
+}
 
 pub struct Disconnected {}
 
 impl From<EndpointError> for Disconnected {
     fn from(val: EndpointError) -> Self {
         match val {
-            EndpointError::BufferOverflow => panic!("USB buffer overflow"),
+            // Packets are never larger than the endpoints, restart the bridge if one is
+            EndpointError::BufferOverflow => {
+                defmt::warn!("[UART]: USB buffer overflow");
+                Disconnected {}
+            }
             EndpointError::Disabled => Disconnected {},
         }
     }
 }
 
+/// Bridges the UART header to the CDC-ACM port, or to `automation_task` in keyboard mode
+///
+/// The header follows the line coding set by the host. Bytes with a framing or parity
+/// error are dropped and reported to the host with a SERIAL_STATE notification.
+/// DTR and RTS of the host drive GP6 and GP7, so tools like esptool and stm32flash can
+/// reset the target into its bootloader. Breaks are sent with SEND_BREAK and received ones
+/// reported to the host like the line errors. A DMA channel moves the received bytes into
+/// a ring buffer, and bytes that don't fit into it or the USB pipe anymore are counted and
+/// reported as overruns, see `HARDWARE_FLOW_CONTROL` to avoid them. The auto-baud detection
+/// replaces the baud rate of the host with the one measured on RX. With the sniffer on,
+/// both pins receive instead, see `sniffer::sniff`. While no terminal has the port open and
+/// the capture is switched on, the received bytes are also recorded to flash, see
+/// `capture::capture_task`.
 #[embassy_executor::task]
-pub async fn uart_task(class: CdcAcmClass<'static, Driver<'static, USB>>, r: UartResources) {
+pub async fn uart_task(
+    mut usb_rx: CdcReceiver,
+    mut usb_tx: CdcSender,
+    mut notifier: CdcNotifier,
+    r: UartResources,
+    flash: &'static SharedFlash,
+) {
     let Pio {
         mut common,
         sm0,
         sm1,
+        sm2,
         ..
     } = Pio::new(r.peripheral, crate::Irqs);
 
-    let tx_prog = PioUartTxProgram::new(&mut common);
-    let mut uart_tx = PioUartTx::new(115200, &mut common, sm0, r.tx, &tx_prog);
+    static RX_RING: StaticCell<RxRing> = StaticCell::new();
+    static SNIFF_RING: StaticCell<RxRing> = StaticCell::new();
+
+    let mut line_coding = header_line_coding().await;
+    let mut uart_tx = PioUartTx::new(
+        line_coding.data_rate,
+        line_coding.format,
+        &mut common,
+        sm0,
+        r.tx,
+    );
+    let rx_program = PioUartRxProgram::new(&mut common);
+    let rx_pin = common.make_pio_pin(r.rx);
+    let mut uart_rx = PioUartRx::new(
+        line_coding.data_rate,
+        line_coding.format,
+        sm1,
+        &rx_pin,
+        &rx_program,
+        r.rx_dma,
+        RX_RING.init_with(RxRing::new),
+    );
+
+    // Receives on the TX pin while the sniffer is on
+    let mut sniff_rx = PioUartRx::new(
+        line_coding.data_rate,
+        line_coding.format,
+        sm2,
+        uart_tx.pin(),
+        &rx_program,
+        r.sniff_dma,
+        SNIFF_RING.init_with(RxRing::new),
+    );
+    sniff_rx.stop();
+    let mut sniffing = false;
 
-    let rx_prog = PioUartRxProgram::new(&mut common);
-    let mut uart_rx = PioUartRx::new(115200, &mut common, sm1, r.rx, &rx_prog);
+    let mut dtr_line = ControlLine::new(r.dtr, DTR_OUTPUT);
+    let mut rts_line = ControlLine::new(r.rts, RTS_OUTPUT);
 
-    let mut usb_pipe: Pipe<NoopRawMutex, 64> = Pipe::new();
-    let (mut usb_pipe_reader, mut usb_pipe_writer) = usb_pipe.split();
+    let rts = HARDWARE_FLOW_CONTROL.then(|| FlowControlRts::new(r.flow_rts));
+    let mut cts = HARDWARE_FLOW_CONTROL.then(|| Input::new(r.flow_cts, Pull::Down));
+
+    // Written and read by different futures, which need its fill level for the flow control
+    let usb_pipe: Pipe<NoopRawMutex, USB_PIPE_SIZE> = Pipe::new();
 
     let mut uart_pipe: Pipe<NoopRawMutex, 64> = Pipe::new();
     let (mut uart_pipe_reader, mut uart_pipe_writer) = uart_pipe.split();
 
-    let (mut usb_tx, mut usb_rx) = class.split();
-
     // Read + write from USB
     let usb_future = async {
         loop {
-            log::debug!("[UART]: Wait for USB connection");
+            defmt::debug!("[UART]: Wait for USB connection");
             usb_rx.wait_connection().await;
-            log::debug!("[UART]: USB Connected");
-            let _baud = usb_rx.line_coding().data_rate(); // TODO: Make use of this in the PIO program
-            let _ = join(
+            defmt::debug!("[UART]: USB Connected");
+            SERIAL_ERRORS.store(0, Ordering::Relaxed);
+            // The first one to see the disconnect ends the others, which may wait on their own
+            // endpoint or on data that never comes
+            let _ = select3(
                 usb_read(&mut usb_rx, &mut uart_pipe_writer),
-                usb_write(&mut usb_tx, &mut usb_pipe_reader),
+                usb_write(&mut usb_tx, &usb_pipe, rts.as_ref()),
+                usb_notify(&mut notifier),
             )
             .await;
-            log::debug!("[UART]: USB Disconnected");
+            HOST_READING.store(false, Ordering::Relaxed);
+            defmt::debug!("[UART]: USB Disconnected");
         }
     };
 
-    // Read + write from UART
-    let uart_future = join(
-        uart_read(&mut uart_rx, &mut usb_pipe_writer),
-        uart_write(&mut uart_tx, &mut uart_pipe_reader),
-    );
+    // Read + write from UART, restarted with the new line coding whenever it changes
+    let uart_future = async {
+        loop {
+            let header = async {
+                if sniffing {
+                    sniffer::sniff(&mut uart_rx, &mut sniff_rx, &usb_pipe).await
+                } else {
+                    join(
+                        uart_read(&mut uart_rx, &usb_pipe, rts.as_ref()),
+                        uart_write(&mut uart_tx, &mut uart_pipe_reader, cts.as_mut()),
+                    )
+                    .await;
+                }
+            };
+            let event = select4(
+                header,
+                select(cdc_acm::LINE_CODING_CHANGED.wait(), HEADER_CHANGED.wait()),
+                cdc_acm::BREAK_REQUESTED.wait(),
+                select(AUTO_BAUD_REQUESTED.wait(), capture::DUMP_REQUESTED.wait()),
+            )
+            .await;
+
+            match event {
+                Either4::Third(duration) => {
+                    // TX is an input of the sniffer
+                    if !header_automation().await && !sniffing {
+                        send_break(&mut uart_tx, duration).await;
+                    }
+                    continue;
+                }
+                // The automation commands have a fixed baud rate
+                Either4::Fourth(Either::First(())) if !header_automation().await => {
+                    detect_baud_rate(&mut uart_rx).await;
+                }
+                // The dump would garble the records of the sniffer
+                Either4::Fourth(Either::Second(())) if !header_automation().await && !sniffing => {
+                    if capture::dump(flash, &usb_pipe).await.is_err() {
+                        defmt::warn!("[UART]: Capture dump aborted, the host stopped reading");
+                    }
+                }
+                _ => {}
+            }
+
+            let new_line_coding = header_line_coding().await;
+            let new_sniffing = sniffer::enabled() && !header_automation().await;
+            if new_line_coding != line_coding || new_sniffing != sniffing {
+                defmt::info!("[UART]: Line coding {:?}", new_line_coding);
+                let LineCoding { data_rate, format } = new_line_coding;
+                uart_rx.configure(data_rate, format);
+                if new_sniffing {
+                    uart_tx.release();
+                    sniff_rx.configure(data_rate, format);
+                } else {
+                    sniff_rx.stop();
+                    uart_tx.configure(data_rate, format);
+                }
+                line_coding = new_line_coding;
+                sniffing = new_sniffing;
+            }
+        }
+    };
 
-    join(usb_future, uart_future).await;
+    // Control lines, set right away without restarting the UART
+    let control_lines_future = async {
+        loop {
+            cdc_acm::CONTROL_LINES_CHANGED.wait().await;
+            dtr_line.set(cdc_acm::dtr());
+            rts_line.set(cdc_acm::rts());
+        }
+    };
+
+    join3(usb_future, uart_future, control_lines_future).await;
+}
+
+/// Measure the baud rate on RX and make it the one of the line coding
+async fn detect_baud_rate<const SM: usize>(uart_rx: &mut PioUartRx<'_, SM>) {
+    defmt::info!("[UART]: Detecting the baud rate");
+    set_auto_baud_status(AutoBaudStatus::Measuring);
+    match uart_rx.detect_baud_rate(AUTO_BAUD_TIMEOUT).await {
+        Some(baud) => {
+            defmt::info!("[UART]: Detected {} baud", baud);
+            cdc_acm::set_data_rate(baud);
+            set_auto_baud_status(AutoBaudStatus::Detected(baud));
+        }
+        None => {
+            defmt::warn!("[UART]: No baud rate detected, too few pulses on RX");
+            set_auto_baud_status(AutoBaudStatus::Failed);
+        }
+    }
+}
+
+/// Hold TX low for a break of `duration` ms, see `cdc_acm::BREAK_REQUESTED`
+async fn send_break<PIO: PioInstance, const SM: usize>(
+    uart_tx: &mut PioUartTx<'_, PIO, SM>,
+    duration: u16,
+) {
+    match duration {
+        0 => uart_tx.set_break(false).await,
+        BREAK_UNTIL_STOPPED => uart_tx.set_break(true).await,
+        ms => {
+            uart_tx.set_break(true).await;
+            Timer::after_millis(ms as u64).await;
+            uart_tx.set_break(false).await;
+        }
+    }
 }
 
 /// Read from the USB and write it to the UART TX pipe
-async fn usb_read<'d, T: UsbInstance + 'd>(
-    usb_rx: &mut embassy_usb::class::cdc_acm::Receiver<'d, Driver<'d, T>>,
-    uart_pipe_writer: &mut embassy_sync::pipe::Writer<'_, NoopRawMutex, 64>,
+async fn usb_read(
+    usb_rx: &mut CdcReceiver,
+    uart_pipe_writer: &mut Writer<'_, NoopRawMutex, 64>,
 ) -> Result<(), Disconnected> {
-    let mut buf = [0; 64];
+    let mut buf = [0; USB_PACKET_SIZE];
     loop {
-        let n = usb_rx.read_packet(&mut buf).await?;
-        let data = &buf[..n];
-        log::debug!("[UART]: USB IN: {:?}", data);
-        (*uart_pipe_writer).write(data).await;
+        let n = match usb_rx.read_packet(&mut buf).await {
+            Ok(n) => n,
+            Err(EndpointError::BufferOverflow) => {
+                let dropped = DROPPED_PACKETS.fetch_add(1, Ordering::Relaxed) + 1;
+                defmt::warn!("[UART]: USB packet too large, {} dropped so far", dropped);
+                continue;
+            }
+            Err(EndpointError::Disabled) => return Err(Disconnected {}),
+        };
+        // Data from USB would garble the automation replies, and the sniffer doesn't send
+        if header_automation().await || sniffer::enabled() {
+            continue;
+        }
+        let mut data = &buf[..n];
+        defmt::debug!("[UART]: USB IN: {:?}", data);
+        while !data.is_empty() {
+            let written = (*uart_pipe_writer).write(data).await;
+            data = &data[written..];
+        }
     }
 }
 
 /// Read from the USB TX pipe and write it to the USB
-async fn usb_write<'d, T: UsbInstance + 'd>(
-    usb_tx: &mut embassy_usb::class::cdc_acm::Sender<'d, Driver<'d, T>>,
-    usb_pipe_reader: &mut Reader<'_, NoopRawMutex, 64>,
+async fn usb_write(
+    usb_tx: &mut CdcSender,
+    usb_pipe: &Pipe<NoopRawMutex, USB_PIPE_SIZE>,
+    rts: Option<&FlowControlRts<'_>>,
 ) -> Result<(), Disconnected> {
-    let mut buf = [0; 64];
+    let mut buf = [0; USB_PACKET_SIZE];
     loop {
-        let n = (*usb_pipe_reader).read(&mut buf).await;
+        let n = usb_pipe.read(&mut buf).await;
+        if let Some(rts) = rts {
+            rts.update(usb_pipe.len());
+        }
         let data = &buf[..n];
-        log::debug!("[UART]: USB OUT: {:?}", data);
-        usb_tx.write_packet(data).await?;
+        defmt::debug!("[UART]: USB OUT: {:?}", data);
+        write_packet(usb_tx, data).await?;
+        // The host only completes a read with a short packet, end a full one with a
+        // zero-length packet unless more data follows right away
+        if n == USB_PACKET_SIZE && usb_pipe.is_empty() {
+            write_packet(usb_tx, &[]).await?;
+        }
+    }
+}
+
+/// Write a packet to the USB and track whether the host reads them, see `HOST_READING`
+async fn write_packet(usb_tx: &mut CdcSender, data: &[u8]) -> Result<(), Disconnected> {
+    let mut write = pin!(usb_tx.write_packet(data));
+    if let Either::First(result) = select(write.as_mut(), Timer::after(HOST_READ_TIMEOUT)).await {
+        result?;
+    } else {
+        if HOST_READING.swap(false, Ordering::Relaxed) {
+            defmt::debug!("[UART]: Host stopped reading");
+        }
+        write.await?;
+    }
+    HOST_READING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Report the line errors of the UART to the host
+async fn usb_notify(notifier: &mut CdcNotifier) -> Result<(), Disconnected> {
+    loop {
+        SERIAL_ERRORS_CHANGED.wait().await;
+        let errors = SERIAL_ERRORS.swap(0, Ordering::Relaxed);
+        if errors & SERIAL_STATE_OVERRUN != 0 {
+            defmt::warn!(
+                "[UART]: Overrun, {} bytes dropped so far",
+                DROPPED_BYTES.load(Ordering::Relaxed)
+            );
+        }
+        // The bridge is always connected to the header
+        let carrier = SERIAL_STATE_RX_CARRIER | SERIAL_STATE_TX_CARRIER;
+        notifier.serial_state(carrier | errors).await?;
     }
 }
 
 /// Read from the UART and write it to the USB TX pipe
-async fn uart_read<PIO: PioInstance, const SM: usize>(
-    uart_rx: &mut PioUartRx<'_, PIO, SM>,
-    usb_pipe_writer: &mut Writer<'_, NoopRawMutex, 64>,
+async fn uart_read<const SM: usize>(
+    uart_rx: &mut PioUartRx<'_, SM>,
+    usb_pipe: &Pipe<NoopRawMutex, USB_PIPE_SIZE>,
+    rts: Option<&FlowControlRts<'_>>,
 ) -> ! {
+    let mut buf = [0; USB_PACKET_SIZE];
     loop {
-        let byte = uart_rx.read_u8().await;
-        let data = &[byte];
-        log::debug!("[UART]: UART IN: {:?}", data);
-        (*usb_pipe_writer).write(data).await;
+        let (n, error) = uart_rx.read(&mut buf).await;
+        let automation = header_automation().await;
+        let overruns = uart_rx.overruns();
+        if overruns > 0 && !automation {
+            report_overrun(overruns);
+        }
+        if let Some(error) = error {
+            defmt::debug!("[UART]: UART IN: {:?}", error);
+            if !automation {
+                report_serial_error(match error {
+                    RxError::Framing => SERIAL_STATE_FRAMING,
+                    RxError::Parity => SERIAL_STATE_PARITY,
+                    RxError::Break => SERIAL_STATE_BREAK,
+                });
+            }
+        }
+
+        let mut data = &buf[..n];
+        if data.is_empty() {
+            continue;
+        }
+        defmt::debug!("[UART]: UART IN: {:?}", data);
+        if automation {
+            AUTOMATION_INPUT.write_all(data).await;
+            continue;
+        }
+        // No terminal is reading, e.g. when the target reboots overnight
+        if !HOST_READING.load(Ordering::Relaxed) && crate::via::uart_capture() {
+            capture::record(data, overruns > 0);
+        }
+        // Waiting for room in the pipe would overrun the ring buffer instead
+        while let Ok(written) = usb_pipe.try_write(data) {
+            data = &data[written..];
+            if data.is_empty() {
+                break;
+            }
+        }
+        if !data.is_empty() {
+            report_overrun(data.len() as u32);
+        }
+        if let Some(rts) = rts {
+            rts.update(usb_pipe.len());
+        }
     }
 }
 
-/// Read from the UART TX pipe and write it to the UART
+/// Read from the UART TX pipe or the automation replies and write it to the UART
 async fn uart_write<PIO: PioInstance, const SM: usize>(
     uart_tx: &mut PioUartTx<'_, PIO, SM>,
     uart_pipe_reader: &mut Reader<'_, NoopRawMutex, 64>,
+    mut cts: Option<&mut Input<'_>>,
 ) -> ! {
     let mut buf = [0; 64];
+    let mut reply_buf = [0; 64];
     loop {
-        let n = (*uart_pipe_reader).read(&mut buf).await;
-        let data = &buf[..n];
-        log::debug!("[UART]: UART OUT: {:?}", data);
+        let data = match select(
+            (*uart_pipe_reader).read(&mut buf),
+            AUTOMATION_OUTPUT.read(&mut reply_buf),
+        )
+        .await
+        {
+            Either::First(n) => &buf[..n],
+            Either::Second(n) => &reply_buf[..n],
+        };
+        defmt::debug!("[UART]: UART OUT: {:?}", data);
         for &byte in data {
+            if let Some(cts) = cts.as_mut() {
+                cts.wait_for_low().await;
+            }
             uart_tx.write_u8(byte).await;
         }
     }
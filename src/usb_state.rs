@@ -0,0 +1,45 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::Handler;
+use portable_atomic::{AtomicBool, Ordering};
+
+/// Set while the host has suspended the USB bus
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the host suspended the bus, so writes to the endpoints would block until it resumes
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Signal to notify when the host resumed the bus, so the HID task sends the current state
+/// of the keys
+pub static RESUMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Tracks the state of the USB device for the other tasks
+pub struct UsbStateHandler;
+
+impl Handler for UsbStateHandler {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            set_suspended(false);
+        }
+    }
+
+    fn reset(&mut self) {
+        set_suspended(false);
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        log::info!("[USB]: Suspended {}", suspended);
+        set_suspended(suspended);
+    }
+}
+
+fn set_suspended(suspended: bool) {
+    if SUSPENDED.swap(suspended, Ordering::Relaxed) != suspended {
+        crate::led::INDICATORS_CHANGED.signal(());
+        if !suspended {
+            RESUMED.signal(());
+        }
+    }
+}
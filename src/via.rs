@@ -0,0 +1,187 @@
+use crate::FLASH_SIZE;
+use crate::hid::{KEYMAP, NUM_LAYERS};
+use crate::keys::Key;
+use crate::layouts::{Backend, KeyAction, MODE_BACKENDS, PresentationEncoder};
+use crate::macros::MacroSteps;
+use crate::presentation::PRESENTATION_LAYOUT;
+use crate::via_config::{Outcome, STORAGE_SIZE, ViaConfig, ViaSettings};
+use core::cell::RefCell;
+use embassy_futures::select::{Either, select};
+use embassy_rp::flash::{Async, ERASE_SIZE, Flash};
+use embassy_rp::peripherals::{FLASH, USB};
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::hid::{HidReaderWriter, ReadError};
+
+pub use crate::via_config::REPORT_SIZE;
+
+pub type ViaHid = HidReaderWriter<'static, Driver<'static, USB>, REPORT_SIZE, REPORT_SIZE>;
+pub type ViaFlash = Flash<'static, FLASH, Async, FLASH_SIZE>;
+
+/// The flash driver, shared with the UART capture in `capture.rs`
+pub type SharedFlash = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, ViaFlash>;
+
+/// Changes are written to flash once VIA has been quiet for this long, as it sends
+/// the keymap in many small chunks
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// The last flash sector is reserved for the settings in `memory.x`
+const STORAGE_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+
+/// Report descriptor of the raw HID interface VIA looks for
+#[rustfmt::skip]
+pub const VIA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,       // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,             // Usage (0x61)
+    0xA1, 0x01,             // Collection (Application)
+    0x09, 0x62,             //   Usage (0x62)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,       //   Logical Maximum (255)
+    0x75, 0x08,             //   Report Size (8)
+    0x95, 0x20,             //   Report Count (32)
+    0x81, 0x02,             //   Input (Data, Variable, Absolute)
+    0x09, 0x63,             //   Usage (0x63)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,       //   Logical Maximum (255)
+    0x75, 0x08,             //   Report Size (8)
+    0x95, 0x20,             //   Report Count (32)
+    0x91, 0x02,             //   Output (Data, Variable, Absolute)
+    0xC0,                   // End Collection
+];
+
+/// Signaled when the keymap was loaded from flash or changed with VIA
+pub static KEYMAP_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Keymap, macros and settings edited with VIA, shared with `hid_task`
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<ViaConfig<NUM_LAYERS>>> =
+    Mutex::new(RefCell::new(ViaConfig::new(
+        &KEYMAP,
+        ViaSettings {
+            backends: MODE_BACKENDS,
+            presentation_encoder: PRESENTATION_LAYOUT.encoder,
+            uart_capture: false,
+        },
+    )));
+
+/// Backend of selector position `position`, see `DeviceMode::index`
+pub fn backend(position: usize) -> Backend {
+    CONFIG.lock(|config| config.borrow().settings().backends[position])
+}
+
+/// Encoder control of the presentation backend
+pub fn presentation_encoder() -> PresentationEncoder {
+    CONFIG.lock(|config| config.borrow().settings().presentation_encoder)
+}
+
+/// Whether the UART header is recorded to flash, see `capture::record`
+pub fn uart_capture() -> bool {
+    CONFIG.lock(|config| config.borrow().settings().uart_capture)
+}
+
+/// Action set with VIA for `key` on `layer`, `None` keeps the action of the firmware keymap
+pub fn action(layer: usize, key: Key) -> Option<KeyAction> {
+    CONFIG.lock(|config| config.borrow().action(layer, key))
+}
+
+/// Steps of macro `index`, none if the macro buffer doesn't have that many macros
+pub fn macro_steps(index: u8) -> MacroSteps {
+    CONFIG.lock(|config| config.borrow().macro_steps(index))
+}
+
+/// Answers the VIA commands on the raw HID interface and keeps the keymap in flash
+///
+/// The keymap is loaded from flash on start, falling back to the firmware keymap.
+/// VIA sees a single row with the three keys and the encoder button, the encoder
+/// steps are edited as encoder 0. Actions without a keycode show up as the custom
+/// "Default" keycode, which keeps the action of the firmware keymap.
+#[embassy_executor::task]
+pub async fn via_task(hid: ViaHid, flash: &'static SharedFlash) -> ! {
+    let mut storage = [0; STORAGE_SIZE];
+    if let Err(e) = flash
+        .lock()
+        .await
+        .blocking_read(STORAGE_OFFSET, &mut storage)
+    {
+        log::error!("[VIA]: Failed to read the keymap from flash: {:?}", e);
+    }
+    CONFIG.lock(|config| {
+        let mut config = config.borrow_mut();
+        if !config.load(&storage) {
+            log::info!("[VIA]: No keymap for this firmware in flash, using the firmware keymap");
+            config.reset_keymap();
+        }
+    });
+    KEYMAP_CHANGED.signal(());
+    // The backends may have changed, and with them whether the LEDs show the talk timer
+    crate::MODE_CHANGED.signal(());
+
+    let (mut reader, mut writer) = hid.split();
+    let mut buf = [0; REPORT_SIZE];
+    let mut unsaved = false;
+
+    loop {
+        let read = if unsaved {
+            match select(reader.read(&mut buf), Timer::after(SAVE_DELAY)).await {
+                Either::First(read) => read,
+                Either::Second(()) => {
+                    save(&mut *flash.lock().await);
+                    unsaved = false;
+                    continue;
+                }
+            }
+        } else {
+            reader.read(&mut buf).await
+        };
+
+        match read {
+            Ok(_) => {}
+            Err(ReadError::Disabled) => {
+                reader.ready().await;
+                continue;
+            }
+            Err(e) => {
+                log::warn!("[VIA]: Failed to read command: {:?}", e);
+                continue;
+            }
+        }
+
+        let outcome =
+            CONFIG.lock(|config| config.borrow_mut().handle_command(&mut buf, Instant::now()));
+
+        if let Err(e) = writer.write(&buf).await {
+            log::error!("[VIA]: Failed to send reply: {:?}", e);
+        }
+
+        match outcome {
+            Outcome::Unchanged => {}
+            Outcome::Changed => {
+                KEYMAP_CHANGED.signal(());
+                crate::MODE_CHANGED.signal(());
+                unsaved = true;
+            }
+            Outcome::Bootloader => {
+                if unsaved {
+                    save(&mut *flash.lock().await);
+                }
+                embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+            }
+        }
+    }
+}
+
+/// Write the keymap and macros to the reserved flash sector
+fn save(flash: &mut ViaFlash) {
+    let mut storage = [0xFF; STORAGE_SIZE];
+    CONFIG.lock(|config| config.borrow().store(&mut storage));
+
+    let result = flash
+        .blocking_erase(STORAGE_OFFSET, STORAGE_OFFSET + ERASE_SIZE as u32)
+        .and_then(|()| flash.blocking_write(STORAGE_OFFSET, &storage));
+    match result {
+        Ok(()) => log::info!("[VIA]: Keymap saved"),
+        Err(e) => log::error!("[VIA]: Failed to save the keymap: {:?}", e),
+    }
+}
@@ -0,0 +1,734 @@
+use crate::keycodes::{self, KB_DEFAULT};
+use crate::keys::{Key, NUM_KEYS};
+use crate::layouts::{Backend, KeyAction, KeyLayout, PresentationEncoder};
+use crate::macros::{MACRO_BUFFER_SIZE, MacroSteps};
+use embassy_time::Instant;
+
+/// Size of the raw HID reports in both directions
+pub const REPORT_SIZE: usize = 32;
+
+/// VIA protocol version implemented, the one of QMK 0.19 and later
+const PROTOCOL_VERSION: u16 = 0x000C;
+
+// Command IDs, the first byte of every report
+const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const ID_CUSTOM_SET_VALUE: u8 = 0x07;
+const ID_CUSTOM_GET_VALUE: u8 = 0x08;
+const ID_CUSTOM_SAVE: u8 = 0x09;
+const ID_EEPROM_RESET: u8 = 0x0A;
+const ID_BOOTLOADER_JUMP: u8 = 0x0B;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const ID_DYNAMIC_KEYMAP_GET_ENCODER: u8 = 0x14;
+const ID_DYNAMIC_KEYMAP_SET_ENCODER: u8 = 0x15;
+/// Reply to commands that aren't supported
+const ID_UNHANDLED: u8 = 0xFF;
+
+/// Channel of the custom values of the menus in `via/oskar.json`
+const ID_CUSTOM_CHANNEL: u8 = 0x00;
+
+// Values of the custom channel, the backends of the selector positions, the encoder of
+// the presentation backend and the switch of the UART capture
+const ID_BACKEND_KEYBOARD: u8 = 0x01;
+const ID_BACKEND_PICOPROG: u8 = 0x02;
+const ID_BACKEND_UNIVERSAL: u8 = 0x03;
+const ID_PRESENTATION_ENCODER: u8 = 0x04;
+const ID_UART_CAPTURE: u8 = 0x05;
+
+/// All values of the custom channel, in the order they are saved
+///
+/// New values go at the end, older settings leave them at their default.
+const CUSTOM_VALUES: [u8; 5] = [
+    ID_BACKEND_KEYBOARD,
+    ID_BACKEND_PICOPROG,
+    ID_BACKEND_UNIVERSAL,
+    ID_PRESENTATION_ENCODER,
+    ID_UART_CAPTURE,
+];
+
+/// Backends in the order of the options of the backend menus in `via/oskar.json`
+const BACKEND_OPTIONS: [Backend; 4] = [
+    Backend::Hid,
+    Backend::Midi,
+    Backend::Gamepad,
+    Backend::Presentation,
+];
+
+/// Presentation encoder controls in the order of the options of their menu
+const PRESENTATION_ENCODER_OPTIONS: [PresentationEncoder; 2] =
+    [PresentationEncoder::Pointer, PresentationEncoder::Zoom];
+
+// Values of ID_GET_KEYBOARD_VALUE
+const ID_UPTIME: u8 = 0x01;
+const ID_LAYOUT_OPTIONS: u8 = 0x02;
+const ID_SWITCH_MATRIX_STATE: u8 = 0x03;
+const ID_FIRMWARE_VERSION: u8 = 0x04;
+
+/// Bytes of a buffer transfer after the command, offset and size
+const MAX_BUFFER_CHUNK: usize = REPORT_SIZE - 4;
+
+/// Keys of the single matrix row, in the order of the columns in `via/oskar.json`
+const MATRIX: [Key; 4] = [Key::Key1, Key::Key2, Key::Key3, Key::EncoderButton];
+
+/// Steps of encoder 0, counter-clockwise and clockwise
+const ENCODER: [Key; 2] = [Key::EncoderLeft, Key::EncoderRight];
+
+/// Number of macros in the macro buffer, bound to `KeyType::Macro(0)` and up
+const MACRO_COUNT: u8 = 8;
+
+const STORAGE_MAGIC: [u8; 4] = *b"OSKV";
+const STORAGE_VERSION: u8 = 1;
+
+/// Magic, version, layer count, key count, a reserved byte and the little-endian
+/// `keymap_hash` of the firmware the keymap was saved with
+const STORAGE_HEADER_SIZE: usize = 12;
+
+/// Selected option of each of the `CUSTOM_VALUES`, after the macro buffer
+const STORAGE_SETTINGS_SIZE: usize = CUSTOM_VALUES.len();
+
+/// Whole flash pages holding the header, the keycodes, the macro buffer and the settings
+pub const STORAGE_SIZE: usize = 1024;
+
+/// Settings of the custom menus in `via/oskar.json`
+#[derive(Clone, Copy, PartialEq)]
+pub struct ViaSettings {
+    /// Backend of each selector position, indexed by `DeviceMode::index`
+    pub backends: [Backend; 3],
+    /// Encoder control of the presentation backend
+    pub presentation_encoder: PresentationEncoder,
+    /// Whether the UART header is recorded to flash while the host doesn't read it
+    pub uart_capture: bool,
+}
+
+/// Result of a VIA command besides its reply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Unchanged,
+    /// The keymap or the macros changed and have to be saved
+    Changed,
+    /// Reboot into the USB bootloader once the reply is sent
+    Bootloader,
+}
+
+/// Keymap, macros and settings edited with VIA for a firmware keymap of `LAYERS` layers
+pub struct ViaConfig<const LAYERS: usize> {
+    keymap: &'static [KeyLayout; LAYERS],
+    /// Keycode of every key per layer, indexed by `Key::index`
+    keycodes: [[u16; NUM_KEYS]; LAYERS],
+    /// Null-terminated macros, see `MacroSteps`
+    macros: [u8; MACRO_BUFFER_SIZE],
+    settings: ViaSettings,
+    /// Settings of the firmware, restored by an EEPROM reset
+    default_settings: ViaSettings,
+}
+
+impl<const LAYERS: usize> ViaConfig<LAYERS> {
+    /// Little-endian keycodes of every key, ordered by layer and key index
+    const STORAGE_KEYCODES_SIZE: usize = LAYERS * NUM_KEYS * 2;
+    const STORAGE_FITS: () = assert!(
+        STORAGE_HEADER_SIZE
+            + Self::STORAGE_KEYCODES_SIZE
+            + MACRO_BUFFER_SIZE
+            + STORAGE_SETTINGS_SIZE
+            <= STORAGE_SIZE
+    );
+
+    /// Configuration that keeps every action of `keymap`, without macros
+    pub const fn new(keymap: &'static [KeyLayout; LAYERS], settings: ViaSettings) -> Self {
+        let () = Self::STORAGE_FITS;
+        Self {
+            keymap,
+            keycodes: [[KB_DEFAULT; NUM_KEYS]; LAYERS],
+            macros: [0; MACRO_BUFFER_SIZE],
+            settings,
+            default_settings: settings,
+        }
+    }
+
+    pub fn settings(&self) -> ViaSettings {
+        self.settings
+    }
+
+    /// Action set for `key` on `layer`, `None` keeps the action of the firmware keymap
+    pub fn action(&self, layer: usize, key: Key) -> Option<KeyAction> {
+        keycodes::action(self.keycodes[layer][key.index()])
+    }
+
+    /// Steps of macro `index`, none if the macro buffer doesn't have that many macros
+    pub fn macro_steps(&self, index: u8) -> MacroSteps {
+        MacroSteps::new(&self.macros, index)
+    }
+
+    /// Set the keycodes to the ones of the firmware keymap
+    pub fn reset_keymap(&mut self) {
+        for (layer, layout) in self.keymap.iter().enumerate() {
+            for key in Key::ALL {
+                self.keycodes[layer][key.index()] = keycodes::keycode(layout.action(key));
+            }
+        }
+    }
+
+    fn reset_macros(&mut self) {
+        self.macros.fill(0);
+    }
+
+    fn reset_settings(&mut self) {
+        self.settings = self.default_settings;
+    }
+
+    /// Option selected for a custom value, `None` for unknown values
+    fn custom_value(&self, id: u8) -> Option<u8> {
+        match id {
+            ID_BACKEND_KEYBOARD..=ID_BACKEND_UNIVERSAL => {
+                let backend = self.settings.backends[(id - ID_BACKEND_KEYBOARD) as usize];
+                BACKEND_OPTIONS
+                    .iter()
+                    .position(|option| *option == backend)
+                    .map(|option| option as u8)
+            }
+            ID_PRESENTATION_ENCODER => PRESENTATION_ENCODER_OPTIONS
+                .iter()
+                .position(|option| *option == self.settings.presentation_encoder)
+                .map(|option| option as u8),
+            ID_UART_CAPTURE => Some(self.settings.uart_capture as u8),
+            _ => None,
+        }
+    }
+
+    /// Select an option of a custom value, `false` for unknown values and options
+    fn set_custom_value(&mut self, id: u8, option: u8) -> bool {
+        match (id, option as usize) {
+            (ID_BACKEND_KEYBOARD..=ID_BACKEND_UNIVERSAL, option)
+                if option < BACKEND_OPTIONS.len() =>
+            {
+                self.settings.backends[(id - ID_BACKEND_KEYBOARD) as usize] =
+                    BACKEND_OPTIONS[option];
+                true
+            }
+            (ID_PRESENTATION_ENCODER, option) if option < PRESENTATION_ENCODER_OPTIONS.len() => {
+                self.settings.presentation_encoder = PRESENTATION_ENCODER_OPTIONS[option];
+                true
+            }
+            (ID_UART_CAPTURE, option @ (0 | 1)) => {
+                self.settings.uart_capture = option == 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keycode of the matrix key at a keymap buffer position, or `None` past the end
+    fn matrix_keycode(&mut self, position: usize) -> Option<&mut u16> {
+        let layer = position / MATRIX.len();
+        let key = MATRIX[position % MATRIX.len()];
+        self.keycodes
+            .get_mut(layer)
+            .map(|keys| &mut keys[key.index()])
+    }
+
+    /// Byte of the keymap buffer, the big-endian keycodes of the matrix by layer, row and column
+    fn keymap_byte(&mut self, offset: usize) -> Option<u8> {
+        let keycode = self.matrix_keycode(offset / 2)?;
+        Some(keycode.to_be_bytes()[offset % 2])
+    }
+
+    fn set_keymap_byte(&mut self, offset: usize, byte: u8) {
+        if let Some(keycode) = self.matrix_keycode(offset / 2) {
+            let mut bytes = keycode.to_be_bytes();
+            bytes[offset % 2] = byte;
+            *keycode = u16::from_be_bytes(bytes);
+        }
+    }
+
+    /// FNV-1a hash of the keycodes of the firmware keymap
+    ///
+    /// Saved keycodes replace the actions of the firmware keymap, so after an update with a
+    /// changed keymap they would override the new actions with ones picked for the old
+    /// keymap. The saved keymap is only used if this hash still matches.
+    fn keymap_hash(&self) -> u32 {
+        let mut hash: u32 = 0x811C_9DC5;
+        for layout in self.keymap.iter() {
+            for key in Key::ALL {
+                for byte in keycodes::keycode(layout.action(key)).to_le_bytes() {
+                    hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+                }
+            }
+        }
+        hash
+    }
+
+    /// Take the settings from a flash sector, `false` if it holds no keymap for this
+    /// firmware: none at all, another format or the keymap of a firmware with a different
+    /// keymap, whose macros and settings are still taken
+    pub fn load(&mut self, storage: &[u8; STORAGE_SIZE]) -> bool {
+        let (header, data) = storage.split_at(STORAGE_HEADER_SIZE);
+        if header[..4] != STORAGE_MAGIC
+            || header[4] != STORAGE_VERSION
+            || header[5] as usize != LAYERS
+            || header[6] as usize != NUM_KEYS
+        {
+            return false;
+        }
+
+        let (keycodes, rest) = data.split_at(Self::STORAGE_KEYCODES_SIZE);
+        let (macros, settings) = rest.split_at(MACRO_BUFFER_SIZE);
+        self.macros.copy_from_slice(macros);
+        for (id, option) in CUSTOM_VALUES.into_iter().zip(settings) {
+            self.set_custom_value(id, *option);
+        }
+        if header[8..12] != self.keymap_hash().to_le_bytes() {
+            return false;
+        }
+
+        for (keycode, bytes) in self
+            .keycodes
+            .iter_mut()
+            .flatten()
+            .zip(keycodes.chunks_exact(2))
+        {
+            *keycode = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        true
+    }
+
+    /// Write the settings into a flash sector, filled with 0xFF beforehand
+    pub fn store(&self, storage: &mut [u8; STORAGE_SIZE]) {
+        let (header, data) = storage.split_at_mut(STORAGE_HEADER_SIZE);
+        header[..4].copy_from_slice(&STORAGE_MAGIC);
+        header[4] = STORAGE_VERSION;
+        header[5] = LAYERS as u8;
+        header[6] = NUM_KEYS as u8;
+        header[8..12].copy_from_slice(&self.keymap_hash().to_le_bytes());
+
+        let (keycodes, rest) = data.split_at_mut(Self::STORAGE_KEYCODES_SIZE);
+        let (macros, settings) = rest.split_at_mut(MACRO_BUFFER_SIZE);
+        for (keycode, bytes) in self
+            .keycodes
+            .iter()
+            .flatten()
+            .zip(keycodes.chunks_exact_mut(2))
+        {
+            bytes.copy_from_slice(&keycode.to_le_bytes());
+        }
+        macros.copy_from_slice(&self.macros);
+        for (option, id) in settings.iter_mut().zip(CUSTOM_VALUES) {
+            *option = self.custom_value(id).unwrap_or(0);
+        }
+    }
+
+    /// Execute a command and turn the report into its reply, which echoes the command
+    ///
+    /// `now` is reported as the uptime.
+    pub fn handle_command(&mut self, data: &mut [u8; REPORT_SIZE], now: Instant) -> Outcome {
+        match data[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                data[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            }
+
+            ID_GET_KEYBOARD_VALUE => match data[1] {
+                ID_UPTIME => {
+                    let uptime = now.as_millis() as u32;
+                    data[2..6].copy_from_slice(&uptime.to_be_bytes());
+                }
+                // No layout options, and the key tester has no access to the key states
+                ID_LAYOUT_OPTIONS | ID_SWITCH_MATRIX_STATE | ID_FIRMWARE_VERSION => {
+                    data[2..6].fill(0);
+                }
+                _ => data[0] = ID_UNHANDLED,
+            },
+
+            ID_SET_KEYBOARD_VALUE => match data[1] {
+                ID_LAYOUT_OPTIONS => {}
+                _ => data[0] = ID_UNHANDLED,
+            },
+
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE | ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let (layer, row, column) = (data[1] as usize, data[2], data[3] as usize);
+                if row != 0 || column >= MATRIX.len() {
+                    return Outcome::Unchanged;
+                }
+                let Some(keys) = self.keycodes.get_mut(layer) else {
+                    return Outcome::Unchanged;
+                };
+                let keycode = &mut keys[MATRIX[column].index()];
+
+                if data[0] == ID_DYNAMIC_KEYMAP_GET_KEYCODE {
+                    data[4..6].copy_from_slice(&keycode.to_be_bytes());
+                } else {
+                    *keycode = u16::from_be_bytes([data[4], data[5]]);
+                    return Outcome::Changed;
+                }
+            }
+
+            ID_DYNAMIC_KEYMAP_GET_ENCODER | ID_DYNAMIC_KEYMAP_SET_ENCODER => {
+                let (layer, encoder, clockwise) = (data[1] as usize, data[2], data[3] != 0);
+                if encoder != 0 {
+                    return Outcome::Unchanged;
+                }
+                let Some(keys) = self.keycodes.get_mut(layer) else {
+                    return Outcome::Unchanged;
+                };
+                let keycode = &mut keys[ENCODER[clockwise as usize].index()];
+
+                if data[0] == ID_DYNAMIC_KEYMAP_GET_ENCODER {
+                    data[4..6].copy_from_slice(&keycode.to_be_bytes());
+                } else {
+                    *keycode = u16::from_be_bytes([data[4], data[5]]);
+                    return Outcome::Changed;
+                }
+            }
+
+            ID_DYNAMIC_KEYMAP_RESET => {
+                self.reset_keymap();
+                return Outcome::Changed;
+            }
+
+            ID_EEPROM_RESET => {
+                self.reset_keymap();
+                self.reset_macros();
+                self.reset_settings();
+                return Outcome::Changed;
+            }
+
+            // Custom values: [command, channel, value, data...]
+            ID_CUSTOM_GET_VALUE => match self.custom_value(data[2]) {
+                Some(option) if data[1] == ID_CUSTOM_CHANNEL => data[3] = option,
+                _ => data[0] = ID_UNHANDLED,
+            },
+
+            ID_CUSTOM_SET_VALUE => {
+                if data[1] == ID_CUSTOM_CHANNEL && self.set_custom_value(data[2], data[3]) {
+                    return Outcome::Changed;
+                }
+                data[0] = ID_UNHANDLED;
+            }
+
+            // Changes are saved on their own once VIA is quiet
+            ID_CUSTOM_SAVE if data[1] == ID_CUSTOM_CHANNEL => {}
+
+            ID_BOOTLOADER_JUMP => return Outcome::Bootloader,
+
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[1] = MACRO_COUNT,
+
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+                data[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes());
+            }
+
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[1] = LAYERS as u8,
+
+            // Buffer transfers: [command, offset high, offset low, size, data...]
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER
+            | ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER
+            | ID_DYNAMIC_KEYMAP_GET_BUFFER
+            | ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+                let command = data[0];
+                let offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+                let size = (data[3] as usize).min(MAX_BUFFER_CHUNK);
+
+                for (i, byte) in data[4..4 + size].iter_mut().enumerate() {
+                    let offset = offset + i;
+                    match command {
+                        ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+                            *byte = self.macros.get(offset).copied().unwrap_or(0);
+                        }
+                        ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+                            if let Some(macro_byte) = self.macros.get_mut(offset) {
+                                *macro_byte = *byte;
+                            }
+                        }
+                        ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+                            *byte = self.keymap_byte(offset).unwrap_or(0);
+                        }
+                        _ => self.set_keymap_byte(offset, *byte),
+                    }
+                }
+
+                if matches!(
+                    command,
+                    ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER | ID_DYNAMIC_KEYMAP_SET_BUFFER
+                ) {
+                    return Outcome::Changed;
+                }
+            }
+
+            ID_DYNAMIC_KEYMAP_MACRO_RESET => {
+                self.reset_macros();
+                return Outcome::Changed;
+            }
+
+            // Lighting menus and the Vial protocol aren't supported
+            _ => data[0] = ID_UNHANDLED,
+        }
+
+        Outcome::Unchanged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layouts::KeyType;
+    use crate::macros::MacroStep;
+    use usbd_hid::descriptor::{KeyboardUsage, MediaKey};
+
+    const A: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardAa);
+    const F: KeyType = KeyType::Keycode(KeyboardUsage::KeyboardFf);
+
+    static KEYMAP: [KeyLayout; 2] = [
+        KeyLayout {
+            encoder_left: KeyAction::Key(KeyType::Media(MediaKey::VolumeDecrement)),
+            encoder_right: KeyAction::Key(KeyType::Media(MediaKey::VolumeIncrement)),
+            encoder_button: KeyAction::Key(KeyType::Media(MediaKey::Mute)),
+            key1: KeyAction::Key(A),
+            key2: KeyAction::MomentaryLayer(1),
+            key3: KeyAction::LayerTap { layer: 1, tap: F },
+        },
+        KeyLayout {
+            encoder_left: KeyAction::Transparent,
+            encoder_right: KeyAction::Transparent,
+            encoder_button: KeyAction::Transparent,
+            key1: KeyAction::Key(F),
+            key2: KeyAction::Transparent,
+            key3: KeyAction::NoAction,
+        },
+    ];
+
+    /// The same layers with another action, like the keymap of a firmware update
+    static OTHER_KEYMAP: [KeyLayout; 2] = [
+        KeyLayout {
+            key1: KeyAction::Key(F),
+            ..KEYMAP[0]
+        },
+        KeyLayout { ..KEYMAP[1] },
+    ];
+
+    const SETTINGS: ViaSettings = ViaSettings {
+        backends: [Backend::Hid, Backend::Midi, Backend::Midi],
+        presentation_encoder: PresentationEncoder::Pointer,
+        uart_capture: false,
+    };
+
+    fn config(keymap: &'static [KeyLayout; 2]) -> ViaConfig<2> {
+        let mut config = ViaConfig::new(keymap, SETTINGS);
+        config.reset_keymap();
+        config
+    }
+
+    /// Reply to a command with its arguments
+    fn command(config: &mut ViaConfig<2>, bytes: &[u8]) -> ([u8; REPORT_SIZE], Outcome) {
+        let mut data = [0; REPORT_SIZE];
+        data[..bytes.len()].copy_from_slice(bytes);
+        let outcome = config.handle_command(&mut data, Instant::from_millis(0x0102_0304));
+        (data, outcome)
+    }
+
+    #[test]
+    fn reports_protocol_version_and_uptime() {
+        let mut config = config(&KEYMAP);
+        let (data, _) = command(&mut config, &[ID_GET_PROTOCOL_VERSION]);
+        assert_eq!(data[..3], [ID_GET_PROTOCOL_VERSION, 0x00, 0x0C]);
+        let (data, _) = command(&mut config, &[ID_GET_KEYBOARD_VALUE, ID_UPTIME]);
+        assert_eq!(data[2..6], [0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn unknown_command_is_unhandled() {
+        let mut config = config(&KEYMAP);
+        let (data, outcome) = command(&mut config, &[0x42, 1, 2]);
+        assert_eq!(data[..3], [ID_UNHANDLED, 1, 2]);
+        assert_eq!(outcome, Outcome::Unchanged);
+    }
+
+    #[test]
+    fn gets_and_sets_keycodes_of_matrix_and_encoder() {
+        let mut config = config(&KEYMAP);
+        // Key1 on layer 0 is KC_A
+        let (data, _) = command(&mut config, &[ID_DYNAMIC_KEYMAP_GET_KEYCODE, 0, 0, 0]);
+        assert_eq!(data[4..6], [0x00, 0x04]);
+
+        // Key1 on layer 1 becomes LSFT(KC_A), which has no key action
+        let set = [ID_DYNAMIC_KEYMAP_SET_KEYCODE, 1, 0, 0, 0x02, 0x04];
+        assert_eq!(command(&mut config, &set).1, Outcome::Changed);
+        assert!(config.action(1, Key::Key1) == Some(KeyAction::NoAction));
+        assert!(config.action(0, Key::Key1) == Some(KeyAction::Key(A)));
+
+        // Clockwise steps of encoder 0 on layer 1
+        let set = [ID_DYNAMIC_KEYMAP_SET_ENCODER, 1, 0, 1, 0x00, 0x09];
+        assert_eq!(command(&mut config, &set).1, Outcome::Changed);
+        assert!(config.action(1, Key::EncoderRight) == Some(KeyAction::Key(F)));
+        let (data, _) = command(&mut config, &[ID_DYNAMIC_KEYMAP_GET_ENCODER, 1, 0, 1]);
+        assert_eq!(data[4..6], [0x00, 0x09]);
+
+        // Positions outside the matrix are ignored
+        let set = [ID_DYNAMIC_KEYMAP_SET_KEYCODE, 2, 0, 0, 0x00, 0x04];
+        assert_eq!(command(&mut config, &set).1, Outcome::Unchanged);
+        let set = [ID_DYNAMIC_KEYMAP_SET_KEYCODE, 0, 0, 4, 0x00, 0x04];
+        assert_eq!(command(&mut config, &set).1, Outcome::Unchanged);
+    }
+
+    #[test]
+    fn keymap_buffer_holds_big_endian_keycodes_by_layer_and_column() {
+        let mut config = config(&KEYMAP);
+        let (data, _) = command(&mut config, &[ID_DYNAMIC_KEYMAP_GET_BUFFER, 0, 6, 4]);
+        // Encoder button of layer 0 (KC_MUTE) and key1 of layer 1 (KC_F)
+        assert_eq!(data[4..8], [0x00, 0xA8, 0x00, 0x09]);
+
+        // Past the end of the keymap reads as zero and writes are dropped
+        let set = [ID_DYNAMIC_KEYMAP_SET_BUFFER, 0, 15, 3, 0x52, 0x00, 0x04];
+        assert_eq!(command(&mut config, &set).1, Outcome::Changed);
+        let (data, _) = command(&mut config, &[ID_DYNAMIC_KEYMAP_GET_BUFFER, 0, 14, 4]);
+        assert_eq!(data[4..8], [0x00, 0x52, 0x00, 0x00]);
+        assert!(
+            config.action(1, Key::EncoderButton)
+                == Some(KeyAction::Key(KeyType::Keycode(KeyboardUsage::from(0x52))))
+        );
+    }
+
+    #[test]
+    fn macro_buffer_is_written_in_chunks() {
+        let mut config = config(&KEYMAP);
+        let set = [ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 0, 3, b'h', b'i', 0];
+        assert_eq!(command(&mut config, &set).1, Outcome::Changed);
+        let set = [ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 3, 2, b'o', 0];
+        command(&mut config, &set);
+        assert!(config.macro_steps(1).collect::<std::vec::Vec<_>>() == [MacroStep::Char(b'o')]);
+
+        let (data, _) = command(&mut config, &[ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER, 0, 0, 4]);
+        assert_eq!(data[4..8], [b'h', b'i', 0, b'o']);
+
+        // The chunk is cut at the end of the report and of the buffer
+        let (data, _) = command(
+            &mut config,
+            &[ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER, 0x01, 0xFF, 0xFF],
+        );
+        assert_eq!(data[4..REPORT_SIZE], [0; MAX_BUFFER_CHUNK]);
+
+        assert_eq!(
+            command(&mut config, &[ID_DYNAMIC_KEYMAP_MACRO_RESET]).1,
+            Outcome::Changed
+        );
+        assert!(config.macro_steps(0).next().is_none());
+    }
+
+    #[test]
+    fn custom_values_select_settings() {
+        let mut config = config(&KEYMAP);
+        // Gamepad in the universal position
+        let set = [
+            ID_CUSTOM_SET_VALUE,
+            ID_CUSTOM_CHANNEL,
+            ID_BACKEND_UNIVERSAL,
+            2,
+        ];
+        assert_eq!(command(&mut config, &set).1, Outcome::Changed);
+        let set = [ID_CUSTOM_SET_VALUE, ID_CUSTOM_CHANNEL, ID_UART_CAPTURE, 1];
+        assert_eq!(command(&mut config, &set).1, Outcome::Changed);
+        assert!(config.settings().backends == [Backend::Hid, Backend::Midi, Backend::Gamepad]);
+        assert!(config.settings().uart_capture);
+
+        let get = [ID_CUSTOM_GET_VALUE, ID_CUSTOM_CHANNEL, ID_BACKEND_UNIVERSAL];
+        assert_eq!(command(&mut config, &get).0[3], 2);
+
+        // Unknown options and values are unhandled and change nothing
+        let set = [
+            ID_CUSTOM_SET_VALUE,
+            ID_CUSTOM_CHANNEL,
+            ID_PRESENTATION_ENCODER,
+            2,
+        ];
+        let (data, outcome) = command(&mut config, &set);
+        assert_eq!((data[0], outcome), (ID_UNHANDLED, Outcome::Unchanged));
+        let get = [ID_CUSTOM_GET_VALUE, ID_CUSTOM_CHANNEL, 0x06];
+        assert_eq!(command(&mut config, &get).0[0], ID_UNHANDLED);
+
+        // An EEPROM reset restores the settings of the firmware
+        command(&mut config, &[ID_EEPROM_RESET]);
+        assert!(config.settings() == SETTINGS);
+    }
+
+    #[test]
+    fn storage_round_trip() {
+        let mut config = config(&KEYMAP);
+        command(
+            &mut config,
+            &[ID_DYNAMIC_KEYMAP_SET_KEYCODE, 0, 0, 0, 0x00, 0x09],
+        );
+        command(
+            &mut config,
+            &[ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 0, 1, b'x'],
+        );
+        command(
+            &mut config,
+            &[ID_CUSTOM_SET_VALUE, ID_CUSTOM_CHANNEL, ID_UART_CAPTURE, 1],
+        );
+        let mut storage = [0xFF; STORAGE_SIZE];
+        config.store(&mut storage);
+
+        let mut loaded = ViaConfig::new(&KEYMAP, SETTINGS);
+        assert!(loaded.load(&storage));
+        assert!(loaded.action(0, Key::Key1) == Some(KeyAction::Key(F)));
+        assert!(loaded.macro_steps(0).next() == Some(MacroStep::Char(b'x')));
+        assert!(loaded.settings().uart_capture);
+    }
+
+    #[test]
+    fn storage_of_another_keymap_keeps_only_macros_and_settings() {
+        let mut config = config(&KEYMAP);
+        command(
+            &mut config,
+            &[ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 0, 1, b'x'],
+        );
+        command(
+            &mut config,
+            &[ID_CUSTOM_SET_VALUE, ID_CUSTOM_CHANNEL, ID_UART_CAPTURE, 1],
+        );
+        let mut storage = [0xFF; STORAGE_SIZE];
+        config.store(&mut storage);
+
+        let mut updated = ViaConfig::new(&OTHER_KEYMAP, SETTINGS);
+        assert!(!updated.load(&storage));
+        assert!(updated.action(0, Key::Key1).is_none());
+        assert!(updated.macro_steps(0).next() == Some(MacroStep::Char(b'x')));
+        assert!(updated.settings().uart_capture);
+    }
+
+    #[test]
+    fn erased_or_older_storage_is_ignored() {
+        let mut config = config(&KEYMAP);
+        assert!(!config.load(&[0xFF; STORAGE_SIZE]));
+
+        let mut storage = [0xFF; STORAGE_SIZE];
+        config.store(&mut storage);
+        storage[4] = STORAGE_VERSION - 1;
+        assert!(!config.load(&storage));
+    }
+
+    #[test]
+    fn settings_missing_from_older_storage_keep_their_default() {
+        let mut storage = [0xFF; STORAGE_SIZE];
+        config(&KEYMAP).store(&mut storage);
+        // Storage written before the UART capture setting existed
+        let capture =
+            STORAGE_HEADER_SIZE + ViaConfig::<2>::STORAGE_KEYCODES_SIZE + MACRO_BUFFER_SIZE + 4;
+        storage[capture] = 0xFF;
+
+        let settings = ViaSettings {
+            uart_capture: true,
+            ..SETTINGS
+        };
+        let mut loaded = ViaConfig::new(&KEYMAP, settings);
+        assert!(loaded.load(&storage));
+        assert!(loaded.settings() == settings);
+    }
+}
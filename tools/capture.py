@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+"""Fetch the UART capture of oskar from its flash.
+
+Opens the serial port and sets it to 75 baud for a moment, which makes oskar send what it
+recorded on the UART header while no program read the port, oldest first, as text with
+the boot count of oskar and the seconds since that boot at the start of every line. With
+--combo it waits for the encoder button and key 3 to be pressed together instead.
+
+Opening the port sets the baud rate of the header, so give the one of the target to
+keep capturing it afterwards.
+
+    tools/capture.py /dev/ttyACM0
+    tools/capture.py /dev/ttyACM0 --baud 921600 > boot.log
+"""
+
+import argparse
+import sys
+
+START_MARKER = b"-- start of capture --"
+END_MARKER = b"-- end of capture --"
+
+# Baud rate that makes oskar send the capture, CAPTURE_DUMP_RATE in src/cdc_acm.rs
+DUMP_BAUD_RATE = 75
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("port", help="serial port of oskar")
+    parser.add_argument("--baud", type=int, default=115200, help="baud rate of the target")
+    parser.add_argument("--timeout", type=float, default=30, help="seconds to wait for data")
+    parser.add_argument("--combo", action="store_true", help="wait for the key combo")
+    args = parser.parse_args()
+
+    import serial
+
+    out = sys.stdout.buffer
+    with serial.Serial(args.port, args.baud, timeout=args.timeout) as ser:
+        if args.combo:
+            print("Press the encoder button and key 3 together", file=sys.stderr)
+        else:
+            # oskar keeps the baud rate of the target, set it back to match the port
+            ser.baudrate = DUMP_BAUD_RATE
+            ser.baudrate = args.baud
+        # Skip what the target sends until the capture starts
+        started = False
+        while line := ser.readline():
+            if line.startswith(START_MARKER):
+                started = True
+            elif line.startswith(END_MARKER) and started:
+                return 0
+            elif started:
+                out.write(line)
+    print("No end of the capture received", file=sys.stderr)
+    return 1
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+"""Decode the records of the UART sniffer of oskar.
+
+Prints both lines of the sniffed link as an interleaved hexdump, or writes them to a
+pcap file with LINKTYPE_USER0, where every packet starts with a direction byte (0 = RX,
+1 = TX) followed by the data. Switch the sniffer on with key 2 + key 3 first, the
+serial port sets the baud rate and format of the sniffed link.
+
+    tools/sniff.py /dev/ttyACM0 115200
+    tools/sniff.py /dev/ttyACM0 115200 --pcap capture.pcap
+    tools/sniff.py saved-stream.bin
+"""
+
+import argparse
+import struct
+import sys
+
+RECORD_SYNC = 0xA5
+HEADER = struct.Struct("<BBIB")
+MAX_RECORD_DATA = 64
+
+FLAG_TX = 1 << 0
+FLAG_OVERRUN = 1 << 1
+ERROR_SHIFT = 2
+ERRORS = {1: "FRAMING ERROR", 2: "PARITY ERROR", 3: "BREAK"}
+
+LINKTYPE_USER0 = 147
+
+
+def records(chunks):
+    """Yield (time in us, tx, overrun, error, data) of the records in a byte stream."""
+    buf = bytearray()
+    # The timestamps of the device wrap after 71 minutes
+    last = None
+    elapsed = 0
+    for chunk in chunks:
+        buf += chunk
+        while True:
+            start = buf.find(RECORD_SYNC)
+            if start < 0:
+                buf.clear()
+                break
+            del buf[:start]
+            if len(buf) < HEADER.size:
+                break
+            _, flags, timestamp, length = HEADER.unpack_from(buf)
+            if length > MAX_RECORD_DATA:
+                # Not a record, resynchronize on the next sync byte
+                del buf[:1]
+                continue
+            if len(buf) < HEADER.size + length:
+                break
+            data = bytes(buf[HEADER.size : HEADER.size + length])
+            del buf[: HEADER.size + length]
+
+            if last is not None:
+                delta = (timestamp - last) & 0xFFFFFFFF
+                # Records are stamped at their first byte, one can start slightly before
+                # the end of the previous one on the other line
+                if delta >= 1 << 31:
+                    delta -= 1 << 32
+                elapsed += delta
+            last = timestamp
+            error = ERRORS.get((flags >> ERROR_SHIFT) & 3)
+            yield elapsed, bool(flags & FLAG_TX), bool(flags & FLAG_OVERRUN), error, data
+
+
+def hexdump(time, tx, overrun, error, data):
+    line = "TX" if tx else "RX"
+    if overrun:
+        print(f"{time / 1e6:12.6f} {line} -- bytes lost --")
+    for offset in range(0, len(data), 16):
+        part = data[offset : offset + 16]
+        hex_part = " ".join(f"{b:02x}" for b in part)
+        text = "".join(chr(b) if 0x20 <= b < 0x7F else "." for b in part)
+        print(f"{time / 1e6:12.6f} {line} {hex_part:<47}  |{text}|")
+    if error:
+        print(f"{time / 1e6:12.6f} {line} -- {error} --")
+
+
+class PcapWriter:
+    def __init__(self, file):
+        self.file = file
+        file.write(struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, LINKTYPE_USER0))
+
+    def write(self, time, tx, overrun, error, data):
+        if not data:
+            return
+        packet = bytes([1 if tx else 0]) + data
+        seconds, micros = divmod(time, 1_000_000)
+        self.file.write(struct.pack("<IIII", seconds, micros, len(packet), len(packet)))
+        self.file.write(packet)
+        self.file.flush()
+
+
+def serial_chunks(port, baud):
+    import serial
+
+    with serial.Serial(port, baud) as ser:
+        while True:
+            yield ser.read(max(1, ser.in_waiting))
+
+
+def file_chunks(path):
+    with open(path, "rb") as file:
+        while chunk := file.read(4096):
+            yield chunk
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("source", help="serial port of oskar, or a file with a saved stream")
+    parser.add_argument("baud", nargs="?", type=int, default=115200, help="baud rate of the link")
+    parser.add_argument("--pcap", help="write a pcap file instead of the hexdump")
+    args = parser.parse_args()
+
+    if args.source.startswith("/dev/") or args.source.upper().startswith("COM"):
+        chunks = serial_chunks(args.source, args.baud)
+    else:
+        chunks = file_chunks(args.source)
+
+    try:
+        if args.pcap:
+            with open(args.pcap, "wb") as file:
+                pcap = PcapWriter(file)
+                for record in records(chunks):
+                    pcap.write(*record)
+        else:
+            for record in records(chunks):
+                hexdump(*record)
+    except KeyboardInterrupt:
+        pass
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,68 @@
+#!/usr/bin/env python3
+"""Measure the throughput of the UART bridge of oskar with a loopback.
+
+Connect GP0 (TX) to GP1 (RX) on the UART header, then send random data through the
+bridge at the given baud rate and compare what comes back. Prints the rate the data
+came back with and how many bytes were lost or corrupted, and exits with 1 if any were.
+
+    tools/throughput.py /dev/ttyACM0 3000000
+    tools/throughput.py /dev/ttyACM0 115200 --size 65536
+"""
+
+import argparse
+import os
+import sys
+import threading
+import time
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("port", help="serial port of oskar")
+    parser.add_argument("baud", type=int, help="baud rate of the UART header")
+    parser.add_argument("--size", type=int, default=1024 * 1024, help="bytes to send")
+    parser.add_argument("--timeout", type=float, default=2, help="seconds without data to stop")
+    args = parser.parse_args()
+
+    import serial
+
+    data = os.urandom(args.size)
+    received = bytearray()
+    with serial.Serial(args.port, args.baud, timeout=args.timeout) as ser:
+        ser.reset_input_buffer()
+
+        # Write from a thread, the bridge only takes as much as the header sends
+        writer = threading.Thread(target=ser.write, args=(data,))
+        start = time.monotonic()
+        writer.start()
+        first = last = None
+        while len(received) < len(data):
+            chunk = ser.read(4096)
+            if not chunk:
+                break
+            last = time.monotonic()
+            if first is None:
+                first = last
+            received += chunk
+        writer.join()
+
+    if first is None:
+        print("Nothing received, is TX connected to RX?", file=sys.stderr)
+        return 1
+
+    seconds = max(last - start, 1e-6)
+    rate = len(received) / seconds
+    # 10 bits per byte with 8N1
+    print(f"{len(received)} of {len(data)} bytes in {seconds:.2f} s")
+    print(f"{rate / 1000:.1f} KB/s, {rate * 10 / args.baud * 100:.1f} % of {args.baud} baud")
+
+    lost = len(data) - len(received)
+    corrupted = sum(a != b for a, b in zip(data, received))
+    if lost or corrupted:
+        print(f"{lost} bytes lost, {corrupted} bytes differ", file=sys.stderr)
+        return 1
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())